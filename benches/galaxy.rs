@@ -0,0 +1,147 @@
+use std::f64::consts::PI;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rstar::{RTree, RTreeObject, AABB};
+use scilib::coordinate::{cartesian::Cartesian, spherical::Spherical};
+use spacebuild::{
+    galaxy::Galaxy,
+    instance::Instance,
+    outbox::OutboxReceiver,
+    protocol::{self, Action},
+};
+use tokio::{runtime::Runtime, sync::mpsc::Sender};
+
+const VIEW_RADIUS: f64 = 10000f64;
+
+/// Copy of the per tick drain/rebuild update the galaxy used before the clock based redesign.
+#[derive(Clone)]
+struct LegacyBody {
+    id: u32,
+    coords: Cartesian,
+    rotating_speed: f64,
+    gravity_center: u32,
+}
+
+impl PartialEq for LegacyBody {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl RTreeObject for LegacyBody {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point([self.coords.x, self.coords.y, self.coords.z])
+    }
+}
+
+fn legacy_update(tree: &mut RTree<LegacyBody>, mut delta: f64) {
+    delta *= 10f64;
+    let mut old_rtree = tree.clone();
+    let mut new_rtree = RTree::<LegacyBody>::default();
+    let mut celestials: Vec<_> = tree.drain().collect();
+
+    while let Some(mut celestial) = celestials.pop() {
+        old_rtree.remove(&celestial);
+        let gravity_center = celestials.iter().find(|g| g.id == celestial.gravity_center);
+        if let Some(gravity_center) = gravity_center {
+            let local_coordinates_sph = Spherical::from_coord(celestial.coords - gravity_center.coords);
+            let mut new_coordinates_sph = local_coordinates_sph;
+            new_coordinates_sph.phi += celestial.rotating_speed * delta;
+            new_coordinates_sph.phi %= PI;
+            let delta_car = Cartesian::from_coord(new_coordinates_sph) - Cartesian::from_coord(local_coordinates_sph);
+            if delta_car.x.is_normal() && delta_car.y.is_normal() && delta_car.z.is_normal() {
+                celestial.coords += delta_car;
+                let mut ids = vec![celestial.id];
+                while let Some(id) = ids.pop() {
+                    celestials.iter_mut().for_each(|g| {
+                        if g.gravity_center == id {
+                            g.coords += delta_car;
+                            ids.push(g.id);
+                        }
+                    });
+                }
+            }
+        }
+        old_rtree.insert(celestial.clone());
+        new_rtree.insert(celestial);
+    }
+    *tree = new_rtree;
+}
+
+fn legacy_view(tree: &RTree<LegacyBody>, center: Cartesian) -> usize {
+    let min = [center.x - VIEW_RADIUS, center.y - VIEW_RADIUS, center.z - VIEW_RADIUS];
+    let max = [center.x + VIEW_RADIUS, center.y + VIEW_RADIUS, center.z + VIEW_RADIUS];
+    tree.locate_in_envelope_intersecting(&AABB::from_corners(min, max))
+        .filter(|g| (g.coords - center).norm() <= VIEW_RADIUS)
+        .count()
+}
+
+/// Instance with `nb_systems` generated systems and a player in one more, whose states are to be drained.
+fn gen_instance(runtime: &Runtime, nb_systems: usize) -> (Instance, Sender<Action>, OutboxReceiver, Cartesian) {
+    runtime.block_on(async {
+        let mut instance = Instance::in_memory().await.unwrap();
+        let mut star_id = 0;
+        for i in 0..nb_systems {
+            star_id = instance
+                .gen_system(Cartesian::from(i as f64 * 50000f64, 0f64, 0f64))
+                .await;
+        }
+        let (_id, action_send, state_recv) = instance.register("bench".to_string(), "").await.unwrap();
+        let view_center = instance.borrow_galaxy().position_of(star_id).unwrap();
+        (instance, action_send, state_recv, view_center)
+    })
+}
+
+fn legacy_tree(galaxy: &Galaxy) -> RTree<LegacyBody> {
+    RTree::bulk_load(
+        galaxy
+            .bodies_now()
+            .into_iter()
+            .map(|body| {
+                let body = protocol::state::Body::from(body);
+                LegacyBody {
+                    id: body.id,
+                    coords: Cartesian::from(body.coords[0], body.coords[1], body.coords[2]),
+                    rotating_speed: body.rotating_speed,
+                    gravity_center: body.gravity_center,
+                }
+            })
+            .collect(),
+    )
+}
+
+fn bench_tick(c: &mut Criterion) {
+    let mut group = c.benchmark_group("galaxy_tick");
+    group.sample_size(10);
+
+    let runtime = Runtime::new().unwrap();
+    for nb_systems in [1, 4] {
+        let (mut instance, _action_send, mut state_recv, view_center) = gen_instance(&runtime, nb_systems);
+        let nb_bodies = instance.borrow_galaxy().borrow_bodies().len();
+
+        let tree = legacy_tree(instance.borrow_galaxy());
+        group.bench_with_input(BenchmarkId::new("drain_rebuild", nb_bodies), &tree, |b, tree| {
+            b.iter_batched(
+                || tree.clone(),
+                |mut tree| {
+                    legacy_update(&mut tree, 0.1);
+                    legacy_view(&tree, view_center)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_function(BenchmarkId::new("instance", nb_bodies), |b| {
+            b.iter(|| {
+                runtime.block_on(instance.update(0.1));
+                while state_recv.try_recv().is_ok() {}
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tick);
+criterion_main!(benches);
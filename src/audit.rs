@@ -0,0 +1,55 @@
+use crate::player::unix_time;
+use crate::protocol::Command;
+use crate::spacebuild_log;
+use crate::storage::Storage;
+use crate::Result;
+use std::sync::Arc;
+
+/// Privileged command attempt, allowed or not.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Seconds since the UNIX epoch.
+    pub time: f64,
+    pub actor: String,
+    pub command: Command,
+    /// `ok: ` or `error: ` followed by the answer sent to the actor.
+    pub outcome: String,
+}
+
+/// Log of the privileged commands, clones writing to the same log.
+#[derive(Clone)]
+pub struct AuditLog {
+    storage: Arc<dyn Storage>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn record(&self, actor: &str, command: &Command, outcome: &str) -> Result<()> {
+        let entry = AuditEntry {
+            time: unix_time(),
+            actor: actor.to_string(),
+            command: command.clone(),
+            outcome: outcome.to_string(),
+        };
+        self.storage.insert_audit(&entry).await
+    }
+
+    /// Records the outcome of a command attempt, errors of the storage being logged only.
+    pub async fn record_result(&self, actor: &str, command: &Command, result: &Result<String>) {
+        let outcome = match result {
+            Ok(message) => format!("ok: {}", message),
+            Err(err) => format!("error: {}", err),
+        };
+        if let Err(err) = self.record(actor, command, &outcome).await {
+            spacebuild_log!(error, "audit", "Could not audit {:?} by {}: {}", command, actor, err);
+        }
+    }
+
+    /// Every entry, oldest first.
+    pub async fn entries(&self) -> Result<Vec<AuditEntry>> {
+        self.storage.audit_entries().await
+    }
+}
@@ -0,0 +1,55 @@
+use crate::error::Error;
+use crate::Result;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use std::sync::OnceLock;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Hash checked for nicknames without account, hashed on first use.
+static UNKNOWN_HASH: OnceLock<String> = OnceLock::new();
+
+/// Hashes `password` with argon2 and a random salt, in the PHC string format stored in the `Player` table.
+///
+/// Hashing is deliberately slow, it runs on the blocking pool to keep the game loop going.
+pub async fn hash_password(password: String) -> Result<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::PasswordTooShort(MIN_PASSWORD_LENGTH));
+    }
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| Error::PasswordHashError(err.to_string()))
+    })
+    .await
+    .map_err(|err| Error::JoinError(err.to_string()))?
+}
+
+/// Refuses a nickname without account as late as `verify_password` would refuse a wrong password, so that
+/// response times do not tell which nicknames are taken.
+pub async fn refuse_unknown(password: String) -> Error {
+    let hash = match UNKNOWN_HASH.get() {
+        Some(hash) => hash.clone(),
+        None => match hash_password("no account has this password".to_string()).await {
+            Ok(hash) => UNKNOWN_HASH.get_or_init(|| hash).clone(),
+            Err(err) => return err,
+        },
+    };
+    let _ = verify_password(password, hash).await;
+    Error::InvalidCredentials
+}
+
+/// Checks `password` against a hash produced by `hash_password`.
+pub async fn verify_password(password: String, hash: String) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|err| Error::PasswordHashError(err.to_string()))?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .map_err(|_err| Error::InvalidCredentials)
+    })
+    .await
+    .map_err(|err| Error::JoinError(err.to_string()))?
+}
@@ -0,0 +1,237 @@
+use crate::error::Error;
+use crate::player::unix_time;
+use crate::protocol::Command;
+use crate::storage::Storage;
+use crate::Result;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Ban of an account or of an address.
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub id: u32,
+    pub nickname: Option<String>,
+    pub address: Option<IpNet>,
+    pub reason: String,
+    pub banned_by: String,
+    pub banned_at: f64,
+    /// Unix time the ban ends at, `None` for a permanent ban.
+    pub expires_at: Option<f64>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: f64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Reason of the bans given without one.
+pub(crate) const DEFAULT_REASON: &str = "Banned by a moderator";
+
+/// Parses an IP address or a CIDR block, an address being a block of its own.
+pub fn parse_address(address: &str) -> Result<IpNet> {
+    IpNet::from_str(address)
+        .or_else(|_| IpAddr::from_str(address).map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_err| Error::InvalidAddress(address.to_string()))
+}
+
+/// Accounts and addresses whose logins are refused.
+///
+/// Address bans are kept in memory as well, being checked at each TCP accept. Clones share the same bans, for
+/// the storage to be written without holding the instance.
+#[derive(Clone)]
+pub struct BanList {
+    storage: Arc<dyn Storage>,
+    addresses: Arc<Mutex<Vec<Ban>>>,
+}
+
+impl BanList {
+    pub(crate) fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            addresses: Arc::default(),
+        }
+    }
+
+    /// Loads the address bans, checked without the storage.
+    pub(crate) async fn load(&self) -> Result<()> {
+        let addresses = self
+            .storage
+            .bans()
+            .await?
+            .into_iter()
+            .filter(|ban| ban.address.is_some())
+            .collect();
+        *self.addresses.lock().unwrap() = addresses;
+        Ok(())
+    }
+
+    async fn insert(&self, ban: &mut Ban) -> Result<()> {
+        ban.id = self.storage.insert_ban(ban).await?;
+        Ok(())
+    }
+
+    /// Carries out the ban commands, returning their answers, `None` for the other commands.
+    ///
+    /// Players are not disconnected here but by `Instance::disconnect_banned`, the bans being written without the
+    /// instance.
+    pub async fn execute(&self, actor: &str, command: &Command) -> Result<Option<String>> {
+        let answer = match command {
+            Command::Ban {
+                nickname,
+                reason,
+                duration,
+            } => {
+                let account = self
+                    .storage
+                    .find_player(nickname)
+                    .await?
+                    .ok_or_else(|| Error::UnknownPlayer(nickname.clone()))?;
+                let reason = reason.as_deref().unwrap_or(DEFAULT_REASON);
+                self.ban(&account.state.nickname, reason, actor, *duration).await?;
+                format!("Banned {}", account.state.nickname)
+            }
+            Command::Unban { nickname } => match self.unban(nickname).await? {
+                true => format!("Unbanned {}", nickname),
+                false => format!("{} was not banned", nickname),
+            },
+            Command::BanAddress {
+                address,
+                reason,
+                duration,
+            } => {
+                let address = parse_address(address)?;
+                let reason = reason.as_deref().unwrap_or(DEFAULT_REASON);
+                self.ban_address(address, reason, actor, *duration).await?;
+                format!("Banned {}", address)
+            }
+            Command::UnbanAddress { address } => {
+                let address = parse_address(address)?;
+                match self.unban_address(address).await? {
+                    true => format!("Unbanned {}", address),
+                    false => format!("{} was not banned", address),
+                }
+            }
+            Command::ListBans => {
+                let bans = self.list().await?;
+                if bans.is_empty() {
+                    return Ok(Some("No bans".to_string()));
+                }
+                bans.iter()
+                    .map(|ban| {
+                        let banned = match (&ban.nickname, &ban.address) {
+                            (Some(nickname), _) => nickname.clone(),
+                            (None, Some(address)) => address.to_string(),
+                            (None, None) => "?".to_string(),
+                        };
+                        let until = match ban.expires_at {
+                            Some(expires_at) => format!("{:.0}s left", expires_at - unix_time()),
+                            None => "permanent".to_string(),
+                        };
+                        format!("{}: {} (by {}, {})", banned, ban.reason, ban.banned_by, until)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(answer))
+    }
+
+    /// Bans `nickname`, for `duration` seconds if any.
+    pub async fn ban(&self, nickname: &str, reason: &str, banned_by: &str, duration: Option<f64>) -> Result<()> {
+        let now = unix_time();
+        let mut ban = Ban {
+            id: 0,
+            nickname: Some(nickname.to_string()),
+            address: None,
+            reason: reason.to_string(),
+            banned_by: banned_by.to_string(),
+            banned_at: now,
+            expires_at: duration.map(|duration| now + duration),
+        };
+        self.insert(&mut ban).await
+    }
+
+    /// Bans the addresses of `address`, for `duration` seconds if any.
+    pub async fn ban_address(
+        &self,
+        address: IpNet,
+        reason: &str,
+        banned_by: &str,
+        duration: Option<f64>,
+    ) -> Result<()> {
+        let now = unix_time();
+        let mut ban = Ban {
+            id: 0,
+            nickname: None,
+            address: Some(address),
+            reason: reason.to_string(),
+            banned_by: banned_by.to_string(),
+            banned_at: now,
+            expires_at: duration.map(|duration| now + duration),
+        };
+        self.insert(&mut ban).await?;
+        self.addresses.lock().unwrap().push(ban);
+        Ok(())
+    }
+
+    /// Lifts the bans of `nickname`, returning whether there was any.
+    pub async fn unban(&self, nickname: &str) -> Result<bool> {
+        let count = self.storage.delete_bans(nickname).await?;
+        Ok(count > 0)
+    }
+
+    /// Lifts the bans of exactly `address`, not the ones of the blocks containing it.
+    pub async fn unban_address(&self, address: IpNet) -> Result<bool> {
+        let count = self.storage.delete_address_bans(address).await?;
+        self.addresses
+            .lock()
+            .unwrap()
+            .retain(|ban| ban.address != Some(address));
+        Ok(count > 0)
+    }
+
+    /// `Error::Banned` with the reason of the latest active ban of `nickname`, if any.
+    pub async fn check(&self, nickname: &str) -> Result<()> {
+        let now = unix_time();
+        for ban in self.storage.bans_of(nickname).await?.into_iter().rev() {
+            if ban.is_active(now) {
+                return Err(Error::Banned(ban.reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// `Error::Banned` with the reason of an active ban of a block containing `address`, if any.
+    pub fn check_address(&self, address: IpAddr) -> Result<()> {
+        let address = address.to_canonical();
+        let now = unix_time();
+        match self
+            .addresses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|ban| ban.is_active(now) && ban.address.is_some_and(|banned| banned.contains(&address)))
+        {
+            Some(ban) => Err(Error::Banned(ban.reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Bans in force, oldest first.
+    pub async fn list(&self) -> Result<Vec<Ban>> {
+        let now = unix_time();
+        Ok(self
+            .storage
+            .bans()
+            .await?
+            .into_iter()
+            .filter(|ban| ban.is_active(now))
+            .collect())
+    }
+}
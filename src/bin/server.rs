@@ -1,12 +1,16 @@
 use anyhow::{bail, Result};
 use clap::Parser;
 use spacebuild::{
+    auth,
+    instance::Instance,
+    limits::{FloodPolicy, RateLimits},
+    protocol::{Command, Role},
     server::{self, InstanceConfig, ServerConfig},
     tls::ServerPki,
     tracing,
 };
-use std::{env, io};
-use tokio::task::JoinHandle;
+use std::{env, io, str::FromStr, sync::Arc};
+use tokio::{sync::Mutex, task::JoinHandle};
 
 #[derive(Parser, Debug)]
 #[command(version, long_about = None)]
@@ -23,6 +27,22 @@ struct Args {
     #[arg(short, long, default_value = "galaxy.db")]
     instance: String,
 
+    /// Keeps the galaxy in memory only, lost once the server stops
+    #[arg(long, conflicts_with = "instance")]
+    in_memory: bool,
+
+    /// Grants the admin role to an existing account, can be repeated
+    #[arg(long, value_name = "NICKNAME")]
+    admin: Vec<String>,
+
+    /// What happens to the messages of a client over the rate limits
+    #[arg(long, default_value = "drop", value_name = "drop|throttle|disconnect", value_parser = parse_flood_policy)]
+    flood_policy: FloodPolicy,
+
+    /// Sustained rate of messages allowed per client
+    #[arg(long, value_name = "RATE")]
+    messages_per_second: Option<f64>,
+
     #[arg(long, default_value = "spacebuild::(.*)", value_name = "REGEX")]
     trace_filter: String,
 
@@ -30,6 +50,15 @@ struct Args {
     trace_level: String,
 }
 
+fn parse_flood_policy(policy: &str) -> std::result::Result<FloodPolicy, String> {
+    match policy {
+        "drop" => Ok(FloodPolicy::Drop),
+        "throttle" => Ok(FloodPolicy::Throttle),
+        "disconnect" => Ok(FloodPolicy::Disconnect),
+        _ => Err(format!("unknown policy '{}'", policy)),
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -45,23 +74,74 @@ async fn main() -> anyhow::Result<()> {
     };
 
     tracing::init(Some(args.trace_filter));
+    let mut instance = if args.in_memory {
+        Instance::in_memory().await?
+    } else {
+        Instance::from_path(args.instance.as_str()).await?
+    };
+    for nickname in args.admin.iter() {
+        instance.set_role(nickname, Role::Admin).await?;
+    }
+    let default_limits = RateLimits::default();
+    instance.set_rate_limits(RateLimits {
+        messages_per_second: args.messages_per_second.unwrap_or(default_limits.messages_per_second),
+        policy: args.flood_policy,
+        ..default_limits
+    });
+    let instance = Arc::new(Mutex::new(instance));
+
     let (stop_on_input_send, stop_on_input_recv) = crossbeam::channel::bounded(1);
+    let (line_send, mut line_recv) = tokio::sync::mpsc::channel::<String>(16);
+    std::thread::spawn(move || {
+        for line in io::stdin().lines().map_while(|line| line.ok()) {
+            if line_send.blocking_send(line).is_err() {
+                return;
+            }
+        }
+    });
+    let console_instance = Arc::clone(&instance);
     tokio::spawn(async move {
-        loop {
-            for line in io::stdin().lines() {
-                if let Ok(line) = line {
-                    if line == "stop" {
-                        stop_on_input_send.send(()).unwrap();
-                        return;
+        while let Some(line) = line_recv.recv().await {
+            let line = line.trim();
+            if line == "stop" {
+                stop_on_input_send.send(()).unwrap();
+                return;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            // Passwords are only set from the console, never logged nor audited.
+            if let ["set-password", nickname, password] = line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                let result = match auth::hash_password(password.to_string()).await {
+                    Ok(hash) => console_instance.lock().await.set_password_hash(nickname, &hash).await,
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(()) => println!("Password of {} set", nickname),
+                    Err(err) => println!("Error: {}", err),
+                }
+                continue;
+            }
+            match Command::from_str(line) {
+                Ok(command) => {
+                    let result = Instance::execute_shared(&console_instance, "console", &command).await;
+                    let audit = console_instance.lock().await.borrow_audit().clone();
+                    audit.record_result("console", &command, &result).await;
+                    match result {
+                        Ok(answer) => println!("{}", answer),
+                        Err(err) => println!("Error: {}", err),
                     }
                 }
+                Err(err) => println!("{}", err),
             }
         }
     });
 
+    let instance_config = InstanceConfig::UserInstance(instance);
+
     let server_hdl: JoinHandle<Result<()>> = tokio::spawn(async move {
         if let spacebuild::Result::Err(err) = server::run(
-            InstanceConfig::UserSqliteDb { path: args.instance },
+            instance_config,
             ServerConfig {
                 tcp: server::TcpConfig::Port(args.port),
                 pki,
@@ -76,8 +76,9 @@ async fn main() -> Result<()> {
 }
 
 async fn run<S: AsyncRead + AsyncWrite + Unpin>(mut client: Bot<S>) -> Result<()> {
-    let new_v4 = Uuid::new_v4();
-    client.login(new_v4.to_string().as_str()).await?;
+    let nickname = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+    client.register(&nickname, &password).await?;
 
     tokio::time::sleep(Duration::from_secs(10)).await;
 
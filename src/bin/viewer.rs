@@ -19,10 +19,14 @@ use ratatui::{
 };
 use spacebuild::{
     bot::{self, Bot},
-    protocol::{state::Body, state::Game},
+    galaxy::TIME_SCALE,
+    protocol::{state::Body, state::Game, Target},
     tls::ClientPki,
 };
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Parser, Debug)]
@@ -40,13 +44,37 @@ struct Args {
         num_args(0..=1)
     )]
     tls: Option<Option<String>>,
+
+    /// Log in as this player instead of spectating
+    #[arg(short, long, requires = "password")]
+    nickname: Option<String>,
+
+    #[arg(short, long)]
+    password: Option<String>,
+
+    /// Create the account before logging in
+    #[arg(short, long, requires = "nickname")]
+    register: bool,
+
+    /// Star system to spectate, the one with the most players by default
+    #[arg(short, long, conflicts_with_all = ["nickname", "region"])]
+    system: Option<u32>,
+
+    /// Region to spectate
+    #[arg(long,
+        value_delimiter = ',',
+        num_args = 4,
+        value_names = ["X", "Y", "Z", "RADIUS"],
+        conflicts_with = "nickname"
+    )]
+    region: Option<Vec<f64>>,
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let pki = if let Some(tls) = args.tls {
+    let pki = if let Some(tls) = args.tls.clone() {
         if let Some(ca_cert_path) = tls {
             Some(ClientPki::Path { cert: ca_cert_path })
         } else {
@@ -57,17 +85,37 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let app_result = if let Some(pki) = pki {
-        run(bot::connect_secure(args.host.as_str(), args.port, pki).await?).await
+        run(bot::connect_secure(args.host.as_str(), args.port, pki).await?, &args).await
     } else {
-        run(bot::connect_plain(args.host.as_str(), args.port).await?).await
+        run(bot::connect_plain(args.host.as_str(), args.port).await?, &args).await
     };
     ratatui::restore();
     app_result
 }
 
-async fn run<S: AsyncRead + AsyncWrite + Unpin>(mut client: Bot<S>) -> Result<()> {
-    println!("Logging in as observer");
-    client.login("observer").await?;
+async fn run<S: AsyncRead + AsyncWrite + Unpin>(mut client: Bot<S>, args: &Args) -> Result<()> {
+    match (&args.nickname, &args.password) {
+        (Some(nickname), Some(password)) => {
+            println!("Logging in as {}", nickname);
+            if args.register {
+                client.register(nickname, password).await?;
+            } else {
+                client.login(nickname, password).await?;
+            }
+        }
+        _ => {
+            let target = match (args.system, &args.region) {
+                (Some(star_id), _) => Some(Target::System(star_id)),
+                (None, Some(region)) => Some(Target::Region {
+                    center: [region[0], region[1], region[2]],
+                    radius: region[3],
+                }),
+                (None, None) => None,
+            };
+            println!("Spectating {:?}", target);
+            client.spectate(target).await?;
+        }
+    }
 
     print!("Running app");
     let terminal = ratatui::init();
@@ -81,6 +129,7 @@ struct App {
     should_quit: bool,
     cursor: (u16, u16),
     celestials: HashMap<u32, spacebuild::protocol::state::Body>,
+    clock: Option<(f64, Instant)>,
     star: Body,
     list_scroll: usize,
     list_area: Rect,
@@ -109,6 +158,7 @@ impl App {
         while !self.should_quit {
             tokio::select! {
                 _ = interval.tick() => {
+                    self.extrapolate();
                     terminal.draw(|frame| self.draw(frame))?;
                 },
                 Some(Ok(event)) = events.next() => {
@@ -119,15 +169,29 @@ impl App {
                         Game::Player(_player_info) => {
 
                         },
-                        Game::Env(bodies) => {
-                            for body in bodies {
+                        Game::EnvEnter(env) => {
+                            self.clock = Some((env.time, Instant::now()));
+                            for body in env.bodies {
                                 if body.body_type == "1" {
                                     self.star = body.clone();
                                 }
                                 self.celestials.insert(body.id, body);
                             }
                         },
-                        // _ => {}
+                        Game::EnvUpdate(env) => {
+                            self.clock = Some((env.time, Instant::now()));
+                            for update in env.bodies {
+                                if let Some(body) = self.celestials.get_mut(&update.id) {
+                                    update.apply(body);
+                                }
+                            }
+                        },
+                        Game::EnvLeave(ids) => {
+                            for id in ids {
+                                self.celestials.remove(&id);
+                            }
+                        },
+                        _ => {}
                     }
                 }
             }
@@ -135,6 +199,20 @@ impl App {
         Ok(())
     }
 
+    /// Moves the orbiting bodies to where they are now, from the last galaxy time received.
+    fn extrapolate(&mut self) {
+        let Some((time, received_at)) = self.clock else {
+            return;
+        };
+        let time = time + received_at.elapsed().as_secs_f64() * TIME_SCALE;
+        let known = self.celestials.clone();
+        for body in self.celestials.values_mut() {
+            if let Some(coords) = body.position_at(&known, time) {
+                body.coords = coords;
+            }
+        }
+    }
+
     fn draw(&mut self, f: &mut Frame) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
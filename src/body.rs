@@ -1,15 +1,44 @@
+use crate::orbit::Orbit;
 use rstar::{RTreeObject, AABB};
 use scilib::coordinate::cartesian::Cartesian;
-use sqlx::sqlite::SqliteRow;
-use sqlx::Row;
+
+pub const GRAVITATIONAL_CONSTANT: f64 = 1f64;
+pub const GRAVITY_SOFTENING: f64 = 10f64;
 
 #[derive(Clone, Debug, Default)]
 pub struct Body {
     pub(crate) id: u32,
     pub(crate) coords: Cartesian,
+    /// Mean motion of the body on its orbit, in radians per galaxy time unit.
     pub(crate) rotating_speed: f64,
     pub(crate) gravity_center: u32,
     pub(crate) body_type: u8,
+    pub(crate) mass: f64,
+    pub(crate) orbit: Orbit,
+}
+
+impl Body {
+    pub fn is_orbiting(&self) -> bool {
+        self.gravity_center != self.id && self.gravity_center != 0
+    }
+
+    pub fn mean_anomaly(&self, time: f64) -> f64 {
+        self.orbit.mean_anomaly_epoch + self.rotating_speed * time
+    }
+
+    /// Position relative to the gravity center at the given galaxy time.
+    pub fn local_position(&self, time: f64) -> Cartesian {
+        self.orbit.position(self.mean_anomaly(time))
+    }
+
+    pub fn gravity_at(&self, coords: Cartesian) -> Cartesian {
+        if self.mass <= 0f64 {
+            return Cartesian::default();
+        }
+        let to_body = self.coords - coords;
+        let distance_sq = to_body.norm().powi(2) + GRAVITY_SOFTENING.powi(2);
+        to_body / distance_sq.sqrt() * (GRAVITATIONAL_CONSTANT * self.mass / distance_sq)
+    }
 }
 
 impl PartialEq for Body {
@@ -28,19 +57,3 @@ impl RTreeObject for Body {
         AABB::from_point([self.coords.x, self.coords.y, self.coords.z])
     }
 }
-
-impl From<&SqliteRow> for Body {
-    fn from(value: &SqliteRow) -> Self {
-        Body {
-            id: value.get(0),
-            body_type: value.get(1),
-            coords: Cartesian {
-                x: value.get(2),
-                y: value.get(3),
-                z: value.get(4),
-            },
-            rotating_speed: value.get(5),
-            gravity_center: value.get(6),
-        }
-    }
-}
@@ -1,5 +1,6 @@
 use crate::error::Error;
-use crate::protocol::{IntoMessage, ShipState};
+use crate::protocol::state::{Game, Hello, Rejection};
+use crate::protocol::{Command, Encoding, Pong, ShipState, Spectate, Target};
 use crate::tls::{get_connector, ClientPki};
 use crate::{
     protocol::{Action, Login},
@@ -8,12 +9,15 @@ use crate::{
 use futures::SinkExt;
 use rustls_pki_types::ServerName;
 use scilib::coordinate::cartesian::Cartesian;
+use serde::Serialize;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
 use tokio_stream::StreamExt;
 use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
 use tokio_tungstenite::WebSocketStream;
 
@@ -22,6 +26,9 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     stream: WebSocketStream<S>,
+    encoding: Encoding,
+    hello: Option<Hello>,
+    session_token: Option<String>,
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> Bot<S> {
@@ -34,6 +41,15 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Bot<S> {
             .map_err(|err| Error::WsCantRead(err))?;
         Ok(message)
     }
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Encoding of the next actions, the server answering in the encoding of the login action.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
     pub async fn terminate(&mut self) -> Result<()> {
         self.stream
             .close(None)
@@ -42,30 +58,81 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Bot<S> {
         Ok(())
     }
 
-    pub async fn login(&mut self, nickname: &str) -> Result<u32> {
-        self.send_action(Action::Login(Login {
-            nickname: nickname.to_string(),
-        }))
-        .await?;
+    pub async fn login(&mut self, nickname: &str, password: &str) -> Result<u32> {
+        self.login_with(Login::new(nickname, password)).await
+    }
 
-        let response = self.next_message().await?;
-        match response {
-            Message::Text(response_str) => {
-                let login_info: crate::protocol::state::Auth = serde_json::from_str(&response_str)
-                    .map_err(|err| Error::DeserializeAuthenticationResponseError(err, response_str.to_string()))?;
+    /// Logs in with an arbitrary login action, to declare another version or other capabilities.
+    pub async fn login_with(&mut self, login: Login) -> Result<u32> {
+        self.authenticate(Action::Login(login)).await
+    }
 
-                let uuid = u32::from_str(login_info.message.as_str())
-                    .map_err(|_err| Error::BadUuidError(login_info.message))?;
+    /// Creates the account `nickname` and logs in.
+    pub async fn register(&mut self, nickname: &str, password: &str) -> Result<u32> {
+        self.register_with(Login::new(nickname, password)).await
+    }
 
-                return Ok(uuid);
-            }
-            _ => return Err(Error::UnexpectedResponse(format!("{:?}", response))),
+    pub async fn register_with(&mut self, login: Login) -> Result<u32> {
+        self.authenticate(Action::Register(login)).await
+    }
+
+    /// Watches `target` read-only, without a player.
+    pub async fn spectate(&mut self, target: Option<Target>) -> Result<u32> {
+        self.authenticate(Action::Spectate(Spectate::new(target))).await
+    }
+
+    /// Changes what a spectator watches.
+    pub async fn watch(&mut self, target: Option<Target>) -> Result<()> {
+        self.send_action(Action::Watch(target)).await
+    }
+
+    /// Takes back the session of a dropped connection, `token` being its `session_token`.
+    pub async fn resume(&mut self, token: &str) -> Result<u32> {
+        self.authenticate(Action::Resume {
+            token: token.to_string(),
+        })
+        .await
+    }
+
+    async fn authenticate(&mut self, action: Action) -> Result<u32> {
+        self.send_action(action).await?;
+
+        let response = self.next_message().await?;
+        if Encoding::of(&response).is_none() {
+            return Err(Error::UnexpectedResponse(format!("{:?}", response)));
         }
+        if let Ok(Game::Error { code, message }) = Encoding::decode(&response) {
+            return Err(Error::ServerError(code, message));
+        }
+        let login_info: crate::protocol::state::Auth = Encoding::decode(&response)?;
+        if !login_info.success {
+            return Err(Error::LoginRejected(
+                login_info.rejection.unwrap_or(Rejection::AuthenticationFailed),
+                login_info.message,
+            ));
+        }
+
+        let uuid =
+            u32::from_str(login_info.message.as_str()).map_err(|_err| Error::BadUuidError(login_info.message))?;
+        self.hello = login_info.hello;
+        self.session_token = login_info.token;
+
+        Ok(uuid)
     }
 
-    async fn send_action<T: IntoMessage>(&mut self, action: T) -> Result<()> {
+    /// Server description received at login.
+    pub fn hello(&self) -> Option<&Hello> {
+        self.hello.as_ref()
+    }
+
+    /// Token to resume the session from another connection if this one drops.
+    pub fn session_token(&self) -> Option<&str> {
+        self.session_token.as_deref()
+    }
+
+    async fn send_action<T: Serialize>(&mut self, action: T) -> Result<()> {
         self.stream
-            .send(action.to_message()?)
+            .send(self.encoding.encode(&action)?)
             .await
             .map_err(|err| Error::WsCantSend(err))?;
         Ok(())
@@ -74,23 +141,57 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Bot<S> {
     pub async fn move_in_space(&mut self, direction: Cartesian) -> Result<()> {
         self.send_action(Action::ShipState(ShipState {
             throttle_up: true,
+            brake: false,
             direction: [direction.x, direction.y, direction.z],
         }))
         .await?;
         Ok(())
     }
 
-    pub async fn next_game_info(&mut self) -> Result<crate::protocol::state::Game> {
-        let next = self.next_message().await?;
+    pub async fn brake(&mut self) -> Result<()> {
+        self.send_action(Action::ShipState(ShipState {
+            throttle_up: false,
+            brake: true,
+            direction: [0.; 3],
+        }))
+        .await?;
+        Ok(())
+    }
+
+    /// Next game state sent by the server, pings being answered on the fly.
+    ///
+    /// `Game::Error` is returned as `Error::ServerError`, notices are returned as is.
+    pub async fn next_game_info(&mut self) -> Result<Game> {
+        loop {
+            let next = self.next_message().await?;
+
+            if Encoding::of(&next).is_none() {
+                return Err(Error::UnexpectedResponse(format!("{:?}", next)));
+            }
+            let game_info = Encoding::decode(&next)?;
 
-        match next {
-            Message::Text(text) => {
-                let game_info =
-                    serde_json::from_str(&text).map_err(|err| Error::DeserializeError(text.to_string(), err))?;
-                Ok(game_info)
+            match game_info {
+                Game::Error { code, message } => return Err(Error::ServerError(code, message)),
+                Game::Ping(ping) => {
+                    let client_time = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs_f64())
+                        .ok();
+                    self.send_action(Action::Pong(Pong::new(ping.seq, client_time))).await?;
+                }
+                game_info => return Ok(game_info),
             }
-            _ => {
-                unreachable!()
+        }
+    }
+
+    /// Runs a privileged command, returning the answer of the server.
+    ///
+    /// Game states and notices received in the meantime are dropped.
+    pub async fn command(&mut self, command: Command) -> Result<String> {
+        self.send_action(Action::Admin(command)).await?;
+        loop {
+            if let Game::Answer { message } = self.next_game_info().await? {
+                return Ok(message);
             }
         }
     }
@@ -99,7 +200,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Bot<S> {
         loop {
             let game_info = self.next_game_info().await?;
 
-            if let crate::protocol::state::Game::Player(player) = game_info {
+            if let Game::Player(player) = game_info {
                 return Ok(player);
             }
         }
@@ -124,19 +225,36 @@ async fn connect_tcp(url: &str) -> Result<TcpStream> {
     Ok(stream)
 }
 
-async fn connect_websocket<S>(request: Request, stream: S) -> Result<Bot<S>>
+async fn connect_websocket<S>(mut request: Request, stream: S, encoding: Option<Encoding>) -> Result<Bot<S>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    if let Some(encoding) = encoding {
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(encoding.subprotocol()));
+    }
     match tokio_tungstenite::client_async(request, stream).await {
-        Ok((stream, _response)) => return Ok(Bot::<S> { stream }),
+        Ok((stream, _response)) => {
+            return Ok(Bot::<S> {
+                stream,
+                encoding: encoding.unwrap_or_default(),
+                hello: None,
+                session_token: None,
+            })
+        }
         Err(err) => {
             return Err(Error::WebSocketUpgrade(err));
         }
     }
 }
 
-pub async fn connect_secure(hostname: &str, port: u16, pki: ClientPki<'_>) -> Result<Bot<TlsStream<TcpStream>>> {
+async fn connect_secure_stream(
+    hostname: &str,
+    port: u16,
+    pki: ClientPki<'_>,
+    encoding: Option<Encoding>,
+) -> Result<Bot<TlsStream<TcpStream>>> {
     let (socket_addr, request) = build_params(hostname, port, true)?;
     let stream = connect_tcp(socket_addr.as_str()).await?;
 
@@ -149,13 +267,36 @@ pub async fn connect_secure(hostname: &str, port: u16, pki: ClientPki<'_>) -> Re
         .await
         .map_err(|err| Error::CouldNotUpgradeToTls(err))?;
 
-    let stream = connect_websocket(request, stream).await?;
+    let stream = connect_websocket(request, stream, encoding).await?;
     Ok(stream)
 }
 
-pub async fn connect_plain(hostname: &str, port: u16) -> Result<Bot<TcpStream>> {
+async fn connect_plain_stream(hostname: &str, port: u16, encoding: Option<Encoding>) -> Result<Bot<TcpStream>> {
     let (socket_addr, request) = build_params(hostname, port, true)?;
     let stream = connect_tcp(socket_addr.as_str()).await?;
-    let stream = connect_websocket(request, stream).await?;
+    let stream = connect_websocket(request, stream, encoding).await?;
     Ok(stream)
 }
+
+pub async fn connect_secure(hostname: &str, port: u16, pki: ClientPki<'_>) -> Result<Bot<TlsStream<TcpStream>>> {
+    connect_secure_stream(hostname, port, pki, None).await
+}
+
+pub async fn connect_plain(hostname: &str, port: u16) -> Result<Bot<TcpStream>> {
+    connect_plain_stream(hostname, port, None).await
+}
+
+/// Connects with `encoding` negotiated through the WebSocket subprotocol.
+pub async fn connect_secure_with_encoding(
+    hostname: &str,
+    port: u16,
+    pki: ClientPki<'_>,
+    encoding: Encoding,
+) -> Result<Bot<TlsStream<TcpStream>>> {
+    connect_secure_stream(hostname, port, pki, Some(encoding)).await
+}
+
+/// Connects with `encoding` negotiated through the WebSocket subprotocol.
+pub async fn connect_plain_with_encoding(hostname: &str, port: u16, encoding: Encoding) -> Result<Bot<TcpStream>> {
+    connect_plain_stream(hostname, port, Some(encoding)).await
+}
@@ -1,69 +1,56 @@
 use crate::error::Error;
-use crate::protocol::Action;
-use crate::{body::Body, player::Player, sqldb::SqlDb};
+use crate::outbox::{outbox, OutboxReceiver, OUTBOX_CAPACITY};
+use crate::protocol::{Action, Role};
+use crate::storage::{PlayerState, Snapshot, Storage};
+use crate::{body::Body, player::Player};
 use crate::{spacebuild_log, Result};
 use is_printable::IsPrintable;
-use sqlx::Row;
 use std::vec;
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, Sender};
+
+/// Whether `body` differs from its `saved` state, the coordinates of an orbiting body following from its orbit.
+fn body_changed(saved: &Body, body: &Body) -> bool {
+    saved.body_type != body.body_type
+        || saved.rotating_speed != body.rotating_speed
+        || saved.gravity_center != body.gravity_center
+        || saved.mass != body.mass
+        || saved.orbit != body.orbit
+        || (!body.is_orbiting() && saved.coords != body.coords)
+}
 
 pub struct BodyCache {
     pub(crate) cache: HashMap<u32, Body>,
-    db: Arc<Mutex<SqlDb>>,
+    /// Bodies as last written to or read from the storage, to write only the ones that changed since.
+    saved: HashMap<u32, Body>,
+    /// Id of the next new body, allocated without the storage, new bodies being written as changes.
+    pub(crate) next_id: u32,
+    storage: Arc<dyn Storage>,
 }
 
 impl BodyCache {
-    pub fn new(db: Arc<Mutex<SqlDb>>) -> Self {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
             cache: HashMap::new(),
-            db: db.clone(),
+            saved: HashMap::new(),
+            next_id: 1,
+            storage,
         }
     }
 
-    pub(crate) async fn init_db(&mut self) {
-        self.db
-            .lock()
-            .await
-            .create_table(
-                "Body",
-                vec![
-                    "id INTEGER PRIMARY KEY AUTOINCREMENT",
-                    "type INTEGER",
-                    "coord_x REAL",
-                    "coord_y REAL",
-                    "coord_z REAL",
-                    "rotating_speed REAL",
-                    "gravity_center INTEGER",
-                    "FOREIGN KEY (gravity_center) REFERENCES Body (id)",
-                ],
-                vec!["id", "gravity_center"],
-            )
-            .await
-            .unwrap();
-    }
-
     pub fn get_body(&mut self, id: u32) -> &Body {
         self.cache.get(&id).unwrap()
     }
 
-    pub(crate) async fn load_body(&mut self, id: u32) -> &Body {
+    pub(crate) async fn load_body(&mut self, id: u32) -> Result<&Body> {
         if self.cache.contains_key(&id) {
-            return self.cache.get(&id).unwrap();
+            return Ok(self.cache.get(&id).unwrap());
         }
-        let body: Body = self
-            .db
-            .lock()
-            .await
-            .select_from_where_equals("Body", "id", id.to_string().as_str())
-            .await
-            .first()
-            .unwrap()
-            .into();
+        let body = self.storage.load_body(id).await?.ok_or(Error::BodyNotFound(id))?;
 
+        self.saved.insert(id, body.clone());
         self.cache.insert(id, body);
-        self.cache.get(&id).unwrap()
+        Ok(self.cache.get(&id).unwrap())
     }
 
     pub(crate) fn add_body(&mut self, id: u32, body: Body) -> &Body {
@@ -71,361 +58,301 @@ impl BodyCache {
         self.cache.get(&id).unwrap()
     }
 
-    pub(crate) async fn save_all(&self) -> () {
-        let mut rows = vec![];
-
-        for (_id, body) in &self.cache {
-            rows.push(vec![
-                body.id.to_string(),
-                body.body_type.to_string(),
-                body.coords.x.to_string(),
-                body.coords.y.to_string(),
-                body.coords.z.to_string(),
-                body.rotating_speed.to_string(),
-                body.gravity_center.to_string(),
-            ]);
+    fn is_changed(&self, body: &Body) -> bool {
+        self.saved.get(&body.id).is_none_or(|saved| body_changed(saved, body))
+    }
+
+    /// Bodies that changed since they were saved.
+    pub(crate) fn changes(&self) -> Vec<Body> {
+        self.cache
+            .values()
+            .filter(|body| self.is_changed(body))
+            .cloned()
+            .collect()
+    }
+
+    /// Records `bodies` as written to the storage.
+    pub(crate) fn mark_saved(&mut self, bodies: Vec<Body>) {
+        for body in bodies {
+            self.saved.insert(body.id, body);
         }
-        if !rows.is_empty() {
-            self.db
-                .lock()
-                .await
-                .insert_rows_into(
-                    "Body",
-                    Some(vec![
-                        "id".to_string(),
-                        "type".to_string(),
-                        "coord_x".to_string(),
-                        "coord_y".to_string(),
-                        "coord_z".to_string(),
-                        "rotating_speed".to_string(),
-                        "gravity_center".to_string(),
-                    ]),
-                    rows,
-                    vec![
-                        ("type", "type"),
-                        ("coord_x", "coord_x"),
-                        ("coord_y", "coord_y"),
-                        ("coord_z", "coord_z"),
-                        ("rotating_speed", "rotating_speed"),
-                        ("gravity_center", "gravity_center"),
-                    ],
-                )
-                .await;
+    }
+
+    /// Writes the bodies that changed since they were saved, returning how many.
+    pub async fn save_all(&mut self) -> Result<usize> {
+        let changes = self.changes();
+        let saved = changes.len();
+        self.storage
+            .write(&[Snapshot {
+                bodies: changes.clone(),
+                ..Default::default()
+            }])
+            .await?;
+        self.mark_saved(changes);
+        Ok(saved)
+    }
+
+    /// Removes the bodies from the cache, returning the ones that changed since they were saved.
+    pub(crate) fn unload(&mut self, bodies: Vec<Body>) -> Vec<Body> {
+        let changes: Vec<Body> = bodies.iter().filter(|body| self.is_changed(body)).cloned().collect();
+        for body in bodies {
+            self.cache.remove(&body.id);
+            self.saved.remove(&body.id);
         }
+        changes
     }
 
-    pub async fn load_gravitings(&mut self, id: u32) -> Vec<Body> {
+    pub async fn load_gravitings(&mut self, id: u32) -> Result<Vec<Body>> {
         let mut ids = vec![id];
         let mut prev_ids = vec![];
         let mut bodies: Vec<Body> = Vec::new();
         while !ids.is_empty() {
             let id = ids.pop().unwrap();
             prev_ids.push(id);
-            let body_rows = self
-                .db
-                .lock()
-                .await
-                .select_from_where_equals("Body", "gravity_center", id.to_string().as_str())
-                .await;
-            for row in body_rows {
-                let body: Body = (&row).into();
+            let gravitings = self.storage.load_gravitings(id).await?;
+            for body in gravitings {
                 if !ids.contains(&body.id) && !prev_ids.contains(&body.id) {
                     ids.push(body.id);
                 }
                 if !self.cache.contains_key(&body.id) {
+                    self.saved.insert(body.id, body.clone());
                     bodies.push(body);
                 }
             }
         }
-        bodies
+        Ok(bodies)
     }
 
-    pub(crate) fn sync(&mut self, bodies: Vec<&Body>) -> () {
+    pub(crate) fn sync(&mut self, bodies: Vec<Body>) -> () {
         for body in bodies {
-            self.add_body(body.id, body.clone());
+            self.add_body(body.id, body);
         }
     }
 
-    pub(crate) async fn new_body(&mut self, body_type: u8) -> &mut Body {
-        let mut new_body = Body {
-            body_type,
-            ..Default::default()
-        };
-        let id = {
-            let mut db = self.db.lock().await;
-            new_body.id = db
-                .insert_row_into(
-                    "Body",
-                    Some(vec![
-                        "type".to_string(),
-                        "coord_x".to_string(),
-                        "coord_y".to_string(),
-                        "coord_z".to_string(),
-                    ]),
-                    vec![
-                        new_body.body_type.to_string(),
-                        new_body.coords.x.to_string(),
-                        new_body.coords.y.to_string(),
-                        new_body.coords.z.to_string(),
-                    ],
-                    vec![],
-                )
-                .await;
-            new_body.id
-        };
-        self.add_body(new_body.id, new_body);
+    pub(crate) fn new_body(&mut self, body_type: u8) -> &mut Body {
+        let id = self.new_bodies(body_type, 1);
         self.cache.get_mut(&id).unwrap()
     }
 
-    pub(crate) async fn new_bodies(&mut self, body_type: u8, cnt: i32) -> u32 {
-        let mut new_bodies: Vec<Body> = Vec::new();
-        let mut bodies_rows = Vec::new();
+    pub(crate) fn new_bodies(&mut self, body_type: u8, cnt: i32) -> u32 {
         for _ in 0..cnt {
-            let new_body = Body {
-                body_type,
-                ..Default::default()
-            };
-            bodies_rows.push(vec![
-                body_type.to_string(),
-                new_body.coords.x.to_string(),
-                new_body.coords.y.to_string(),
-                new_body.coords.z.to_string(),
-            ]);
-            new_bodies.push(new_body);
+            let id = self.next_id;
+            self.next_id += 1;
+            self.cache.insert(
+                id,
+                Body {
+                    id,
+                    body_type,
+                    ..Default::default()
+                },
+            );
         }
-        let last_id = {
-            let db = self.db.lock().await;
-            db.insert_rows_into(
-                "Body",
-                Some(vec![
-                    "type".to_string(),
-                    "coord_x".to_string(),
-                    "coord_y".to_string(),
-                    "coord_z".to_string(),
-                ]),
-                bodies_rows,
-                vec![],
-            )
-            .await
-        };
-        for i in 0..cnt {
-            let body = new_bodies.get_mut(i as usize).unwrap();
-            body.id = last_id - i as u32;
-            self.cache.insert(last_id - i as u32, body.clone());
-        }
-        last_id
+        self.next_id - 1
+    }
+}
+
+fn player_state(player: &Player) -> PlayerState {
+    PlayerState {
+        id: player.id,
+        nickname: player.nickname.clone(),
+        coords: player.coords,
+        velocity: player.velocity,
+        direction: player.direction,
+        current_system: player.current_system,
     }
 }
 
 pub struct PlayerCache {
     pub(crate) cache: HashMap<u32, Player>,
-    pub db: Arc<Mutex<SqlDb>>,
+    /// States as last written to or read from the storage, to write only the players that changed since.
+    saved: HashMap<u32, PlayerState>,
+    storage: Arc<dyn Storage>,
 }
 
 impl PlayerCache {
-    pub(crate) async fn init_db(&mut self) {
-        self.db
-            .lock()
-            .await
-            .create_table(
-                "Player",
-                vec![
-                    "id INTEGER PRIMARY KEY",
-                    "nickname TEXT",
-                    "coord_x REAL",
-                    "coord_y REAL",
-                    "coord_z REAL",
-                    "direction_x REAL",
-                    "direction_y REAL",
-                    "direction_z REAL",
-                    "current_system INTEGER",
-                ],
-                vec!["id", "nickname"],
-            )
-            .await
-            .unwrap();
-    }
-
     pub fn get_player(&mut self, id: u32) -> &Player {
         self.cache.get(&id).unwrap()
     }
 
-    pub async fn can_login(&mut self, nickname: String) -> Result<()> {
+    fn check_nickname(nickname: &str) -> Result<()> {
         if nickname.is_empty() || !nickname.is_printable() {
             return Err(Error::InvalidNickname);
         }
+        Ok(())
+    }
 
-        for (_, player) in &self.cache {
-            if player.nickname == nickname {
-                return Err(Error::PlayerAlreadyAuthenticated);
-            }
+    pub async fn can_login(&mut self, nickname: String) -> Result<()> {
+        Self::check_nickname(&nickname)?;
+
+        if self
+            .cache
+            .values()
+            .any(|player| player.nickname.eq_ignore_ascii_case(&nickname))
+        {
+            return Err(Error::PlayerAlreadyAuthenticated);
         }
 
-        let query_result = self
-            .db
-            .lock()
-            .await
-            .select_from_where_like("Player", "nickname", &nickname)
-            .await;
-
-        if query_result.is_empty() {
+        if self.storage.find_player(&nickname).await?.is_none() {
             return Err(Error::PlayerIsNew);
         };
         Ok(())
     }
 
-    // pub async fn sync(&mut self, id: u32) -> u32 {
-    //     let player = self.cache.get(&id).unwrap();
-    //     self.db
-    //         .lock()
-    //         .await
-    //         .insert_row_into(
-    //             "Player",
-    //             Some(vec![
-    //                 "id".to_string(),
-    //                 "nickname".to_string(),
-    //                 "coord_x".to_string(),
-    //                 "coord_y".to_string(),
-    //                 "coord_z".to_string(),
-    //             ]),
-    //             vec![
-    //                 id.to_string(),
-    //                 format!("\"{}\"", player.nickname),
-    //                 player.coords.x.to_string(),
-    //                 player.coords.y.to_string(),
-    //                 player.coords.z.to_string(),
-    //             ],
-    //             vec![("coord_x", "coord_x"), ("coord_y", "coord_y"), ("coord_z", "coord_z")],
-    //         )
-    //         .await
-    // }
-
-    pub async fn sync_and_unload(&mut self, id: u32) {
-        self.save(id).await;
-        self.cache.remove(&id);
+    /// Password hash of an account, `None` for accounts created before passwords.
+    pub async fn password_hash(&mut self, nickname: &str) -> Result<Option<String>> {
+        Self::check_nickname(nickname)?;
+
+        match self.storage.find_player(nickname).await? {
+            Some(account) => Ok(account.password_hash),
+            None => Err(Error::InvalidCredentials),
+        }
     }
 
-    pub(crate) async fn load(
-        &mut self,
-        nickname: String,
-    ) -> (u32, Sender<Action>, Receiver<crate::protocol::state::Game>) {
-        let result = self
-            .db
-            .lock()
-            .await
-            .select_from_where_like("Player", "nickname", &nickname)
-            .await;
+    /// Checks that `nickname` is free, accounts without password included: they are given one from the console.
+    pub async fn can_register(&mut self, nickname: &str) -> Result<()> {
+        if self
+            .cache
+            .values()
+            .any(|player| player.nickname.eq_ignore_ascii_case(nickname))
+        {
+            return Err(Error::NicknameTaken);
+        }
+        match self.password_hash(nickname).await {
+            Err(Error::InvalidCredentials) => Ok(()),
+            Ok(_) => Err(Error::NicknameTaken),
+            Err(err) => Err(err),
+        }
+    }
 
-        assert!(result.len() == 1);
+    pub async fn set_role(&mut self, nickname: &str, role: Role) -> Result<()> {
+        match self.password_hash(nickname).await {
+            Err(Error::InvalidCredentials) => return Err(Error::UnknownPlayer(nickname.to_string())),
+            Err(err) => return Err(err),
+            Ok(_) => {}
+        }
+        self.storage.set_role(nickname, role).await?;
+        if let Some(player) = self
+            .cache
+            .values_mut()
+            .find(|player| player.nickname.eq_ignore_ascii_case(nickname))
+        {
+            player.role = role;
+        }
+        Ok(())
+    }
 
-        let (action_send, action_recv) = mpsc::channel(10000);
-        let (state_send, state_recv) = mpsc::channel(10000);
-        let mut player = Player::new(nickname, state_send, action_recv);
+    pub async fn set_password_hash(&mut self, nickname: &str, password_hash: &str) -> Result<()> {
+        match self.password_hash(nickname).await {
+            Err(Error::InvalidCredentials) => return Err(Error::UnknownPlayer(nickname.to_string())),
+            Err(err) => return Err(err),
+            Ok(_) => {}
+        }
+        self.storage.set_password_hash(nickname, password_hash).await
+    }
 
-        let row = result.first().unwrap();
+    /// Removes the player from the cache, returning its state if it changed since it was saved.
+    pub(crate) fn unload(&mut self, id: u32) -> Option<PlayerState> {
+        let player = self.cache.remove(&id)?;
+        let state = player_state(&player);
+        (self.saved.remove(&id).as_ref() != Some(&state)).then_some(state)
+    }
 
-        player.id = row.get(0);
-        player.coords.x = row.get(2);
-        player.coords.y = row.get(3);
-        player.coords.z = row.get(4);
-        player.direction.x = row.get(5);
-        player.direction.y = row.get(6);
-        player.direction.z = row.get(7);
-        player.current_system = row.get(8);
+    pub async fn sync_and_unload(&mut self, id: u32) -> Result<()> {
+        let Some(state) = self.unload(id) else {
+            return Ok(());
+        };
+        self.storage
+            .write(&[Snapshot {
+                players: vec![state],
+                ..Default::default()
+            }])
+            .await
+    }
+
+    pub(crate) async fn load(&mut self, nickname: String) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        let account = self
+            .storage
+            .find_player(&nickname)
+            .await?
+            .ok_or(Error::DbLoadPlayerByNicknameNotFound)?;
+
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let (state_send, state_recv) = outbox(OUTBOX_CAPACITY);
+        // Nicknames are typed in any case, the player keeps the one of its account.
+        let mut player = Player::new(account.state.nickname, state_send, action_recv);
+        player.role = account.role;
+        player.id = account.state.id;
+        player.coords = account.state.coords;
+        player.velocity = account.state.velocity;
+        player.direction = account.state.direction;
+        player.current_system = account.state.current_system;
 
         let player_id = player.id;
+        self.saved.insert(player.id, player_state(&player));
         self.cache.insert(player.id, player);
 
-        (player_id, action_send, state_recv)
+        Ok((player_id, action_send, state_recv))
     }
 
     pub(crate) async fn new_player(
         &mut self,
         nickname: String,
-    ) -> (&mut Player, Sender<Action>, Receiver<crate::protocol::state::Game>) {
+        password_hash: &str,
+    ) -> Result<(&mut Player, Sender<Action>, OutboxReceiver)> {
+        if self.storage.find_player(&nickname).await?.is_some() {
+            return Err(Error::NicknameTaken);
+        }
         let (action_send, action_recv) = mpsc::channel(10000);
-        let (state_send, state_recv) = mpsc::channel(10000);
+        let (state_send, state_recv) = outbox(OUTBOX_CAPACITY);
         let mut new_player = Player::new(nickname.clone(), state_send, action_recv);
-        let id = {
-            let mut db = self.db.lock().await;
-            if !db
-                .select_from_where_like("Player", "nickname", &nickname)
-                .await
-                .is_empty()
-            {
-                panic!("Player {} already exists", nickname);
-            }
-            new_player.id = db
-                .insert_row_into(
-                    "Player",
-                    Some(vec!["nickname".to_string()]),
-                    vec![format!("\"{}\"", nickname)],
-                    vec![],
-                )
-                .await;
-            // new_player.id = db.last_insert_id().await;
-            spacebuild_log!(info, "cache", "last insert id: {}", new_player.id);
-            new_player.id
-        };
+        new_player.id = self.storage.insert_player(&nickname, password_hash).await?;
+        spacebuild_log!(info, "cache", "last insert id: {}", new_player.id);
+        let id = new_player.id;
         self.cache.insert(id, new_player);
         let player = self.cache.get_mut(&id).unwrap();
-        (player, action_send, state_recv)
+        Ok((player, action_send, state_recv))
     }
 
-    pub(crate) fn new(db: Arc<Mutex<SqlDb>>) -> Self {
+    pub(crate) fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
             cache: HashMap::new(),
-            db,
+            saved: HashMap::new(),
+            storage,
         }
     }
 
-    pub async fn save(&self, id: u32) {
-        let player = self.cache.get(&id).unwrap();
-        self.db
-            .lock()
-            .await
-            .insert_row_into(
-                "Player",
-                Some(vec![
-                    "id".to_string(),
-                    "nickname".to_string(),
-                    "coord_x".to_string(),
-                    "coord_y".to_string(),
-                    "coord_z".to_string(),
-                    "direction_x".to_string(),
-                    "direction_y".to_string(),
-                    "direction_z".to_string(),
-                    "current_system".to_string(),
-                ]),
-                vec![
-                    player.id.to_string(),
-                    // fixme: sql injection?
-                    format!("\"{}\"", player.nickname),
-                    player.coords.x.to_string(),
-                    player.coords.y.to_string(),
-                    player.coords.z.to_string(),
-                    player.direction.x.to_string(),
-                    player.direction.y.to_string(),
-                    player.direction.z.to_string(),
-                    player.current_system.to_string(),
-                ],
-                vec![
-                    ("nickname", "nickname"),
-                    ("coord_x", "coord_x"),
-                    ("coord_y", "coord_y"),
-                    ("coord_z", "coord_z"),
-                    ("direction_x", "direction_x"),
-                    ("direction_y", "direction_y"),
-                    ("direction_z", "direction_z"),
-                    ("current_system", "current_system"),
-                ],
-            )
-            .await;
+    /// States of the players that changed since they were saved.
+    pub(crate) fn changes(&self) -> Vec<PlayerState> {
+        self.cache
+            .values()
+            .map(player_state)
+            .filter(|state| self.saved.get(&state.id) != Some(state))
+            .collect()
     }
 
-    pub(crate) async fn save_all(&self) {
-        for (id, _) in &self.cache {
-            self.save(*id).await;
+    /// Records `states` as written to the storage.
+    pub(crate) fn mark_saved(&mut self, states: Vec<PlayerState>) {
+        for state in states {
+            self.saved.insert(state.id, state);
         }
     }
+
+    /// Writes the player if it changed since it was saved.
+    pub async fn save(&mut self, id: u32) -> Result<()> {
+        let Some(player) = self.cache.get(&id) else {
+            return Ok(());
+        };
+        let state = player_state(player);
+        if self.saved.get(&id) == Some(&state) {
+            return Ok(());
+        }
+        self.storage
+            .write(&[Snapshot {
+                players: vec![state.clone()],
+                ..Default::default()
+            }])
+            .await?;
+        self.saved.insert(id, state);
+        Ok(())
+    }
 }
@@ -1,3 +1,6 @@
+use crate::protocol::state::{ErrorCode, Rejection};
+use crate::protocol::Role;
+use crate::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use rustls_pki_types::InvalidDnsNameError;
 use tokio::io;
 use tokio_tungstenite::tungstenite;
@@ -48,6 +51,8 @@ pub enum Error {
     DbSelectFromJoinedIdsError(String, String, String, sqlx::Error),
     #[error("Can't select in '{0}' with where clause '{1}': {2}")]
     DbSelectFromWhereError(String, String, sqlx::Error),
+    #[error("Can't select in '{0}': {1}")]
+    DbSelectError(String, sqlx::Error),
     #[error("DB file creation error {0}")]
     DbFileCreationError(std::io::Error),
     #[error("DB invalid ID: {0}")]
@@ -112,4 +117,128 @@ pub enum Error {
     InvalidJson(serde_json::Error),
     #[error("Login error: {0}")]
     Login(String),
+    #[error("Login rejected ({0:?}): {1}")]
+    LoginRejected(Rejection, String),
+    #[error("Server error ({0:?}): {1}")]
+    ServerError(ErrorCode, String),
+    #[error("Not a data message")]
+    NotADataMessage,
+    #[error("JSON: can't serialize: {0}")]
+    SerializeError(serde_json::Error),
+    #[error("MessagePack: can't serialize: {0}")]
+    MessagePackEncodeError(rmp_serde::encode::Error),
+    #[error("MessagePack: can't deserialize: {0}")]
+    MessagePackDecodeError(rmp_serde::decode::Error),
+    #[error("Invalid nickname or password")]
+    InvalidCredentials,
+    #[error("Nickname already taken")]
+    NicknameTaken,
+    #[error("Password must be at least {0} characters long")]
+    PasswordTooShort(usize),
+    #[error("Account has no password yet, an operator has to set one")]
+    PasswordNotSet,
+    #[error("Password hashing error: {0}")]
+    PasswordHashError(String),
+    #[error("Protocol version {0} is not supported, expected {min} to {max}", min = MIN_PROTOCOL_VERSION, max = PROTOCOL_VERSION)]
+    IncompatibleProtocol(u32),
+    #[error("Unknown or expired session token")]
+    InvalidSessionToken,
+    #[error("Session is still attached to a connection")]
+    SessionInUse,
+    #[error("Invalid role '{0}'")]
+    InvalidRole(String),
+    #[error("Requires the {0:?} role")]
+    Forbidden(Role),
+    #[error("Unknown player {0}")]
+    UnknownPlayer(String),
+    #[error("Player {0} is not logged in")]
+    PlayerNotLoggedIn(String),
+    #[error("Banned: {0}")]
+    Banned(String),
+    #[error("Invalid IP address or CIDR block '{0}'")]
+    InvalidAddress(String),
+    #[error("Invalid command '{0}'")]
+    InvalidCommand(String),
+    #[error("Too many messages")]
+    RateLimited,
+    #[error("Message larger than {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("Can't update '{0}' where '{1}': {2}")]
+    DbUpdateError(String, String, sqlx::Error),
+    #[error("Changes not written to the database: {0}")]
+    DbWriteFailed(String),
+    #[error("Database transaction failed: {0}")]
+    DbTransactionError(sqlx::Error),
+    #[error("Can't migrate the database to version {0}: {1}")]
+    DbMigrationError(u32, sqlx::Error),
+    #[error("Database schema version {0} is newer than version {1} of this server")]
+    DbSchemaTooNew(u32, u32),
+    #[error("Body {0} not found")]
+    BodyNotFound(u32),
+}
+
+impl Error {
+    /// Code sent to clients when this error ends their session or refuses their action.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::NotTextMessage
+            | Error::NotADataMessage
+            | Error::InvalidJson(_)
+            | Error::DeserializeError(_, _)
+            | Error::MessagePackDecodeError(_) => ErrorCode::InvalidMessage,
+            Error::NotALoginAction => ErrorCode::NotALoginAction,
+            Error::AuthenticationError(_)
+            | Error::PlayerAlreadyAuthenticated
+            | Error::InvalidNickname
+            | Error::InvalidCredentials
+            | Error::NicknameTaken
+            | Error::PasswordTooShort(_)
+            | Error::PasswordNotSet
+            | Error::InvalidSessionToken
+            | Error::SessionInUse
+            | Error::Banned(_)
+            | Error::LoginRejected(_, _) => ErrorCode::AuthenticationFailed,
+            Error::IncompatibleProtocol(_) => ErrorCode::IncompatibleProtocol,
+            Error::Forbidden(_) => ErrorCode::Forbidden,
+            Error::UnknownPlayer(_)
+            | Error::PlayerNotLoggedIn(_)
+            | Error::InvalidRole(_)
+            | Error::InvalidAddress(_)
+            | Error::InvalidCommand(_) => ErrorCode::InvalidCommand,
+            Error::RateLimited => ErrorCode::RateLimited,
+            Error::MessageTooLarge(_) => ErrorCode::MessageTooLarge,
+            Error::ServerError(code, _) => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Reason sent to clients when this error refuses their login.
+    pub fn rejection(&self) -> Rejection {
+        match self {
+            Error::InvalidCredentials | Error::PasswordNotSet => Rejection::InvalidCredentials,
+            Error::NicknameTaken => Rejection::NicknameTaken,
+            Error::PasswordTooShort(_) => Rejection::WeakPassword,
+            Error::IncompatibleProtocol(client) => Rejection::IncompatibleProtocol {
+                client: *client,
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            },
+            Error::InvalidSessionToken => Rejection::InvalidSessionToken,
+            Error::SessionInUse => Rejection::SessionInUse,
+            Error::Banned(reason) => Rejection::Banned(reason.clone()),
+            Error::LoginRejected(rejection, _) => rejection.clone(),
+            _ => Rejection::AuthenticationFailed,
+        }
+    }
+
+    /// Whether writing the same data again fails the same way, the data breaking a constraint of the database.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::SqlDbInsertError(_, sqlx::Error::Database(err))
+            | Error::DbTransactionError(sqlx::Error::Database(err)) => {
+                !matches!(err.kind(), sqlx::error::ErrorKind::Other)
+            }
+            _ => false,
+        }
+    }
 }
@@ -1,107 +1,174 @@
-use super::body::Body;
+use crate::body::Body;
+use crate::error::Error;
+use crate::system::StarSystem;
+use crate::Result;
 use core::f64;
-use rstar::{RTree, AABB};
-use scilib::coordinate::{cartesian::Cartesian, spherical::Spherical};
-use std::f64::consts::PI;
+use rayon::prelude::*;
+use rstar::{RTree, RTreeObject, AABB};
+use scilib::coordinate::cartesian::Cartesian;
+use std::collections::HashMap;
+
+pub const TIME_SCALE: f64 = 10f64;
+pub const REINDEX_PERIOD: f64 = 50f64;
+
+#[derive(Clone, Copy, PartialEq)]
+struct IndexedSystem {
+    star_id: u32,
+    coords: [f64; 3],
+    radius: f64,
+}
+
+impl RTreeObject for IndexedSystem {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        let [x, y, z] = self.coords;
+        let r = self.radius;
+        AABB::from_corners([x - r, y - r, z - r], [x + r, y + r, z + r])
+    }
+}
 
+/// Index of the loaded star systems, each one owning its bodies, and the galaxy clock they are
+/// all evaluated against.
 #[derive(Default)]
 pub struct Galaxy {
-    pub(crate) celestials: RTree<Body>,
+    pub(crate) systems: HashMap<u32, StarSystem>,
+    system_of: HashMap<u32, u32>,
+    index: RTree<IndexedSystem>,
+    pub(crate) time: f64,
 }
 
 impl Galaxy {
-    pub fn insert_celestial(&mut self, celestial: Body) {
-        self.celestials.insert(celestial);
+    pub fn insert_celestial(&mut self, celestial: Body) -> Result<()> {
+        let id = celestial.id;
+        if !celestial.is_orbiting() {
+            self.systems.insert(id, StarSystem::new(celestial, self.time));
+            self.system_of.insert(id, id);
+            self.reindex_system(id);
+            return Ok(());
+        }
+
+        let star_id = *self
+            .system_of
+            .get(&celestial.gravity_center)
+            .ok_or(Error::GravityCenterNotFound)?;
+        let system = self.systems.get_mut(&star_id).unwrap();
+        let radius = system.radius();
+        if !system.insert(celestial, self.time) {
+            return Err(Error::GravityCenterNotFound);
+        }
+        self.system_of.insert(id, star_id);
+        if system.radius() > radius {
+            self.reindex_system(star_id);
+        }
+        Ok(())
+    }
+
+    fn reindex_system(&mut self, star_id: u32) {
+        let system = self.systems.get(&star_id).unwrap();
+        let coords = system.star_coords();
+        let indexed = IndexedSystem {
+            star_id,
+            coords: [coords.x, coords.y, coords.z],
+            radius: system.radius(),
+        };
+        let previous = self.index.iter().find(|indexed| indexed.star_id == star_id).copied();
+        if let Some(previous) = previous {
+            self.index.remove(&previous);
+        }
+        self.index.insert(indexed);
+    }
+
+    pub fn remove_system(&mut self, star_id: u32) -> Option<StarSystem> {
+        let system = self.systems.remove(&star_id)?;
+        for id in system.bodies.keys() {
+            self.system_of.remove(id);
+        }
+        let indexed = self.index.iter().find(|indexed| indexed.star_id == star_id).copied();
+        if let Some(indexed) = indexed {
+            self.index.remove(&indexed);
+        }
+        Some(system)
+    }
+
+    pub fn system_ids(&self) -> Vec<u32> {
+        self.systems.keys().copied().collect()
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn borrow_system(&self, star_id: u32) -> Option<&StarSystem> {
+        self.systems.get(&star_id)
+    }
+
+    pub fn borrow_system_mut(&mut self, star_id: u32) -> Option<&mut StarSystem> {
+        self.systems.get_mut(&star_id)
+    }
+
+    pub fn system_of(&self, id: u32) -> Option<u32> {
+        self.system_of.get(&id).copied()
+    }
+
+    pub fn attach_player(&mut self, star_id: u32, player_id: u32) {
+        if let Some(system) = self.systems.get_mut(&star_id) {
+            system.players.insert(player_id);
+        }
+    }
+
+    pub fn detach_player(&mut self, star_id: u32, player_id: u32) {
+        if let Some(system) = self.systems.get_mut(&star_id) {
+            system.players.remove(&player_id);
+        }
     }
 
     pub fn borrow_bodies(&self) -> Vec<&Body> {
-        self.celestials.iter().collect()
+        self.systems
+            .values()
+            .flat_map(|system| system.bodies.values())
+            .collect()
     }
 
     pub fn borrow_body(&self, id: u32) -> Option<&Body> {
-        self.celestials.iter().find(|g| g.id == id)
+        self.systems.get(&self.system_of(id)?)?.bodies.get(&id)
     }
 
     pub fn borrow_body_mut(&mut self, id: u32) -> Option<&mut Body> {
-        self.celestials.iter_mut().find(|g| g.id == id)
+        let star_id = self.system_of(id)?;
+        self.systems.get_mut(&star_id)?.bodies.get_mut(&id)
     }
 
-    // pub fn _remove_by_id(&mut self, id: Id) -> Option<CelestialBody> {
-    //     self.celestials.remove(&CelestialBody::dummy(id))
-    // }
+    /// Copy of the body with its coordinates evaluated at the current galaxy time.
+    pub fn body(&self, id: u32) -> Option<Body> {
+        self.systems.get(&self.system_of(id)?)?.body(id, self.time)
+    }
 
-    pub fn galactics_in_spherical_view(tree: &RTree<Body>, center: Cartesian, radius: f64) -> Vec<&Body> {
-        let radius_sq = radius * radius;
-        let min = [center.x - radius, center.y - radius, center.z - radius];
-        let max = [center.x + radius, center.y + radius, center.z + radius];
-        tree.locate_in_envelope_intersecting(&AABB::from_corners(min, max))
-            .filter(|g| {
-                let d_sq =
-                    (g.coords.x - center.x).powi(2) + (g.coords.y - center.y).powi(2) + (g.coords.z - center.z).powi(2);
-                d_sq <= radius_sq
-            })
+    /// All bodies with their coordinates evaluated at the current galaxy time.
+    pub fn bodies_now(&self) -> Vec<Body> {
+        self.systems
+            .values()
+            .flat_map(|system| system.bodies_at(self.time))
             .collect()
     }
 
-    pub async fn update(&mut self, mut delta: f64) {
-        delta *= 10f64;
-        if self.celestials.iter().count() < 2 {
-            return;
-        }
+    pub fn position_of(&self, id: u32) -> Option<Cartesian> {
+        self.systems.get(&self.system_of(id)?)?.position_of(id, self.time)
+    }
 
-        let mut old_rtree = self.celestials.clone();
-        let mut new_rtree = RTree::<Body>::default();
-        let mut celestials: Vec<_> = self.celestials.drain().collect();
-
-        while celestials.len() > 0 {
-            let mut celestial = celestials.pop().unwrap();
-            old_rtree.remove(&celestial);
-
-            let gravity_center = celestials.iter().find(|g| g.id == celestial.gravity_center);
-
-            // if let Entity::Player(player) = &mut celestial.entity {
-            //     let env = Self::galactics_in_spherical_view(&old_rtree, celestial.coords, 10000f64);
-
-            //     let (coords, direction, _speed) =
-            //         player.update(celestial.coords, celestial.local_speed, delta, env).await;
-
-            //     celestial.coords = coords;
-            //     celestial.local_direction = direction;
-            // } else
-            if let Some(gravity_center) = gravity_center {
-                let local_coordinates_car = celestial.coords - gravity_center.coords;
-                let local_coordinates_sph = Spherical::from_coord(local_coordinates_car);
-                let mut new_coordinates_sph = local_coordinates_sph.clone();
-                new_coordinates_sph.phi = new_coordinates_sph.phi + celestial.rotating_speed * delta;
-
-                new_coordinates_sph.phi %= PI;
-
-                let delta_car =
-                    Cartesian::from_coord(new_coordinates_sph) - Cartesian::from_coord(local_coordinates_sph);
-
-                if !delta_car.x.is_normal() || !delta_car.y.is_normal() || !delta_car.z.is_normal() {
-                    // spacebuild_log!(warn, "galaxy", "NUMBER NOT NORMAL");
-                } else {
-                    celestial.coords += delta_car;
-
-                    let mut ids = vec![celestial.id];
-
-                    while ids.len() > 0 {
-                        let id = ids.pop().unwrap();
-                        celestials.iter_mut().for_each(|g| {
-                            if g.gravity_center == id {
-                                g.coords += delta_car;
-                                ids.push(g.id);
-                            }
-                        });
-                    }
-                }
-            }
-            old_rtree.insert(celestial.clone());
-            new_rtree.insert(celestial);
-        }
+    pub fn galactics_in_spherical_view(&self, center: Cartesian, radius: f64) -> Vec<Body> {
+        let min = [center.x - radius, center.y - radius, center.z - radius];
+        let max = [center.x + radius, center.y + radius, center.z + radius];
+        self.index
+            .locate_in_envelope_intersecting(&AABB::from_corners(min, max))
+            .filter_map(|indexed| self.systems.get(&indexed.star_id))
+            .flat_map(|system| system.view(center, radius, self.time))
+            .collect()
+    }
 
-        // assert_eq!(old_rtree.iter().count(), new_rtree.iter().count());
-        self.celestials = new_rtree;
+    pub async fn update(&mut self, delta: f64) {
+        self.time += delta * TIME_SCALE;
+        let time = self.time;
+        self.systems.par_iter_mut().for_each(|(_, system)| system.update(time));
     }
 }
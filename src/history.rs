@@ -0,0 +1,114 @@
+use crate::body::Body;
+use crate::galaxy::TIME_SCALE;
+use std::collections::VecDeque;
+use std::f64::consts::{PI, TAU};
+
+/// Default span of galaxy time kept in the history, five seconds of real time.
+pub const HISTORY_WINDOW: f64 = 5f64 * TIME_SCALE;
+const LAG_ITERATIONS: usize = 16;
+const LAG_TOLERANCE: f64 = 1e-6;
+/// Galaxy time step of the numerical derivative of an orbit angle.
+const RATE_STEP: f64 = 1e-3;
+
+/// Ring buffer of the galaxy times of recent ticks, oldest first.
+///
+/// Bodies are not kept, their positions following from the time. Times older than `window` galaxy time units
+/// before the newest one are dropped on push.
+pub struct History {
+    times: VecDeque<f64>,
+    window: f64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(HISTORY_WINDOW)
+    }
+}
+
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+/// Angle of a body around its gravity center in the xz plane at `time`.
+fn angle_at(body: &Body, time: f64) -> f64 {
+    let local = body.local_position(time);
+    local.z.atan2(local.x)
+}
+
+impl History {
+    pub fn new(window: f64) -> Self {
+        Self {
+            times: VecDeque::new(),
+            window,
+        }
+    }
+
+    pub fn window(&self) -> f64 {
+        self.window
+    }
+
+    pub fn set_window(&mut self, window: f64) {
+        self.window = window;
+        self.trim();
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    pub fn oldest(&self) -> Option<f64> {
+        self.times.front().copied()
+    }
+
+    pub fn newest(&self) -> Option<f64> {
+        self.times.back().copied()
+    }
+
+    pub fn push(&mut self, time: f64) {
+        self.times.push_back(time);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let Some(newest) = self.newest() else {
+            return;
+        };
+        while self.times.front().is_some_and(|time| *time < newest - self.window) {
+            self.times.pop_front();
+        }
+    }
+
+    /// Latest tick at or before `time`.
+    pub fn at(&self, time: f64) -> Option<f64> {
+        let index = self.times.partition_point(|tick| *tick <= time);
+        self.times.get(index.checked_sub(1)?).copied()
+    }
+
+    /// Galaxy time within the history at which `body` was at `angle` around its gravity center.
+    ///
+    /// Solves the orbit for the time, starting from the mean motion estimate.
+    /// Returns `None` if the body does not orbit or was not at `angle` within the window.
+    pub fn seen_at(&self, body: &Body, angle: f64) -> Option<f64> {
+        let newest = self.newest()?;
+        let oldest = self.oldest()?;
+        if !body.is_orbiting() || body.rotating_speed == 0f64 {
+            return None;
+        }
+
+        let mut time = newest - wrap_angle(angle_at(body, newest) - angle) / body.rotating_speed;
+        for _ in 0..LAG_ITERATIONS {
+            time = time.clamp(oldest, newest);
+            let error = wrap_angle(angle_at(body, time) - angle);
+            if error.abs() < LAG_TOLERANCE {
+                return Some(time);
+            }
+            let rate = wrap_angle(angle_at(body, time + RATE_STEP) - angle_at(body, time)) / RATE_STEP;
+            time -= error / if rate != 0f64 { rate } else { body.rotating_speed };
+        }
+        None
+    }
+}
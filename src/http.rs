@@ -1,11 +1,14 @@
 use crate::error::Error;
 use crate::instance::Instance;
+use crate::protocol::Encoding;
 use crate::service::Service;
 use http_body_util::Full;
 use hyper::body::Bytes;
+use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, StatusCode};
+use hyper_tungstenite::tungstenite::protocol::WebSocketConfig;
 use hyper_util::rt::TokioIo;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -50,7 +53,14 @@ pub async fn serve(
 
     if hyper_tungstenite::is_upgrade_request(&request) {
         spacebuild_log!(info, address, "Upgrade request");
-        let res = hyper_tungstenite::upgrade(&mut request, None);
+        let (limits, violations) = {
+            let guard = instance.lock().await;
+            (guard.rate_limits().clone(), Arc::clone(&guard.violations))
+        };
+        let config = WebSocketConfig::default()
+            .max_message_size(Some(limits.max_message_size))
+            .max_frame_size(Some(limits.max_message_size));
+        let res = hyper_tungstenite::upgrade(&mut request, Some(config));
         if res.is_err() {
             let err_str: String = res.err().unwrap().to_string();
 
@@ -60,7 +70,19 @@ pub async fn serve(
             return Ok(response);
         }
 
-        let (ws_resp, websocket) = res.unwrap();
+        let (mut ws_resp, websocket) = res.unwrap();
+
+        let encoding = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|header| header.to_str().ok())
+            .and_then(Encoding::from_subprotocols);
+        if let Some(encoding) = encoding {
+            spacebuild_log!(info, address, "Negotiated subprotocol {}", encoding.subprotocol());
+            ws_resp
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(encoding.subprotocol()));
+        }
 
         tokio::spawn(async move {
             let instance_cln = Arc::clone(&instance);
@@ -72,7 +94,7 @@ pub async fn serve(
                 spacebuild_log!(trace, address, "websocket await error");
                 return ();
             }
-            let mut client = Service::new(websocket.unwrap(), instance_cln, address);
+            let mut client = Service::new(websocket.unwrap(), instance_cln, address, encoding, &limits, violations);
             let result = client.serve().await;
             if let Err(err) = result {
                 spacebuild_log!(warn, address, "Error from client service: {}", err);
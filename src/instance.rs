@@ -1,76 +1,248 @@
+use crate::audit::AuditLog;
+use crate::auth;
+use crate::ban::{self, BanList};
 use crate::body::Body;
 use crate::cache::BodyCache;
 use crate::cache::PlayerCache;
 use crate::error::Error;
 use crate::galaxy::Galaxy;
-use crate::protocol::Action;
+use crate::history::History;
+use crate::limits::{RateLimits, Violations};
+use crate::orbit::Orbit;
+use crate::outbox::{outbox, OutboxReceiver, OUTBOX_CAPACITY};
+use crate::persistence::Persistence;
+use crate::player::Latency;
+use crate::protocol;
+use crate::protocol::state::ErrorCode;
+use crate::protocol::{Action, Command, Role, Target};
 use crate::spacebuild_log;
-use crate::sqldb::SqlDb;
+use crate::spectator::Spectator;
+use crate::sqlstorage::SqlStorage;
+use crate::storage::{MemoryStorage, Snapshot, Storage};
 use crate::Result;
 use rand::prelude::*;
 use rand::random;
 use rand::Rng;
 use rand_chacha::ChaCha8Rng;
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
 use scilib::coordinate::cartesian::Cartesian;
 use scilib::coordinate::spherical::Spherical;
-use sqlx::SqlitePool;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::{PI, TAU};
-use std::fs::File;
-use std::path::Path;
+use std::net::IpAddr;
+use std::ops::Range;
 use std::sync::Arc;
-use tokio::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Distance within which bodies and other ships are sent to a player.
+pub const VIEW_RADIUS: f64 = 10000f64;
+/// Radius beyond which a spectator region is capped.
+pub const MAX_REGION_RADIUS: f64 = 4f64 * VIEW_RADIUS;
+/// How long a player whose connection dropped stays loaded, waiting for an `Action::Resume`.
+pub const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Channels of a player whose connection dropped, kept until it resumes or `deadline` passes.
+pub(crate) struct Suspended {
+    pub(crate) action_send: Sender<Action>,
+    pub(crate) state_recv: OutboxReceiver,
+    /// State that could not be sent before the connection dropped.
+    pub(crate) pending: Option<protocol::state::Game>,
+    deadline: Instant,
+}
 
 pub struct Instance {
-    pub(crate) history: Vec<HashMap<u32, Body>>,
+    pub(crate) history: History,
     pub(crate) bodies: BodyCache,
     pub(crate) galaxy: Galaxy,
     pub(crate) players: PlayerCache,
+    pub(crate) bans: BanList,
+    pub(crate) audit: AuditLog,
+    persistence: Persistence,
+    ships: RTree<GeomWithData<[f64; 3], u32>>,
     rng: ChaCha8Rng,
+    /// Player ids by session token.
+    sessions: HashMap<String, u32>,
+    suspended: HashMap<u32, Suspended>,
+    resume_grace_period: Duration,
+    rate_limits: RateLimits,
+    pub(crate) violations: Arc<Violations>,
+    pub(crate) spectators: HashMap<u32, Spectator>,
+    next_spectator_id: u32,
+}
+
+/// Bodies and ship ids of what `target` designates, see `protocol::Spectate`.
+///
+/// The ships of a system are the ones of its players, wherever they are.
+fn watched(
+    galaxy: &Galaxy,
+    ships: &RTree<GeomWithData<[f64; 3], u32>>,
+    target: Option<&Target>,
+) -> Option<(Vec<Body>, Vec<u32>)> {
+    let system = match target {
+        Some(Target::Region { center, radius }) => {
+            let radius = radius.clamp(0f64, MAX_REGION_RADIUS);
+            let in_region = ships
+                .locate_within_distance(*center, radius.powi(2))
+                .map(|indexed| indexed.data)
+                .collect();
+            let center = Cartesian::from(center[0], center[1], center[2]);
+            return Some((galaxy.galactics_in_spherical_view(center, radius), in_region));
+        }
+        Some(Target::System(star_id)) => galaxy.borrow_system(*star_id)?,
+        None => galaxy
+            .systems
+            .values()
+            .max_by_key(|system| (system.players.len(), Reverse(system.star_id)))?,
+    };
+    Some((
+        system.bodies_at(galaxy.time()),
+        system.players.iter().copied().collect(),
+    ))
 }
 
 impl Instance {
-    pub async fn save_all(&mut self) -> () {
-        self.bodies.save_all().await;
-        self.players.save_all().await;
+    /// Queues the bodies and players that changed since the previous save for the persistence task, returning
+    /// how many, without waiting for them to be written.
+    pub fn save(&mut self) -> usize {
+        self.bodies.sync(self.galaxy.bodies_now());
+        let snapshot = Snapshot {
+            bodies: self.bodies.changes(),
+            players: self.players.changes(),
+            time: Some(self.galaxy.time()),
+        };
+        let saved = snapshot.len();
+        self.bodies.mark_saved(snapshot.bodies.clone());
+        self.players.mark_saved(snapshot.players.clone());
+        self.persistence.write(snapshot);
+        saved
+    }
+
+    /// Saves as `save` and waits for everything queued to be written.
+    pub async fn save_all(&mut self) -> Result<usize> {
+        let saved = self.save();
+        self.persistence.flush().await?;
+        Ok(saved)
     }
 
     pub async fn update(&mut self, delta: f64) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .suspended
+            .iter()
+            .filter(|(_, suspended)| suspended.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            spacebuild_log!(info, "instance", "Session of {} expired", id);
+            self.leave(id).await;
+        }
+
         self.galaxy.update(delta).await;
-        self.bodies.sync(self.galaxy.borrow_bodies());
+        self.history.push(self.galaxy.time());
         for (_, player) in &mut self.players.cache {
-            let env = Galaxy::galactics_in_spherical_view(&self.galaxy.celestials, player.coords, 10000f64);
-            player.update(delta, env, &self.history).await;
+            let env = self.galaxy.galactics_in_spherical_view(player.coords, VIEW_RADIUS);
+            player.update(delta, self.galaxy.time(), env.iter().collect(), &self.history);
         }
-        self.history.push(self.bodies.cache.clone());
-    }
 
-    pub async fn from_path(db_path: &'_ str) -> Result<Instance> {
-        if !Path::new(db_path).exists() {
-            File::create(db_path).map_err(|err| Error::DbFileCreationError(err))?;
+        let ships: HashMap<u32, protocol::state::Ship> = self
+            .players
+            .cache
+            .values()
+            .map(|player| (player.id, player.ship()))
+            .collect();
+        self.ships = RTree::bulk_load(
+            ships
+                .values()
+                .map(|ship| GeomWithData::new(ship.coords, ship.id))
+                .collect(),
+        );
+        for player in self.players.cache.values_mut() {
+            let in_view = self
+                .ships
+                .locate_within_distance([player.coords.x, player.coords.y, player.coords.z], VIEW_RADIUS.powi(2))
+                .filter(|indexed| indexed.data != player.id)
+                .filter_map(|indexed| ships.get(&indexed.data).cloned())
+                .collect();
+            player.send_ships(in_view);
         }
 
-        let pool = SqlitePool::connect(db_path)
-            .await
-            .map_err(|err| Error::DbOpenError(db_path.to_string(), err))?;
+        for spectator in self.spectators.values_mut() {
+            spectator.handle_actions();
+            let (env, in_view) = match watched(&self.galaxy, &self.ships, spectator.target.as_ref()) {
+                Some((env, ids)) => (env, ids.iter().filter_map(|id| ships.get(id).cloned()).collect()),
+                None => (vec![], vec![]),
+            };
+            spectator.update(self.galaxy.time(), &env.iter().collect::<Vec<_>>(), in_view);
+        }
+
+        // Clients not keeping up are removed, their services closing the connections once the outboxes are dropped.
+        let too_slow: Vec<u32> = self
+            .players
+            .cache
+            .values()
+            .filter(|player| player.state_send.is_overflowed())
+            .map(|player| player.id)
+            .collect();
+        for id in too_slow {
+            spacebuild_log!(warn, "instance", "Player {} is too slow, removing it", id);
+            self.leave(id).await;
+        }
+        self.spectators.retain(|id, spectator| {
+            let too_slow = spectator.state_send.is_overflowed();
+            if too_slow {
+                spacebuild_log!(warn, "instance", "Spectator {} is too slow, removing it", id);
+            }
+            !too_slow
+        });
+    }
 
-        let db = SqlDb::new(pool);
-        let db = Arc::new(Mutex::new(db));
+    /// Instance stored in the SQLite database at `db_path`, created if needed.
+    pub async fn from_path(db_path: &'_ str) -> Result<Instance> {
+        Self::with_storage(Arc::new(SqlStorage::open(db_path).await?)).await
+    }
 
-        let mut bodies = BodyCache::new(db.clone());
-        bodies.init_db().await;
+    /// Instance stored in memory only, lost once dropped.
+    pub async fn in_memory() -> Result<Instance> {
+        Self::with_storage(Arc::new(MemoryStorage::default())).await
+    }
 
-        let mut players = PlayerCache::new(db.clone());
-        players.init_db().await;
+    pub async fn with_storage(storage: Arc<dyn Storage>) -> Result<Instance> {
+        let persistence = Persistence::spawn(storage.clone());
+        // The caches load what they unloaded as it was queued, without waiting for it to be written.
+        let queued = persistence.queued(storage.clone());
+        let mut bodies = BodyCache::new(queued.clone());
+        bodies.next_id = storage.last_body_id().await? + 1;
+        let players = PlayerCache::new(queued);
+        let bans = BanList::new(storage.clone());
+        bans.load().await?;
+        // Orbiting bodies are where the clock puts them, which has to go on from where it was saved.
+        let mut galaxy = Galaxy::default();
+        galaxy.time = storage.galaxy_time().await?;
+        let audit = AuditLog::new(storage);
 
         Ok(Instance {
             bodies,
-            galaxy: Galaxy::default(),
+            galaxy,
             players,
+            bans,
+            audit,
+            persistence,
+            ships: RTree::new(),
             rng: ChaCha8Rng::seed_from_u64(random()),
-            history: Vec::new(),
+            history: History::default(),
+            sessions: HashMap::new(),
+            suspended: HashMap::new(),
+            resume_grace_period: RESUME_GRACE_PERIOD,
+            rate_limits: RateLimits::default(),
+            violations: Arc::new(Violations::default()),
+            spectators: HashMap::new(),
+            next_spectator_id: 1,
         })
     }
 
@@ -82,12 +254,339 @@ impl Instance {
         &mut self.galaxy
     }
 
+    /// Sends a notice to every player logged in and every spectator.
+    pub async fn notify_all(&mut self, message: &str) {
+        for player in self.players.cache.values_mut() {
+            player.send_notice(message);
+        }
+        for spectator in self.spectators.values_mut() {
+            spectator.send_notice(message);
+        }
+    }
+
+    /// Features enabled for a player, see `protocol::FEATURES`.
+    pub fn set_capabilities(&mut self, id: u32, capabilities: HashSet<String>) {
+        if let Some(player) = self.players.cache.get_mut(&id) {
+            player.capabilities = capabilities;
+        }
+    }
+
+    /// Remembers where a player connects from, for the address bans to disconnect it.
+    pub(crate) fn set_address(&mut self, id: u32, address: IpAddr) {
+        if let Some(player) = self.players.cache.get_mut(&id) {
+            player.address = Some(address);
+        }
+    }
+
+    pub fn nickname(&self, id: u32) -> Option<&str> {
+        self.players.cache.get(&id).map(|player| player.nickname.as_str())
+    }
+
+    pub fn role(&self, id: u32) -> Role {
+        self.players
+            .cache
+            .get(&id)
+            .map(|player| player.role)
+            .unwrap_or_default()
+    }
+
+    /// Stores the role of an account, logged in or not.
+    pub async fn set_role(&mut self, nickname: &str, role: Role) -> Result<()> {
+        self.players.set_role(nickname, role).await
+    }
+
+    pub fn borrow_bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn borrow_audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    fn online_id(&self, nickname: &str) -> Result<u32> {
+        self.players
+            .cache
+            .values()
+            .find(|player| player.nickname.eq_ignore_ascii_case(nickname))
+            .map(|player| player.id)
+            .ok_or_else(|| Error::PlayerNotLoggedIn(nickname.to_string()))
+    }
+
+    /// Disconnects a player, its service closing the connection once the channels are dropped.
+    async fn kick(&mut self, id: u32, reason: &str) {
+        if let Some(player) = self.players.cache.get_mut(&id) {
+            player.send_error(ErrorCode::Kicked, reason);
+        }
+        self.leave(id).await;
+    }
+
+    /// Carries out a privileged command of `actor`, a nickname or `console`, returning the answer to send it.
+    ///
+    /// The role of the actor is not checked here but by the service, see `Command::required_role`.
+    pub async fn execute(&mut self, actor: &str, command: &Command) -> Result<String> {
+        if let Some(answer) = self.bans.execute(actor, command).await? {
+            return Ok(self.disconnect_banned(command, answer).await);
+        }
+        match command {
+            Command::Kick { nickname, reason } => {
+                let id = self.online_id(nickname)?;
+                self.kick(id, reason.as_deref().unwrap_or("Kicked by a moderator"))
+                    .await;
+                Ok(format!("Kicked {}", nickname))
+            }
+            Command::Ban { .. }
+            | Command::Unban { .. }
+            | Command::BanAddress { .. }
+            | Command::UnbanAddress { .. }
+            | Command::ListBans => unreachable!("Carried out by the ban list"),
+            Command::Broadcast { message } => {
+                self.notify_all(message).await;
+                Ok("Broadcast sent".to_string())
+            }
+            Command::Teleport { nickname, coords } => {
+                let id = self.online_id(nickname)?;
+                let player = self.players.cache.get_mut(&id).unwrap();
+                player.coords = Cartesian::from(coords[0], coords[1], coords[2]);
+                player.velocity = Cartesian::default();
+                player.first_state_sent = false;
+                Ok(format!("Teleported {}", nickname))
+            }
+            Command::SpawnSystem { coords } => {
+                let star_id = self.gen_system(Cartesian::from(coords[0], coords[1], coords[2])).await;
+                // Systems are only kept loaded for the players in them.
+                self.unload_system(star_id).await;
+                Ok(format!("Spawned system {}", star_id))
+            }
+            Command::SetRole { nickname, role } => {
+                self.set_role(nickname, *role).await?;
+                Ok(format!("{} is now {:?}", nickname, role))
+            }
+            Command::Latency { nickname } => {
+                let id = self.online_id(nickname)?;
+                Ok(match self.player_latency(id) {
+                    Some(Latency {
+                        rtt,
+                        clock_offset: Some(clock_offset),
+                    }) => format!(
+                        "{}: round trip {:.1} ms, clock offset {:+.1} ms",
+                        nickname,
+                        rtt * 1000f64,
+                        clock_offset * 1000f64
+                    ),
+                    Some(Latency {
+                        rtt,
+                        clock_offset: None,
+                    }) => {
+                        format!("{}: round trip {:.1} ms", nickname, rtt * 1000f64)
+                    }
+                    None => format!("{}: no pong received yet", nickname),
+                })
+            }
+        }
+    }
+
+    /// Carries out a command as `execute` on a shared instance, bans being written without holding it.
+    pub async fn execute_shared(instance: &Mutex<Instance>, actor: &str, command: &Command) -> Result<String> {
+        let bans = instance.lock().await.bans.clone();
+        match bans.execute(actor, command).await? {
+            Some(answer) => Ok(instance.lock().await.disconnect_banned(command, answer).await),
+            None => instance.lock().await.execute(actor, command).await,
+        }
+    }
+
+    /// Disconnects the players a ban command just banned, returning the answer of the command.
+    async fn disconnect_banned(&mut self, command: &Command, answer: String) -> String {
+        match command {
+            Command::Ban { nickname, reason, .. } => {
+                if let Ok(id) = self.online_id(nickname) {
+                    self.kick(id, reason.as_deref().unwrap_or(ban::DEFAULT_REASON)).await;
+                }
+                answer
+            }
+            Command::BanAddress { address, reason, .. } => {
+                let Ok(address) = ban::parse_address(address) else {
+                    return answer;
+                };
+                let banned: Vec<u32> = self
+                    .players
+                    .cache
+                    .values()
+                    .filter(|player| player.address.is_some_and(|ip| address.contains(&ip.to_canonical())))
+                    .map(|player| player.id)
+                    .collect();
+                for id in banned.iter() {
+                    self.kick(*id, reason.as_deref().unwrap_or(ban::DEFAULT_REASON)).await;
+                }
+                format!("{}, {} player(s) disconnected", answer, banned.len())
+            }
+            _ => answer,
+        }
+    }
+
+    /// Records a command attempt of `actor` in the audit log.
+    pub async fn audit(&mut self, actor: &str, command: &Command, result: &Result<String>) {
+        self.audit.record_result(actor, command, result).await
+    }
+
+    pub fn player_latency(&self, id: u32) -> Option<Latency> {
+        self.players.cache.get(&id)?.latency()
+    }
+
+    pub fn borrow_history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn borrow_history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    /// Limits of the connections accepted from now on.
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.rate_limits = rate_limits;
+    }
+
+    pub fn violations(&self) -> &Violations {
+        &self.violations
+    }
+
+    pub fn resume_grace_period(&self) -> Duration {
+        self.resume_grace_period
+    }
+
+    pub fn set_resume_grace_period(&mut self, grace_period: Duration) {
+        self.resume_grace_period = grace_period;
+    }
+
+    /// Issues the token resuming the session of a player, the previous ones being revoked.
+    pub(crate) fn open_session(&mut self, id: u32) -> String {
+        self.sessions.retain(|_, player_id| *player_id != id);
+        let token = Uuid::new_v4().simple().to_string();
+        self.sessions.insert(token.clone(), id);
+        token
+    }
+
+    /// Keeps a player whose connection dropped loaded for the grace period.
+    pub(crate) fn suspend(
+        &mut self,
+        id: u32,
+        action_send: Sender<Action>,
+        state_recv: OutboxReceiver,
+        pending: Option<protocol::state::Game>,
+    ) {
+        spacebuild_log!(info, "instance", "Suspending {} for {:?}", id, self.resume_grace_period);
+        self.suspended.insert(
+            id,
+            Suspended {
+                action_send,
+                state_recv,
+                pending,
+                deadline: Instant::now() + self.resume_grace_period,
+            },
+        );
+    }
+
+    /// Takes back the channels of the suspended player owning `token`.
+    pub(crate) fn resume(&mut self, token: &str) -> Result<(u32, Suspended)> {
+        let id = *self.sessions.get(token).ok_or(Error::InvalidSessionToken)?;
+        let suspended = self.suspended.remove(&id).ok_or(Error::SessionInUse)?;
+        spacebuild_log!(info, "instance", "Resuming {}", id);
+        Ok((id, suspended))
+    }
+
+    /// Features enabled for a player, in the order of `protocol::FEATURES`.
+    pub fn capabilities(&self, id: u32) -> Vec<String> {
+        let Some(player) = self.players.cache.get(&id) else {
+            return vec![];
+        };
+        protocol::FEATURES
+            .iter()
+            .filter(|feature| player.capabilities.contains(**feature))
+            .map(|feature| feature.to_string())
+            .collect()
+    }
+
+    /// Adds a read-only client, loading nothing: it only sees systems loaded for players.
+    pub(crate) fn spectate(
+        &mut self,
+        target: Option<Target>,
+        capabilities: HashSet<String>,
+    ) -> (u32, Sender<Action>, OutboxReceiver) {
+        let id = self.next_spectator_id;
+        self.next_spectator_id += 1;
+        spacebuild_log!(info, "instance", "New spectator {} watching {:?}", id, target);
+
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let (state_send, state_recv) = outbox(OUTBOX_CAPACITY);
+        let mut spectator = Spectator::new(id, target, state_send, action_recv);
+        spectator.capabilities = capabilities;
+        self.spectators.insert(id, spectator);
+        (id, action_send, state_recv)
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.len()
+    }
+
+    pub fn leave_spectator(&mut self, id: u32) {
+        spacebuild_log!(info, "instance", "Spectator {} left", id);
+        self.spectators.remove(&id);
+    }
+
     pub async fn leave(&mut self, id: u32) {
         spacebuild_log!(info, "instance", "Leave for {}", id);
-        self.players.sync_and_unload(id).await;
+        self.sessions.retain(|_, player_id| *player_id != id);
+        self.suspended.remove(&id);
+        let Some(current_system) = self.players.cache.get(&id).map(|player| player.current_system) else {
+            return;
+        };
+        let players = self.players.unload(id).into_iter().collect();
+        self.persistence.write(Snapshot {
+            players,
+            bodies: vec![],
+            time: Some(self.galaxy.time()),
+        });
+        self.galaxy.detach_player(current_system, id);
+
+        let is_deserted = self
+            .galaxy
+            .borrow_system(current_system)
+            .is_some_and(|system| system.players.is_empty());
+        if is_deserted {
+            self.unload_system(current_system).await;
+        }
     }
 
-    async fn new_player(&mut self, nickname: String) -> (u32, Sender<Action>, Receiver<crate::protocol::state::Game>) {
+    async fn unload_system(&mut self, star_id: u32) {
+        let time = self.galaxy.time();
+        if let Some(system) = self.galaxy.remove_system(star_id) {
+            spacebuild_log!(
+                info,
+                "instance",
+                "Unloading system {} ({} bodies)",
+                star_id,
+                system.len()
+            );
+            let bodies = self.bodies.unload(system.bodies_at(time));
+            self.persistence.write(Snapshot {
+                bodies,
+                players: vec![],
+                time: Some(time),
+            });
+        }
+    }
+
+    /// Creates the account, then the system the player starts in, nothing being generated for a refused account.
+    async fn new_player(
+        &mut self,
+        nickname: String,
+        password_hash: &str,
+    ) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        let (player, action_send, state_recv) = self.players.new_player(nickname, password_hash).await?;
+        let player_id = player.id;
         spacebuild_log!(info, "server", "New player, generating spawning bodies...");
 
         let offset = Spherical::from(
@@ -102,97 +601,197 @@ impl Instance {
             self.rng.random_range(PI - 0.1..PI + 0.1),
             self.rng.random_range(-TAU..TAU),
         );
-        let (player, action_send, state_recv) = self.players.new_player(nickname).await;
-        player.coords = Cartesian::from_coord(player_offset);
-        player.current_system = current_system;
-        // + Cartesian::from_coord(player_offset)
-
-        // fixme
-        // self.galaxy.celestials.insert(player);
+        if let Some(player) = self.players.cache.get_mut(&player_id) {
+            player.coords = Cartesian::from_coord(offset) + Cartesian::from_coord(player_offset);
+            player.current_system = current_system;
+        }
+        self.galaxy.attach_player(current_system, player_id);
 
-        (player.id, action_send, state_recv)
+        Ok((player_id, action_send, state_recv))
     }
 
+    /// Logs a player in after checking its password.
+    ///
+    /// Holds the instance while hashing, the service checks the password with `password_hash` and
+    /// `auth::verify_password` before calling `authenticate_verified` instead.
     pub async fn authenticate(
         &mut self,
         nickname: String,
-    ) -> Result<(u32, Sender<Action>, Receiver<crate::protocol::state::Game>)> {
+        password: &str,
+    ) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        let password_hash = match self.password_hash(&nickname).await {
+            Err(Error::InvalidCredentials) => return Err(auth::refuse_unknown(password.to_string()).await),
+            password_hash => password_hash?.ok_or(Error::PasswordNotSet)?,
+        };
+        auth::verify_password(password.to_string(), password_hash).await?;
+        self.authenticate_verified(nickname).await
+    }
+
+    /// Password hash of an account, `None` if it was created before passwords.
+    pub async fn password_hash(&mut self, nickname: &str) -> Result<Option<String>> {
+        self.players.password_hash(nickname).await
+    }
+
+    /// Sets the password of an account, accounts created before passwords being refused until then.
+    ///
+    /// `password_hash` comes from `auth::hash_password`, hashed without holding the instance.
+    pub async fn set_password_hash(&mut self, nickname: &str, password_hash: &str) -> Result<()> {
+        self.players.set_password_hash(nickname, password_hash).await
+    }
+
+    pub(crate) async fn authenticate_verified(
+        &mut self,
+        nickname: String,
+    ) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        self.bans.check(&nickname).await?;
+
+        let suspended = self
+            .players
+            .cache
+            .values()
+            .find(|player| player.nickname.eq_ignore_ascii_case(&nickname))
+            .map(|player| player.id)
+            .filter(|id| self.suspended.contains_key(id));
+        if let Some(id) = suspended {
+            spacebuild_log!(info, "instance", "Ending the suspended session of {}", id);
+            self.leave(id).await;
+        }
+
         match self.players.can_login(nickname.clone()).await {
-            Err(Error::PlayerIsNew) => Ok(self.new_player(nickname).await),
-            Ok(_) => Ok(self.login(nickname).await),
+            Err(Error::PlayerIsNew) => Err(Error::InvalidCredentials),
+            Ok(_) => self.login(nickname).await,
             Err(err) => Err(err),
         }
     }
 
+    /// Creates an account and logs it in, `password_hash` coming from `auth::hash_password`.
+    ///
+    /// Accounts created before passwords are taken too, their passwords being set from the console.
+    pub async fn register(
+        &mut self,
+        nickname: String,
+        password_hash: &str,
+    ) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        self.bans.check(&nickname).await?;
+        self.players.can_register(&nickname).await?;
+        self.new_player(nickname, password_hash).await
+    }
+
+    fn gen_orbit(&mut self, distance: Range<f64>, max_eccentricity: f64) -> Orbit {
+        Orbit {
+            semi_major_axis: self.rng.random_range(distance),
+            eccentricity: self.rng.random_range(0f64..max_eccentricity),
+            inclination: self.rng.random_range(0f64..0.1),
+            ascending_node: self.rng.random_range(0f64..TAU),
+            arg_periapsis: self.rng.random_range(0f64..TAU),
+            mean_anomaly_epoch: self.rng.random_range(0f64..TAU),
+        }
+    }
+
+    fn insert_celestial(&mut self, body: Body) {
+        let id = body.id;
+        if let Err(err) = self.galaxy.insert_celestial(body) {
+            spacebuild_log!(warn, "instance", "Could not insert body {} in galaxy: {}", id, err);
+        }
+    }
+
+    fn spawn_body(&mut self, body: Body) {
+        self.bodies.add_body(body.id, body.clone());
+        self.insert_celestial(body);
+    }
+
     pub async fn gen_system(&mut self, offset: Cartesian) -> u32 {
-        // let phi = self.rng.random_range(-TAU..TAU);
-        // let theta = self.rng.random_range(PI - 0.1..PI + 0.1);
-        // let distance = self.rng.random_range(10000f64..100000f64);
+        let time = self.galaxy.time();
 
-        let mut star = self.bodies.new_body(1).await.clone();
+        let mut star = self.bodies.new_body(1).clone();
         star.gravity_center = star.id;
         star.coords = offset;
         star.rotating_speed = 0f64;
+        star.mass = self.rng.random_range(10000000f64..50000000f64);
+        let star_id = star.id;
+        let star_coords = star.coords;
+        self.spawn_body(star);
 
         let nb_planets = self.rng.random_range(5..15);
         for _ in 0..nb_planets {
-            let mut planet = self.bodies.new_body(2).await.clone();
+            let mut planet = self.bodies.new_body(2).clone();
             planet.rotating_speed = self.rng.random_range(0.0001..0.001);
-            let phi = self.rng.random_range(-TAU..TAU);
-            let theta = self.rng.random_range(PI - 0.1..PI + 0.1);
-            let distance = self.rng.random_range(500f64..4000f64);
-            let add_vec = Cartesian::from_coord(Spherical::from(distance, theta, phi));
-            planet.coords = star.coords.clone() + add_vec;
-            planet.gravity_center = star.id;
+            planet.mass = self.rng.random_range(10000f64..100000f64);
+            planet.orbit = self.gen_orbit(500f64..4000f64, 0.2);
+            planet.coords = star_coords + planet.local_position(time);
+            planet.gravity_center = star_id;
+            let planet_id = planet.id;
+            let planet_coords = planet.coords;
+            self.spawn_body(planet);
 
             let nb_moons = self.rng.random_range(0..3);
 
             for _ in 0..nb_moons {
-                let mut moon = self.bodies.new_body(3).await.clone();
+                let mut moon = self.bodies.new_body(3).clone();
                 moon.rotating_speed = self.rng.random_range(0.005..0.01);
-                let phi = self.rng.random_range(-TAU..TAU);
-                let theta = self.rng.random_range(PI - 0.1..PI + 0.1);
-                let distance = self.rng.random_range(30f64..200f64);
-                let add_vec = Cartesian::from_coord(Spherical::from(distance, theta, phi));
-                let mut cln = planet.coords.clone();
-                cln = cln + add_vec;
-                moon.coords = cln;
-                moon.gravity_center = planet.id;
-                self.galaxy.insert_celestial(moon);
+                moon.mass = self.rng.random_range(100f64..1000f64);
+                moon.orbit = self.gen_orbit(30f64..200f64, 0.1);
+                moon.coords = planet_coords + moon.local_position(time);
+                moon.gravity_center = planet_id;
+                self.spawn_body(moon);
             }
-
-            self.galaxy.insert_celestial(planet);
         }
 
         let nb_asteroids = self.rng.random_range(500..2500);
-        let last_id = self.bodies.new_bodies(4, nb_asteroids).await;
+        let last_id = self.bodies.new_bodies(4, nb_asteroids);
 
         for i in 0..nb_asteroids {
             let mut body = self.bodies.get_body(last_id - i as u32).clone();
             body.rotating_speed = self.rng.random_range(0.0001..0.001);
-            let phi = self.rng.random_range(-TAU..TAU);
-            let theta = self.rng.random_range(PI - 0.1..PI + 0.1);
-            let distance = self.rng.random_range(1500f64..4000f64);
-            body.coords = star.coords.clone() + Cartesian::from_coord(Spherical::from(distance, theta, phi));
-            body.gravity_center = star.id;
-            self.galaxy.insert_celestial(body);
+            body.mass = self.rng.random_range(1f64..10f64);
+            body.orbit = self.gen_orbit(1500f64..4000f64, 0.3);
+            body.coords = star_coords + body.local_position(time);
+            body.gravity_center = star_id;
+            self.spawn_body(body);
+        }
+
+        // Queued right away, the system being stored before any player in it.
+        if let Some(system) = self.galaxy.borrow_system(star_id) {
+            let bodies = system.bodies_at(time);
+            self.bodies.mark_saved(bodies.clone());
+            self.persistence.write(Snapshot {
+                bodies,
+                players: vec![],
+                time: Some(time),
+            });
         }
 
-        let star_id = star.id;
-        self.galaxy.insert_celestial(star);
         star_id
     }
 
-    async fn login(&mut self, nickname: String) -> (u32, Sender<Action>, Receiver<crate::protocol::state::Game>) {
-        let (player_id, send, recv) = self.players.load(nickname).await;
+    async fn login(&mut self, nickname: String) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        let (player_id, send, recv) = self.players.load(nickname).await?;
+
+        let current_system = self.players.get_player(player_id).current_system;
+        if self.galaxy.borrow_system(current_system).is_none() {
+            if let Err(err) = self.load_system(current_system).await {
+                self.players.unload(player_id);
+                return Err(err);
+            }
+        }
+        self.galaxy.attach_player(current_system, player_id);
+        Ok((player_id, send, recv))
+    }
 
-        let player = self.players.get_player(player_id);
-        let star = self.bodies.load_body(player.current_system).await.clone();
-        let gravitings = self.bodies.load_gravitings(star.id).await;
-        self.galaxy.insert_celestial(star);
+    /// Loads an unloaded system in the galaxy, leaving the caches as they were if it can't be read.
+    async fn load_system(&mut self, star_id: u32) -> Result<()> {
+        let star = self.bodies.load_body(star_id).await?.clone();
+        let gravitings = match self.bodies.load_gravitings(star.id).await {
+            Ok(gravitings) => gravitings,
+            Err(err) => {
+                self.bodies.unload(vec![star]);
+                return Err(err);
+            }
+        };
+        self.insert_celestial(star);
         for graviting in gravitings {
-            self.galaxy.insert_celestial(graviting);
+            self.insert_celestial(graviting);
         }
-        (player_id, send, recv)
+        Ok(())
     }
 }
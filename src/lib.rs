@@ -1,18 +1,32 @@
 #![forbid(unsafe_code)]
 
+pub mod audit;
+pub mod auth;
+pub mod ban;
 pub mod body;
 pub mod bot;
 pub mod cache;
 pub mod error;
 pub mod galaxy;
+pub mod history;
 pub mod http;
 pub mod instance;
+pub mod limits;
+pub mod migration;
+pub mod orbit;
+pub mod outbox;
+pub mod persistence;
 pub mod player;
 pub mod protocol;
 pub mod server;
 pub mod service;
+pub mod spectator;
 pub mod sqldb;
+pub mod sqlstorage;
+pub mod storage;
+pub mod system;
 pub mod tls;
+pub mod view;
 
 #[cfg(feature = "tracing")]
 pub mod tracing;
@@ -56,13 +70,12 @@ use test_helpers_async::*;
 #[before_all]
 #[cfg(test)]
 mod test_01_body_cache {
-    use std::{env, fs::File, path::Path, sync::Arc};
+    use std::{env, sync::Arc};
 
     use sqlx::SqlitePool;
-    use tokio::sync::Mutex;
     use uuid::Uuid;
 
-    use crate::{body, cache::BodyCache, sqldb::SqlDb};
+    use crate::{body, cache::BodyCache, orbit::Orbit, sqldb::SqlDb, sqlstorage::SqlStorage};
 
     pub fn before_all() {
         spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
@@ -78,15 +91,8 @@ mod test_01_body_cache {
         )
     }
 
-    async fn bootstrap(db_path: &String) -> BodyCache {
-        if !Path::new(&db_path).exists() {
-            File::create(&db_path).unwrap();
-        }
-        let pool = SqlitePool::connect(&db_path).await.unwrap();
-        let db = SqlDb::new(pool);
-        let mut cache = BodyCache::new(Arc::new(Mutex::new(db)));
-        cache.init_db().await;
-        cache
+    async fn bootstrap(db_path: &str) -> BodyCache {
+        BodyCache::new(Arc::new(SqlStorage::open(db_path).await.unwrap()))
     }
 
     #[tokio::test]
@@ -100,6 +106,7 @@ mod test_01_body_cache {
         body.coords.z = 6f64;
         body.gravity_center = 0;
         body.rotating_speed = 8f64;
+        body.mass = 10f64;
         let body_id = body.id;
         cache.add_body(body_id, body.clone());
         let body_ref = cache.get_body(body_id);
@@ -108,6 +115,7 @@ mod test_01_body_cache {
         assert_eq!(body_ref.gravity_center, body.gravity_center);
         assert_eq!(body_ref.id, body.id);
         assert_eq!(body_ref.rotating_speed, body.rotating_speed);
+        assert_eq!(body_ref.mass, body.mass);
         Ok(())
     }
 
@@ -121,21 +129,68 @@ mod test_01_body_cache {
         body.coords.z = 6f64;
         body.gravity_center = 0;
         body.rotating_speed = 8f64;
+        body.mass = 10f64;
+        body.orbit = Orbit {
+            semi_major_axis: 100f64,
+            eccentricity: 0.1,
+            inclination: 0.05,
+            ascending_node: 1f64,
+            arg_periapsis: 2f64,
+            mean_anomaly_epoch: 3f64,
+        };
         let db_path = get_random_db_path();
         {
             let mut cache = bootstrap(&db_path).await;
             let body_id = body.id;
             cache.add_body(body_id, body.clone());
-            cache.save_all().await;
+            cache.save_all().await?;
         }
         {
             let mut cache = bootstrap(&db_path).await;
-            let body_ref = cache.load_body(body.id).await;
+            let body_ref = cache.load_body(body.id).await?;
             assert_eq!(body_ref.body_type, body.body_type);
             assert_eq!(body_ref.coords, body.coords);
             assert_eq!(body_ref.gravity_center, body.gravity_center);
             assert_eq!(body_ref.id, body.id);
             assert_eq!(body_ref.rotating_speed, body.rotating_speed);
+            assert_eq!(body_ref.mass, body.mass);
+            assert_eq!(body_ref.orbit, body.orbit);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_batched_upsert() -> anyhow::Result<()> {
+        // More rows than a single statement can bind, moons written in any order with their planets and star.
+        const COUNT: u32 = 10000;
+        const PLANETS: u32 = 100;
+        const STAR: u32 = COUNT;
+        let planet_of = |id: u32| STAR - PLANETS + id % PLANETS;
+        let db_path = get_random_db_path();
+        {
+            let mut cache = bootstrap(&db_path).await;
+            assert_eq!(COUNT, cache.new_bodies(3, COUNT as i32));
+            for body in cache.cache.values_mut() {
+                body.coords.x = body.id as f64;
+                (body.body_type, body.gravity_center) = match body.id {
+                    STAR => (1, STAR),
+                    id if id >= STAR - PLANETS => (2, STAR),
+                    id => (3, planet_of(id)),
+                };
+            }
+            cache.save_all().await?;
+        }
+        {
+            let mut cache = bootstrap(&db_path).await;
+            assert_eq!(COUNT as usize, cache.load_gravitings(STAR).await?.len());
+            for (id, body_type, gravity_center) in [(1, 3, planet_of(1)), (STAR - 1, 2, STAR), (STAR, 1, STAR)] {
+                let body_ref = cache.load_body(id).await?;
+                assert_eq!(body_type, body_ref.body_type);
+                assert_eq!(gravity_center, body_ref.gravity_center);
+                assert_eq!(id as f64, body_ref.coords.x);
+            }
+            let db = SqlDb::new(SqlitePool::connect(&db_path).await?);
+            assert_eq!(COUNT as usize, db.select_all_from("Body").await?.len());
         }
         Ok(())
     }
@@ -144,14 +199,13 @@ mod test_01_body_cache {
 #[before_all]
 #[cfg(test)]
 mod test_02_player_cache {
-    use std::{env, fs::File, path::Path, sync::Arc};
+    use std::{env, fs::File, sync::Arc};
 
     use scilib::coordinate::cartesian::Cartesian;
     use sqlx::SqlitePool;
-    use tokio::sync::Mutex;
     use uuid::Uuid;
 
-    use crate::{cache::PlayerCache, sqldb::SqlDb, tracing};
+    use crate::{cache::PlayerCache, error::Error, sqlstorage::SqlStorage, tracing};
 
     pub fn before_all() {
         spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
@@ -168,21 +222,14 @@ mod test_02_player_cache {
         )
     }
 
-    async fn bootstrap(db_path: &String) -> PlayerCache {
-        if !Path::new(&db_path).exists() {
-            File::create(&db_path).unwrap();
-        }
-        let pool = SqlitePool::connect(&db_path).await.unwrap();
-        let db = SqlDb::new(pool);
-        let mut cache = PlayerCache::new(Arc::new(Mutex::new(db)));
-        cache.init_db().await;
-        cache
+    async fn bootstrap(db_path: &str) -> PlayerCache {
+        PlayerCache::new(Arc::new(SqlStorage::open(db_path).await.unwrap()))
     }
 
     #[tokio::test]
     async fn case_01_new_player() -> anyhow::Result<()> {
         let mut cache = bootstrap(&get_random_db_path()).await;
-        let (player, _, _) = cache.new_player("test123".to_string()).await;
+        let (player, _, _) = cache.new_player("test123".to_string(), "").await?;
         assert_eq!(1, player.id);
         assert_eq!("test123", player.nickname);
         assert_eq!(Cartesian::default(), player.coords);
@@ -197,7 +244,7 @@ mod test_02_player_cache {
         {
             let mut cache = bootstrap(&db_path).await;
             spacebuild_log!(info, "tests", "{}", db_path);
-            let (player, _, _) = cache.new_player("test123".to_string()).await;
+            let (player, _, _) = cache.new_player("test123".to_string(), "").await?;
             assert_eq!(1, player.id);
             assert_eq!("test123", player.nickname);
             assert_eq!(Cartesian::default(), player.coords);
@@ -205,7 +252,7 @@ mod test_02_player_cache {
         }
         {
             let mut cache = bootstrap(&db_path).await;
-            let (id, _, _) = cache.load("test123".to_string()).await;
+            let (id, _, _) = cache.load("test123".to_string()).await?;
             assert_eq!(1, id);
         }
         Ok(())
@@ -214,8 +261,8 @@ mod test_02_player_cache {
     #[tokio::test]
     async fn case_03_new_player_new_player_diff() -> anyhow::Result<()> {
         let mut cache = bootstrap(&get_random_db_path()).await;
-        let (_player1, _, _) = cache.new_player("test123".to_string()).await;
-        let (player2, _, _) = cache.new_player("test456".to_string()).await;
+        let (_player1, _, _) = cache.new_player("test123".to_string(), "").await?;
+        let (player2, _, _) = cache.new_player("test456".to_string(), "").await?;
 
         assert_eq!(2, player2.id);
         assert_eq!("test456", player2.nickname);
@@ -225,11 +272,15 @@ mod test_02_player_cache {
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Player test123 already exists")]
-    async fn case_04_new_player_new_player_same() {
+    async fn case_04_new_player_new_player_same() -> anyhow::Result<()> {
         let mut cache = bootstrap(&get_random_db_path()).await;
-        let (_, _, _) = cache.new_player("test123".to_string()).await;
-        let (_, _, _) = cache.new_player("test123".to_string()).await;
+        let (_, _, _) = cache.new_player("test123".to_string(), "").await?;
+        assert!(matches!(
+            cache.new_player("test123".to_string(), "").await,
+            Err(Error::NicknameTaken)
+        ));
+        assert_eq!(1, cache.cache.len());
+        Ok(())
     }
 
     #[tokio::test]
@@ -238,21 +289,21 @@ mod test_02_player_cache {
         {
             let mut cache = bootstrap(&db_path).await;
             let id = {
-                let (player, _, _) = cache.new_player("test123".to_string()).await;
+                let (player, _, _) = cache.new_player("test123".to_string(), "").await?;
                 player.coords = Cartesian::from(2, 4, 6);
                 player.id
             };
-            cache.sync_and_unload(id).await;
+            cache.sync_and_unload(id).await?;
             let id = {
-                let (player, _, _) = cache.new_player("test456".to_string()).await;
+                let (player, _, _) = cache.new_player("test456".to_string(), "").await?;
                 player.coords = Cartesian::from(3, 5, 7);
                 player.id
             };
-            cache.sync_and_unload(id).await;
+            cache.sync_and_unload(id).await?;
         }
         {
             let mut cache = bootstrap(&db_path).await;
-            let (id, _, _) = cache.load("test123".to_string()).await;
+            let (id, _, _) = cache.load("test123".to_string()).await?;
             let player = cache.get_player(id);
 
             assert_eq!(1, player.id);
@@ -260,7 +311,7 @@ mod test_02_player_cache {
             assert_eq!(Cartesian::from(2, 4, 6), player.coords);
             assert_eq!(false, player.first_state_sent);
 
-            let (id, _, _) = cache.load("test456".to_string()).await;
+            let (id, _, _) = cache.load("test456".to_string()).await?;
             let player = cache.get_player(id);
             assert_eq!(2, player.id);
             assert_eq!("test456", player.nickname);
@@ -269,6 +320,98 @@ mod test_02_player_cache {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn case_06_password_hash_reload() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        {
+            let mut cache = bootstrap(&db_path).await;
+            let (player, _, _) = cache.new_player("test123".to_string(), "hash123").await?;
+            let id = player.id;
+            cache.sync_and_unload(id).await?;
+        }
+        {
+            let mut cache = bootstrap(&db_path).await;
+            assert_eq!(Some("hash123".to_string()), cache.password_hash("test123").await?);
+            assert!(matches!(
+                cache.password_hash("test456").await,
+                Err(Error::InvalidCredentials)
+            ));
+            assert!(matches!(cache.can_register("test123").await, Err(Error::NicknameTaken)));
+            cache.can_register("test456").await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_07_player_without_password() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        File::create(&db_path)?;
+        let pool = SqlitePool::connect(&db_path).await?;
+        sqlx::query("CREATE TABLE Player (id INTEGER PRIMARY KEY, nickname TEXT, coord_x REAL, coord_y REAL, coord_z REAL, velocity_x REAL, velocity_y REAL, velocity_z REAL, direction_x REAL, direction_y REAL, direction_z REAL, current_system INTEGER)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("INSERT INTO Player (nickname) VALUES ('test123')")
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+
+        let mut cache = bootstrap(&db_path).await;
+        assert_eq!(None, cache.password_hash("test123").await?);
+        assert!(matches!(cache.can_register("test123").await, Err(Error::NicknameTaken)));
+        assert!(matches!(
+            cache.set_password_hash("test456", "hash123").await,
+            Err(Error::UnknownPlayer(_))
+        ));
+
+        cache.set_password_hash("test123", "hash123").await?;
+        assert_eq!(Some("hash123".to_string()), cache.password_hash("test123").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_08_hostile_nicknames() -> anyhow::Result<()> {
+        let nicknames = [
+            "test123",
+            "\"",
+            "'",
+            "test\"); DROP TABLE Player; --",
+            "test'); DELETE FROM Player; --",
+            "coord_x",
+            "%",
+            "test_23",
+            "\\",
+        ];
+        let db_path = get_random_db_path();
+        {
+            let mut cache = bootstrap(&db_path).await;
+            for (i, nickname) in nicknames.iter().enumerate() {
+                cache.can_register(nickname).await?;
+                let id = {
+                    let (player, _, _) = cache.new_player(nickname.to_string(), nickname).await?;
+                    player.coords = Cartesian::from(i as f64, 0, 0);
+                    player.id
+                };
+                cache.sync_and_unload(id).await?;
+            }
+        }
+        {
+            let mut cache = bootstrap(&db_path).await;
+            for (i, nickname) in nicknames.iter().enumerate() {
+                assert_eq!(Some(nickname.to_string()), cache.password_hash(nickname).await?);
+                let (id, _, _) = cache.load(nickname.to_string()).await?;
+                let player = cache.get_player(id);
+                assert_eq!(i as u32 + 1, player.id);
+                assert_eq!(*nickname, player.nickname);
+                assert_eq!(Cartesian::from(i as f64, 0, 0), player.coords);
+            }
+            assert!(matches!(
+                cache.password_hash("test%").await,
+                Err(Error::InvalidCredentials)
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[before_all]
@@ -277,7 +420,13 @@ mod test_03_player {
     use scilib::coordinate::cartesian::Cartesian;
     use tokio::sync::mpsc::{self};
 
-    use crate::{player::Player, protocol};
+    use crate::{
+        body::Body,
+        history::History,
+        outbox::{outbox, OUTBOX_CAPACITY},
+        player::{self, Player},
+        protocol,
+    };
 
     pub fn before_all() {
         spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
@@ -288,22 +437,22 @@ mod test_03_player {
 
     #[tokio::test]
     async fn case_01_update() -> anyhow::Result<()> {
-        let (state_send, _state_recv) = mpsc::channel(10000);
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
         let (_action_send, action_recv) = mpsc::channel(10000);
         let mut player = Player::new("test123".to_string(), state_send, action_recv);
         player.coords = Cartesian::from(2, 4, 6);
-        player.update(1f64, vec![], &Vec::new()).await;
+        player.update(1f64, 0f64, vec![], &History::default());
         assert_eq!(Cartesian::from(2, 4, 6), player.coords);
         Ok(())
     }
 
     #[tokio::test]
     async fn case_02_state_recv() -> anyhow::Result<()> {
-        let (state_send, mut state_recv) = mpsc::channel(10000);
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
         let (_action_send, action_recv) = mpsc::channel(10000);
         let mut player = Player::new("test123".to_string(), state_send, action_recv);
         player.coords = Cartesian::from(2, 4, 6);
-        player.update(1f64, vec![], &Vec::new()).await;
+        player.update(1f64, 0f64, vec![], &History::default());
         let result = state_recv.try_recv();
         assert!(result.is_ok());
         if let protocol::state::Game::Player(player_state) = result.unwrap() {
@@ -319,17 +468,18 @@ mod test_03_player {
 
     #[tokio::test]
     async fn case_03_action_send_throttle_no_changes() -> anyhow::Result<()> {
-        let (state_send, mut state_recv) = mpsc::channel(10000);
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
         let (action_send, action_recv) = mpsc::channel(10000);
         let mut player = Player::new("test123".to_string(), state_send, action_recv);
         player.coords = Cartesian::from(2, 4, 6);
         action_send
             .send(protocol::Action::ShipState(protocol::ShipState {
                 throttle_up: false,
+                brake: false,
                 direction: [0.; 3],
             }))
             .await?;
-        player.update(1f64, vec![], &Vec::new()).await;
+        player.update(1f64, 0f64, vec![], &History::default());
         let result = state_recv.try_recv();
         assert!(result.is_ok());
         if let protocol::state::Game::Player(player_state) = result.unwrap() {
@@ -345,17 +495,18 @@ mod test_03_player {
 
     #[tokio::test]
     async fn case_04_action_send_throttle_throttle_up_direction_zero() -> anyhow::Result<()> {
-        let (state_send, mut state_recv) = mpsc::channel(10000);
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
         let (action_send, action_recv) = mpsc::channel(10000);
         let mut player = Player::new("test123".to_string(), state_send, action_recv);
         player.coords = Cartesian::from(2, 4, 6);
         action_send
             .send(protocol::Action::ShipState(protocol::ShipState {
                 throttle_up: true,
+                brake: false,
                 direction: [0.; 3],
             }))
             .await?;
-        player.update(1f64, vec![], &Vec::new()).await;
+        player.update(1f64, 0f64, vec![], &History::default());
         let result = state_recv.try_recv();
         assert!(result.is_ok());
         if let protocol::state::Game::Player(player_state) = result.unwrap() {
@@ -371,17 +522,18 @@ mod test_03_player {
 
     #[tokio::test]
     async fn case_05_action_send_throttle_throttle_up_direction_good() -> anyhow::Result<()> {
-        let (state_send, mut state_recv) = mpsc::channel(10000);
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
         let (action_send, action_recv) = mpsc::channel(10000);
         let mut player = Player::new("test123".to_string(), state_send, action_recv);
         player.coords = Cartesian::from(2, 4, 6);
         action_send
             .send(protocol::Action::ShipState(protocol::ShipState {
                 throttle_up: true,
+                brake: false,
                 direction: [1f64, 0f64, 0f64],
             }))
             .await?;
-        player.update(1f64, vec![], &Vec::new()).await;
+        player.update(1f64, 0f64, vec![], &History::default());
         let result = state_recv.try_recv();
         assert!(result.is_ok());
         if let protocol::state::Game::Player(player_state) = result.unwrap() {
@@ -393,4 +545,2597 @@ mod test_03_player {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn case_06_inertia_after_throttle_down() -> anyhow::Result<()> {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        action_send
+            .send(protocol::Action::ShipState(protocol::ShipState {
+                throttle_up: true,
+                brake: false,
+                direction: [1f64, 0f64, 0f64],
+            }))
+            .await?;
+        player.update(1f64, 0f64, vec![], &History::default());
+        let velocity = player.velocity;
+        action_send
+            .send(protocol::Action::ShipState(protocol::ShipState {
+                throttle_up: false,
+                brake: false,
+                direction: [1f64, 0f64, 0f64],
+            }))
+            .await?;
+        let coords = player.coords;
+        player.update(1f64, 0f64, vec![], &History::default());
+        assert_eq!(velocity, player.velocity);
+        assert_eq!(coords + velocity, player.coords);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_07_brake() -> anyhow::Result<()> {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        player.velocity = Cartesian::from(100, 0, 0);
+        action_send
+            .send(protocol::Action::ShipState(protocol::ShipState {
+                throttle_up: false,
+                brake: true,
+                direction: [0.; 3],
+            }))
+            .await?;
+        player.update(1f64, 0f64, vec![], &History::default());
+        assert_eq!(Cartesian::default(), player.velocity);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_08_max_speed() -> anyhow::Result<()> {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        action_send
+            .send(protocol::Action::ShipState(protocol::ShipState {
+                throttle_up: true,
+                brake: false,
+                direction: [0f64, 1f64, 0f64],
+            }))
+            .await?;
+        for _ in 0..100 {
+            player.update(1f64, 0f64, vec![], &History::default());
+        }
+        assert!((player.velocity.norm() - player::MAX_SPEED).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_09_gravity_pull() -> anyhow::Result<()> {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        let star = Body {
+            id: 1,
+            coords: Cartesian::from(1000, 0, 0),
+            mass: 10000000f64,
+            ..Default::default()
+        };
+        player.update(1f64, 0f64, vec![&star], &History::default());
+        assert!(player.velocity.x > 0f64);
+        assert_eq!(0f64, player.velocity.y);
+        assert_eq!(0f64, player.velocity.z);
+        assert!(player.coords.x > 0f64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_10_massless_body_no_pull() -> anyhow::Result<()> {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        let asteroid = Body {
+            id: 1,
+            coords: Cartesian::from(10, 0, 0),
+            ..Default::default()
+        };
+        player.update(1f64, 0f64, vec![&asteroid], &History::default());
+        assert_eq!(Cartesian::default(), player.velocity);
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_04_orbit {
+    use std::f64::consts::{PI, TAU};
+
+    use scilib::coordinate::cartesian::Cartesian;
+
+    use crate::{
+        body::Body,
+        galaxy::{Galaxy, REINDEX_PERIOD, TIME_SCALE},
+        orbit::Orbit,
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+    const EPSILON: f64 = 1e-6;
+
+    fn orbit(eccentricity: f64, inclination: f64) -> Orbit {
+        Orbit {
+            semi_major_axis: 1000f64,
+            eccentricity,
+            inclination,
+            ascending_node: 0.3,
+            arg_periapsis: 1.2,
+            mean_anomaly_epoch: 0f64,
+        }
+    }
+
+    #[test]
+    fn case_01_circular_orbit_constant_radius() {
+        let orbit = orbit(0f64, 0.1);
+        for i in 0..100 {
+            let position = orbit.position(i as f64 * TAU / 100f64);
+            assert!((position.norm() - 1000f64).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn case_02_periapsis_apoapsis() {
+        let orbit = orbit(0.5, 0f64);
+        assert!((orbit.position(0f64).norm() - 500f64).abs() < EPSILON);
+        assert!((orbit.position(PI).norm() - 1500f64).abs() < EPSILON);
+    }
+
+    #[test]
+    fn case_03_periodic() {
+        let orbit = orbit(0.3, 0.05);
+        let position = orbit.position(1f64);
+        let position_later = orbit.position(1f64 + 3f64 * TAU);
+        assert!((position - position_later).norm() < EPSILON);
+    }
+
+    #[test]
+    fn case_04_kepler_equation() {
+        let orbit = orbit(0.9, 0f64);
+        for mean_anomaly in [-3f64, -1f64, 0f64, 0.5, 2f64, 3.1] {
+            let eccentric_anomaly = orbit.eccentric_anomaly(mean_anomaly);
+            assert!((eccentric_anomaly - 0.9 * eccentric_anomaly.sin() - mean_anomaly).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn case_05_reference_plane() {
+        let orbit = orbit(0.2, 0f64);
+        for i in 0..10 {
+            assert!(orbit.position(i as f64).y.abs() < EPSILON);
+        }
+    }
+
+    fn system() -> Galaxy {
+        let mut galaxy = Galaxy::default();
+        galaxy
+            .insert_celestial(Body {
+                id: 1,
+                gravity_center: 1,
+                coords: Cartesian::from(100, 200, 300),
+                ..Default::default()
+            })
+            .unwrap();
+        galaxy
+            .insert_celestial(Body {
+                id: 2,
+                gravity_center: 1,
+                rotating_speed: 0.01,
+                orbit: orbit(0.1, 0.05),
+                ..Default::default()
+            })
+            .unwrap();
+        galaxy
+            .insert_celestial(Body {
+                id: 3,
+                gravity_center: 2,
+                rotating_speed: 0.1,
+                orbit: Orbit {
+                    semi_major_axis: 50f64,
+                    ..orbit(0f64, 0f64)
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        galaxy
+    }
+
+    #[tokio::test]
+    async fn case_06_galaxy_update_tick_jitter() {
+        let mut galaxy = system();
+        let mut jittery_galaxy = system();
+        galaxy.update(1f64).await;
+        for delta in [0.1, 0.25, 0.05, 0.3, 0.2, 0.1] {
+            jittery_galaxy.update(delta).await;
+        }
+        for id in 1..=3 {
+            let coords = galaxy.body(id).unwrap().coords;
+            let jittery_coords = jittery_galaxy.body(id).unwrap().coords;
+            assert!((coords - jittery_coords).norm() < EPSILON);
+        }
+    }
+
+    #[tokio::test]
+    async fn case_07_galaxy_update_hierarchy() {
+        let mut galaxy = system();
+        galaxy.update(1f64).await;
+        let star = galaxy.body(1).unwrap();
+        let planet = galaxy.body(2).unwrap();
+        let moon = galaxy.body(3).unwrap();
+        assert_eq!(Cartesian::from(100, 200, 300), star.coords);
+        assert!((planet.coords - star.coords - planet.local_position(galaxy.time())).norm() < EPSILON);
+        assert!(((moon.coords - planet.coords).norm() - 50f64).abs() < EPSILON);
+    }
+
+    #[tokio::test]
+    async fn case_08_view_between_reindexes() {
+        let mut galaxy = system();
+        let delta = REINDEX_PERIOD / TIME_SCALE / 2f64;
+        let coords = galaxy.body(3).unwrap().coords;
+        galaxy.update(delta).await;
+        let coords_later = galaxy.body(3).unwrap().coords;
+        assert!((coords - coords_later).norm() > 1f64);
+
+        let view = galaxy.galactics_in_spherical_view(coords_later, 0.5);
+        assert_eq!(1, view.len());
+        assert_eq!(3, view.first().unwrap().id);
+        assert!((view.first().unwrap().coords - coords_later).norm() < EPSILON);
+        assert!(galaxy.galactics_in_spherical_view(coords, 0.5).is_empty());
+    }
+
+    #[tokio::test]
+    async fn case_09_view_after_reindex() {
+        let mut galaxy = system();
+        for _ in 0..100 {
+            galaxy.update(REINDEX_PERIOD / TIME_SCALE / 3f64).await;
+            let coords = galaxy.body(2).unwrap().coords;
+            let view = galaxy.galactics_in_spherical_view(coords, 0.5);
+            assert_eq!(1, view.len());
+            assert_eq!(2, view.first().unwrap().id);
+        }
+    }
+
+    #[test]
+    fn case_10_circular_through() {
+        for local in [
+            Cartesian::from(1000, 0, 0),
+            Cartesian::from(-300, 200, 400),
+            Cartesian::from(0, -50, 0),
+        ] {
+            let orbit = Orbit::circular_through(local);
+            assert_eq!(local.norm(), orbit.semi_major_axis);
+            assert!((orbit.position(orbit.mean_anomaly_epoch) - local).norm() < EPSILON);
+        }
+        assert_eq!(Orbit::default(), Orbit::circular_through(Cartesian::default()));
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_05_star_system {
+    use scilib::coordinate::cartesian::Cartesian;
+
+    use crate::{body::Body, error::Error, galaxy::Galaxy, orbit::Orbit};
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn insert_system(galaxy: &mut Galaxy, star_id: u32, coords: Cartesian) {
+        galaxy
+            .insert_celestial(Body {
+                id: star_id,
+                gravity_center: star_id,
+                coords,
+                ..Default::default()
+            })
+            .unwrap();
+        for i in 1..=10 {
+            galaxy
+                .insert_celestial(Body {
+                    id: star_id + i,
+                    gravity_center: star_id,
+                    rotating_speed: 0.001,
+                    orbit: Orbit {
+                        semi_major_axis: 100f64 * i as f64,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn case_01_systems_are_partitioned() {
+        let mut galaxy = Galaxy::default();
+        insert_system(&mut galaxy, 100, Cartesian::default());
+        insert_system(&mut galaxy, 200, Cartesian::from(100000, 0, 0));
+        galaxy.update(1f64).await;
+
+        assert_eq!(2, galaxy.systems.len());
+        assert_eq!(11, galaxy.borrow_system(100).unwrap().len());
+        assert_eq!(11, galaxy.borrow_system(200).unwrap().len());
+        assert_eq!(Some(200), galaxy.system_of(205));
+
+        let view = galaxy.galactics_in_spherical_view(Cartesian::default(), 5000f64);
+        assert_eq!(11, view.len());
+        assert!(view.iter().all(|body| galaxy.system_of(body.id) == Some(100)));
+    }
+
+    #[tokio::test]
+    async fn case_02_view_across_systems() {
+        let mut galaxy = Galaxy::default();
+        insert_system(&mut galaxy, 100, Cartesian::default());
+        insert_system(&mut galaxy, 200, Cartesian::from(2500, 0, 0));
+        galaxy.update(1f64).await;
+
+        let view = galaxy.galactics_in_spherical_view(Cartesian::from(1250, 0, 0), 10000f64);
+        assert_eq!(22, view.len());
+    }
+
+    #[tokio::test]
+    async fn case_03_unknown_gravity_center() {
+        let mut galaxy = Galaxy::default();
+        insert_system(&mut galaxy, 100, Cartesian::default());
+        let result = galaxy.insert_celestial(Body {
+            id: 300,
+            gravity_center: 299,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(Error::GravityCenterNotFound)));
+        assert!(galaxy.borrow_body(300).is_none());
+    }
+
+    #[tokio::test]
+    async fn case_04_attach_detach_players() {
+        let mut galaxy = Galaxy::default();
+        insert_system(&mut galaxy, 100, Cartesian::default());
+        galaxy.attach_player(100, 1);
+        galaxy.attach_player(100, 2);
+        assert_eq!(2, galaxy.borrow_system(100).unwrap().players.len());
+        galaxy.detach_player(100, 1);
+        assert!(galaxy.borrow_system(100).unwrap().players.contains(&2));
+        assert!(!galaxy.borrow_system(100).unwrap().players.contains(&1));
+    }
+
+    #[tokio::test]
+    async fn case_05_remove_system() {
+        let mut galaxy = Galaxy::default();
+        insert_system(&mut galaxy, 100, Cartesian::default());
+        insert_system(&mut galaxy, 200, Cartesian::from(2500, 0, 0));
+
+        let removed = galaxy.remove_system(100).unwrap();
+        assert_eq!(11, removed.len());
+        assert!(galaxy.remove_system(100).is_none());
+        assert_eq!(vec![200], galaxy.system_ids());
+        assert_eq!(None, galaxy.system_of(105));
+        assert!(galaxy.borrow_body(105).is_none());
+
+        let view = galaxy.galactics_in_spherical_view(Cartesian::from(1250, 0, 0), 10000f64);
+        assert_eq!(11, view.len());
+        assert!(view.iter().all(|body| galaxy.system_of(body.id) == Some(200)));
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_06_history {
+    use tokio::sync::mpsc;
+
+    use crate::{
+        body::Body,
+        galaxy::TIME_SCALE,
+        history::History,
+        orbit::Orbit,
+        outbox::{outbox, OUTBOX_CAPACITY},
+        player::{Player, LAG_SAMPLES},
+        protocol::Action,
+        system::StarSystem,
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn build_system() -> StarSystem {
+        let mut system = StarSystem::new(
+            Body {
+                id: 1,
+                gravity_center: 1,
+                ..Default::default()
+            },
+            0f64,
+        );
+        system.insert(
+            Body {
+                id: 2,
+                gravity_center: 1,
+                rotating_speed: 0.01,
+                orbit: Orbit {
+                    semi_major_axis: 1000f64,
+                    eccentricity: 0.3,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            0f64,
+        );
+        system
+    }
+
+    fn body_of(system: &StarSystem, id: u32) -> Body {
+        system.bodies_at(0f64).into_iter().find(|body| body.id == id).unwrap()
+    }
+
+    fn angle_of(system: &StarSystem, id: u32, time: f64) -> f64 {
+        let coords = system.position_of(id, time).unwrap();
+        coords.z.atan2(coords.x)
+    }
+
+    fn build_history(until: f64) -> History {
+        let mut history = History::default();
+        let mut time = 0f64;
+        while time <= until {
+            history.push(time);
+            time += 1f64;
+        }
+        history
+    }
+
+    #[tokio::test]
+    async fn case_01_window_is_bounded() {
+        let mut history = History::new(50f64);
+        for i in 0..1000 {
+            history.push(i as f64);
+        }
+        assert_eq!(51, history.len());
+        assert_eq!(Some(949f64), history.oldest());
+        assert_eq!(Some(999f64), history.newest());
+
+        history.set_window(10f64);
+        assert_eq!(11, history.len());
+        assert_eq!(Some(989f64), history.oldest());
+    }
+
+    #[tokio::test]
+    async fn case_02_lookup_by_time() {
+        let mut history = History::new(100f64);
+        for i in 0..10 {
+            history.push(i as f64 * 2f64);
+        }
+        assert_eq!(Some(10f64), history.at(10f64));
+        assert_eq!(Some(10f64), history.at(11.5));
+        assert_eq!(Some(18f64), history.at(1000f64));
+        assert!(history.at(-1f64).is_none());
+        assert!(History::default().at(0f64).is_none());
+    }
+
+    #[tokio::test]
+    async fn case_03_seen_at_solves_the_orbit() {
+        let system = build_system();
+        let history = build_history(40f64);
+        let planet = body_of(&system, 2);
+
+        for time in [12.3, 25.75, 39.9] {
+            let seen_at = history.seen_at(&planet, angle_of(&system, 2, time)).unwrap();
+            assert!((seen_at - time).abs() < 1e-4, "{} != {}", seen_at, time);
+        }
+        assert!(history.seen_at(&body_of(&system, 1), 0f64).is_none());
+        assert!(History::default().seen_at(&planet, 0f64).is_none());
+    }
+
+    #[tokio::test]
+    async fn case_04_ping_estimates_lag() -> anyhow::Result<()> {
+        let system = build_system();
+        let history = build_history(40f64);
+        let (star, planet) = (body_of(&system, 1), body_of(&system, 2));
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        // Bodies out of view are unknown to the player.
+        action_send.send(Action::Ping((2, 0f64))).await?;
+        player.update(0f64, 0f64, vec![], &history);
+        action_send.send(Action::Ping((42, 0f64))).await?;
+        action_send.send(Action::Ping((1, 0f64))).await?;
+        player.update(0f64, 0f64, vec![&star, &planet], &history);
+        assert!(player.prev_lag_values.is_empty());
+
+        action_send
+            .send(Action::Ping((2, angle_of(&system, 2, 40f64 - TIME_SCALE))))
+            .await?;
+        player.update(0f64, 0f64, vec![&star, &planet], &history);
+        assert!((player.average_lag_value - 1f64).abs() < 1e-3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_05_lag_samples_are_bounded() -> anyhow::Result<()> {
+        let system = build_system();
+        let history = build_history(40f64);
+        let planet = body_of(&system, 2);
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        for _ in 0..LAG_SAMPLES * 5 {
+            action_send.send(Action::Ping((2, angle_of(&system, 2, 40f64)))).await?;
+        }
+        player.update(0f64, 0f64, vec![&planet], &history);
+        assert_eq!(LAG_SAMPLES, player.prev_lag_values.len());
+        assert!(player.average_lag_value.abs() < 1e-3);
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_07_latency {
+    use tokio::sync::mpsc;
+
+    use crate::{
+        history::History,
+        outbox::{outbox, OutboxReceiver, OUTBOX_CAPACITY},
+        player::{Player, PENDING_PINGS, PING_PERIOD},
+        protocol::{state::Game, Action, Pong},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn pings(state_recv: &mut OutboxReceiver) -> Vec<(u32, f64)> {
+        let mut pings = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            if let Game::Ping(ping) = state {
+                pings.push((ping.seq, ping.server_time));
+            }
+        }
+        pings
+    }
+
+    #[tokio::test]
+    async fn case_01_ping_is_periodic() {
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        player.update(PING_PERIOD / 2f64, 0f64, vec![], &History::default());
+        assert_eq!(
+            vec![0],
+            pings(&mut state_recv).iter().map(|ping| ping.0).collect::<Vec<_>>()
+        );
+        player.update(PING_PERIOD / 2f64, 0f64, vec![], &History::default());
+        assert!(pings(&mut state_recv).is_empty());
+        player.update(PING_PERIOD / 2f64, 0f64, vec![], &History::default());
+        assert_eq!(
+            vec![1],
+            pings(&mut state_recv).iter().map(|ping| ping.0).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn case_02_pong_measures_latency() -> anyhow::Result<()> {
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        player.update(0f64, 0f64, vec![], &History::default());
+        let (seq, server_time) = pings(&mut state_recv)[0];
+
+        action_send.send(Action::Pong(Pong::new(seq + 1, None))).await?;
+        player.update(0f64, 0f64, vec![], &History::default());
+        assert!(player.latency().is_none());
+
+        action_send
+            .send(Action::Pong(Pong::new(seq, Some(server_time + 100f64))))
+            .await?;
+        player.update(0f64, 0f64, vec![], &History::default());
+        let latency = player.latency().unwrap();
+        assert!(latency.rtt >= 0f64 && latency.rtt < 1f64);
+        assert!((latency.clock_offset.unwrap() - 100f64).abs() < 1f64);
+
+        action_send.send(Action::Pong(Pong::new(seq, None))).await?;
+        player.update(0f64, 0f64, vec![], &History::default());
+        assert_eq!(Some(latency), player.latency());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_unanswered_pings_are_bounded() {
+        let (state_send, _state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        for _ in 0..PENDING_PINGS * 3 {
+            player.update(PING_PERIOD, 0f64, vec![], &History::default());
+        }
+        assert_eq!(PENDING_PINGS, player.pending_pings.len());
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_08_ships {
+    use scilib::coordinate::cartesian::Cartesian;
+
+    use crate::{
+        instance::{Instance, VIEW_RADIUS},
+        outbox::OutboxReceiver,
+        protocol::state::{Game, Ship},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn ships(state_recv: &mut OutboxReceiver) -> Vec<Vec<Ship>> {
+        let mut ships = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            if let Game::Ships(in_view) = state {
+                ships.push(in_view);
+            }
+        }
+        ships
+    }
+
+    #[tokio::test]
+    async fn case_01_ships_in_view() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (id_1, _action_1, mut state_1) = instance.register("test123".to_string(), "").await?;
+        let (id_2, _action_2, mut state_2) = instance.register("test456".to_string(), "").await?;
+
+        let coords = instance.players.cache.get(&id_1).unwrap().coords;
+        instance.players.cache.get_mut(&id_2).unwrap().coords = coords + Cartesian::from(100, 0, 0);
+        instance.update(0.1).await;
+
+        let ships_1 = ships(&mut state_1);
+        assert_eq!(1, ships_1.len());
+        assert_eq!(1, ships_1[0].len());
+        assert_eq!(id_2, ships_1[0][0].id);
+        assert_eq!("test456", ships_1[0][0].nickname);
+        let ships_2 = ships(&mut state_2);
+        assert_eq!(1, ships_2.len());
+        assert_eq!(id_1, ships_2[0][0].id);
+
+        instance.players.cache.get_mut(&id_2).unwrap().coords = coords + Cartesian::from(VIEW_RADIUS * 2f64, 0, 0);
+        instance.update(0.1).await;
+        assert_eq!(vec![Vec::<Ship>::new()], ships(&mut state_1));
+
+        instance.update(0.1).await;
+        assert!(ships(&mut state_1).is_empty());
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_09_view {
+    use scilib::coordinate::cartesian::Cartesian;
+    use serde::Serialize;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        body::Body,
+        galaxy::Galaxy,
+        history::History,
+        instance::VIEW_RADIUS,
+        orbit::Orbit,
+        outbox::{outbox, OutboxReceiver, OUTBOX_CAPACITY},
+        player::{Player, ENV_CHUNK_SIZE},
+        protocol::state::{self, Game},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+    const DELTA: f64 = 0.1;
+
+    /// Body as sent every tick in `Env` chunks before delta compression.
+    #[derive(Serialize)]
+    struct LegacyBody {
+        id: u32,
+        coords: [f64; 3],
+        rotating_speed: f64,
+        gravity_center: u32,
+        body_type: String,
+    }
+
+    #[derive(Serialize)]
+    enum LegacyGame {
+        Env(Vec<LegacyBody>),
+    }
+
+    fn build_galaxy() -> Galaxy {
+        let mut galaxy = Galaxy::default();
+        galaxy
+            .insert_celestial(Body {
+                id: 1,
+                gravity_center: 1,
+                body_type: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        for i in 0..200 {
+            galaxy
+                .insert_celestial(Body {
+                    id: 2 + i,
+                    gravity_center: 1,
+                    body_type: 2,
+                    rotating_speed: 0.001,
+                    orbit: Orbit {
+                        semi_major_axis: 500f64 + 20f64 * i as f64,
+                        mean_anomaly_epoch: i as f64,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        galaxy
+    }
+
+    async fn tick(galaxy: &mut Galaxy, player: &mut Player) {
+        galaxy.update(DELTA).await;
+        let env = galaxy.galactics_in_spherical_view(player.coords, VIEW_RADIUS);
+        player.update(DELTA, galaxy.time(), env.iter().collect(), &History::default());
+    }
+
+    fn env_states(state_recv: &mut OutboxReceiver) -> Vec<Game> {
+        let mut states = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            if matches!(state, Game::EnvEnter(_) | Game::EnvUpdate(_) | Game::EnvLeave(_)) {
+                states.push(state);
+            }
+        }
+        states
+    }
+
+    fn bytes_of(states: &[Game]) -> usize {
+        states
+            .iter()
+            .map(|state| serde_json::to_string(state).unwrap().len())
+            .sum()
+    }
+
+    fn legacy_bytes_of(galaxy: &Galaxy, player: &Player) -> usize {
+        let env = galaxy.galactics_in_spherical_view(player.coords, VIEW_RADIUS);
+        env.chunks(ENV_CHUNK_SIZE)
+            .map(|chunk| {
+                let bodies = chunk
+                    .iter()
+                    .map(|body| {
+                        let body = state::Body::from(body.clone());
+                        LegacyBody {
+                            id: body.id,
+                            coords: body.coords,
+                            rotating_speed: body.rotating_speed,
+                            gravity_center: body.gravity_center,
+                            body_type: body.body_type,
+                        }
+                    })
+                    .collect();
+                serde_json::to_string(&LegacyGame::Env(bodies)).unwrap().len()
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn case_01_enter_once() {
+        let mut galaxy = build_galaxy();
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        tick(&mut galaxy, &mut player).await;
+        let states = env_states(&mut state_recv);
+        let entered: usize = states
+            .iter()
+            .map(|state| match state {
+                Game::EnvEnter(env) => env.bodies.len(),
+                _ => panic!("unexpected {:?}", state),
+            })
+            .sum();
+        assert_eq!(201, entered);
+        assert_eq!(201usize.div_ceil(ENV_CHUNK_SIZE), states.len());
+
+        for _ in 0..10 {
+            tick(&mut galaxy, &mut player).await;
+            assert!(env_states(&mut state_recv).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn case_02_update_changed_fields() {
+        let mut galaxy = build_galaxy();
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        tick(&mut galaxy, &mut player).await;
+        env_states(&mut state_recv);
+
+        galaxy.borrow_body_mut(10).unwrap().mass = 42f64;
+        tick(&mut galaxy, &mut player).await;
+        let states = env_states(&mut state_recv);
+        assert_eq!(1, states.len());
+        let Game::EnvUpdate(env) = &states[0] else {
+            panic!("unexpected {:?}", states[0]);
+        };
+        assert_eq!(
+            vec![state::BodyUpdate {
+                id: 10,
+                mass: Some(42f64),
+                ..Default::default()
+            }],
+            env.bodies
+        );
+        assert_eq!(
+            r#"{"EnvUpdate":{"time":2.0,"bodies":[{"id":10,"mass":42.0}]}}"#,
+            serde_json::to_string(&states[0]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn case_03_leave() {
+        let mut galaxy = build_galaxy();
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        tick(&mut galaxy, &mut player).await;
+        env_states(&mut state_recv);
+
+        player.coords = Cartesian::from(VIEW_RADIUS * 10f64, 0, 0);
+        tick(&mut galaxy, &mut player).await;
+        let states = env_states(&mut state_recv);
+        assert_eq!(1, states.len());
+        let Game::EnvLeave(ids) = &states[0] else {
+            panic!("unexpected {:?}", states[0]);
+        };
+        assert_eq!(201, ids.len());
+        assert!(player.view.is_empty());
+    }
+
+    #[tokio::test]
+    async fn case_04_extrapolated_positions() {
+        let mut galaxy = build_galaxy();
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        tick(&mut galaxy, &mut player).await;
+
+        let known: std::collections::HashMap<u32, state::Body> = env_states(&mut state_recv)
+            .into_iter()
+            .flat_map(|state| match state {
+                Game::EnvEnter(env) => env.bodies,
+                _ => vec![],
+            })
+            .map(|body| (body.id, body))
+            .collect();
+        for _ in 0..50 {
+            tick(&mut galaxy, &mut player).await;
+        }
+        for body in known.values() {
+            let expected = galaxy.position_of(body.id).unwrap();
+            let coords = body.position_at(&known, galaxy.time()).unwrap();
+            assert!((expected.x - coords[0]).abs() < 1e-6);
+            assert!((expected.y - coords[1]).abs() < 1e-6);
+            assert!((expected.z - coords[2]).abs() < 1e-6);
+        }
+    }
+
+    #[tokio::test]
+    async fn case_05_bytes_per_tick() {
+        let mut galaxy = build_galaxy();
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (_action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+        tick(&mut galaxy, &mut player).await;
+        let first_tick_bytes = bytes_of(&env_states(&mut state_recv));
+
+        let ticks = 100;
+        let mut legacy_bytes = 0;
+        let mut bytes = 0;
+        for i in 0..ticks {
+            if i == ticks / 2 {
+                galaxy.borrow_body_mut(10).unwrap().mass = 42f64;
+            }
+            tick(&mut galaxy, &mut player).await;
+            legacy_bytes += legacy_bytes_of(&galaxy, &player);
+            bytes += bytes_of(&env_states(&mut state_recv));
+        }
+        spacebuild_log!(
+            info,
+            "test",
+            "Env bytes per tick: legacy {}, first tick {}, then {}",
+            legacy_bytes / ticks,
+            first_tick_bytes,
+            bytes as f64 / ticks as f64
+        );
+        assert!(bytes * 1000 < legacy_bytes);
+        // Entering bodies carry their orbit, once.
+        assert!(first_tick_bytes < legacy_bytes / ticks * 3);
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_10_encoding {
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::{
+        error::Error,
+        protocol::{
+            state::{self, Game},
+            Action, Encoding, Pong, ShipState,
+        },
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    #[tokio::test]
+    async fn case_01_subprotocols() {
+        assert_eq!(
+            Some(Encoding::MessagePack),
+            Encoding::from_subprotocols("chat, spacebuild.msgpack, spacebuild.json")
+        );
+        assert_eq!(Some(Encoding::Json), Encoding::from_subprotocols("spacebuild.json"));
+        assert_eq!(None, Encoding::from_subprotocols("chat"));
+        assert_eq!(Some(Encoding::Json), Encoding::of(&Message::text("{}")));
+        assert_eq!(None, Encoding::of(&Message::Ping(Default::default())));
+    }
+
+    #[tokio::test]
+    async fn case_02_round_trip() -> anyhow::Result<()> {
+        let update = Game::EnvUpdate(state::EnvUpdate {
+            time: 12.5,
+            bodies: vec![state::BodyUpdate {
+                id: 3,
+                mass: Some(42f64),
+                ..Default::default()
+            }],
+        });
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let message = encoding.encode(&update)?;
+            assert_eq!(Some(encoding), Encoding::of(&message));
+            let Game::EnvUpdate(decoded) = Encoding::decode(&message)? else {
+                panic!("unexpected variant");
+            };
+            assert_eq!(12.5, decoded.time);
+            assert_eq!(Some(42f64), decoded.bodies[0].mass);
+            assert_eq!(None, decoded.bodies[0].orbit);
+
+            let message = encoding.encode(&Action::Pong(Pong::new(7, None)))?;
+            assert!(matches!(Encoding::decode(&message)?, Action::Pong(Pong { seq: 7, .. })));
+            let message = encoding.encode(&Action::ShipState(ShipState {
+                throttle_up: true,
+                brake: false,
+                direction: [1., 2., 3.],
+            }))?;
+            assert!(matches!(
+                Encoding::decode(&message)?,
+                Action::ShipState(ShipState { throttle_up: true, .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_message_pack_is_smaller() -> anyhow::Result<()> {
+        let player = Game::Player(state::Player {
+            coords: [1234.5678 / 3f64, -8765.4321 / 7f64, 1f64 / 3f64],
+            velocity: [12.5f64.sqrt(), 0.25f64.ln(), -3.75f64.cbrt()],
+            rtt: Some(0.012 / 9f64),
+        });
+        let Message::Text(json) = Encoding::Json.encode(&player)? else {
+            panic!("not a text frame");
+        };
+        let Message::Binary(bytes) = Encoding::MessagePack.encode(&player)? else {
+            panic!("not a binary frame");
+        };
+        assert!(bytes.len() < json.len());
+        assert!(matches!(
+            Encoding::decode::<Game>(&Message::Binary(vec![0xc1].into())),
+            Err(Error::MessagePackDecodeError(_))
+        ));
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_11_errors {
+    use tokio::sync::mpsc;
+
+    use crate::{
+        error::Error,
+        history::History,
+        outbox::{outbox, OUTBOX_CAPACITY},
+        player::Player,
+        protocol::{
+            state::{ErrorCode, Game},
+            Action, Login,
+        },
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    #[tokio::test]
+    async fn case_01_codes() {
+        assert_eq!(ErrorCode::InvalidMessage, Error::NotADataMessage.code());
+        assert_eq!(ErrorCode::NotALoginAction, Error::NotALoginAction.code());
+        assert_eq!(
+            ErrorCode::AuthenticationFailed,
+            Error::PlayerAlreadyAuthenticated.code()
+        );
+        assert_eq!(
+            ErrorCode::IncompatibleProtocol,
+            Error::ServerError(ErrorCode::IncompatibleProtocol, String::new()).code()
+        );
+        assert_eq!(ErrorCode::Internal, Error::FailedToSaveInstanceAtStop.code());
+    }
+
+    #[tokio::test]
+    async fn case_02_login_refused_in_game() -> anyhow::Result<()> {
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let (action_send, action_recv) = mpsc::channel(10000);
+        let mut player = Player::new("test123".to_string(), state_send, action_recv);
+
+        action_send
+            .send(Action::Login(Login::new("test456", "password")))
+            .await?;
+        player.update(0f64, 0f64, vec![], &History::default());
+
+        let mut errors = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            if let Game::Error { code, .. } = state {
+                errors.push(code);
+            }
+        }
+        assert_eq!(vec![ErrorCode::UnexpectedAction], errors);
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_12_auth {
+    use std::sync::Arc;
+
+    use scilib::coordinate::cartesian::Cartesian;
+
+    use crate::{
+        auth::{hash_password, verify_password, MIN_PASSWORD_LENGTH},
+        error::Error,
+        instance::Instance,
+        storage::{MemoryStorage, PlayerState, Snapshot, Storage},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    #[tokio::test]
+    async fn case_01_hash_verify() -> anyhow::Result<()> {
+        let hash = hash_password("password123".to_string()).await?;
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(!hash.contains("password123"));
+        assert_ne!(hash, hash_password("password123".to_string()).await?);
+
+        verify_password("password123".to_string(), hash.clone()).await?;
+        assert!(matches!(
+            verify_password("password124".to_string(), hash).await,
+            Err(Error::InvalidCredentials)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_short_password() {
+        assert!(matches!(
+            hash_password("a".repeat(MIN_PASSWORD_LENGTH - 1)).await,
+            Err(Error::PasswordTooShort(MIN_PASSWORD_LENGTH))
+        ));
+    }
+
+    #[tokio::test]
+    async fn case_03_authenticate() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        assert!(matches!(
+            instance.authenticate("test123".to_string(), "password123").await,
+            Err(Error::InvalidCredentials)
+        ));
+
+        let hash = hash_password("password123".to_string()).await?;
+        let (id, _, _) = instance.register("test123".to_string(), &hash).await?;
+        instance.leave(id).await;
+        assert!(matches!(
+            instance.register("test123".to_string(), &hash).await,
+            Err(Error::NicknameTaken)
+        ));
+
+        assert!(matches!(
+            instance.authenticate("test123".to_string(), "password124").await,
+            Err(Error::InvalidCredentials)
+        ));
+        let (id_later, _, _) = instance.authenticate("test123".to_string(), "password123").await?;
+        assert_eq!(id, id_later);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_04_account_without_password() -> anyhow::Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut instance = Instance::with_storage(storage.clone()).await?;
+        let current_system = instance.gen_system(Cartesian::default()).await;
+        storage
+            .write(&[Snapshot {
+                players: vec![PlayerState {
+                    id: 1,
+                    nickname: "test123".to_string(),
+                    coords: Cartesian::default(),
+                    velocity: Cartesian::default(),
+                    direction: Cartesian::default(),
+                    current_system,
+                }],
+                ..Default::default()
+            }])
+            .await?;
+
+        // Nobody can claim it, until an operator sets its password.
+        let hash = hash_password("password123".to_string()).await?;
+        assert!(matches!(
+            instance.register("test123".to_string(), &hash).await,
+            Err(Error::NicknameTaken)
+        ));
+        assert!(matches!(
+            instance.authenticate("test123".to_string(), "password123").await,
+            Err(Error::PasswordNotSet)
+        ));
+
+        instance.set_password_hash("test123", &hash).await?;
+        let (id, _, _) = instance.authenticate("test123".to_string(), "password123").await?;
+        assert_eq!(1, id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_05_nickname_case() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let hash = hash_password("password123".to_string()).await?;
+        let (id, _, _) = instance.register("alice".to_string(), &hash).await?;
+        let next_body_id = instance.bodies.next_id;
+        for nickname in ["Alice", "ALICE"] {
+            assert!(matches!(
+                instance.register(nickname.to_string(), &hash).await,
+                Err(Error::NicknameTaken)
+            ));
+            assert!(matches!(
+                instance.authenticate(nickname.to_string(), "password123").await,
+                Err(Error::PlayerAlreadyAuthenticated)
+            ));
+        }
+        // Refused accounts get no system.
+        assert_eq!(next_body_id, instance.bodies.next_id);
+
+        instance.leave(id).await;
+        let (id_later, _, _) = instance.authenticate("ALICE".to_string(), "password123").await?;
+        assert_eq!(id, id_later);
+        assert_eq!("alice", instance.players.cache.get(&id).unwrap().nickname);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_06_system_missing() -> anyhow::Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut instance = Instance::with_storage(storage.clone()).await?;
+        let hash = hash_password("password123".to_string()).await?;
+        storage.insert_player("test123", &hash).await?;
+
+        // Its system 0 was never stored, the login fails instead of the server.
+        assert!(matches!(
+            instance.authenticate("test123".to_string(), "password123").await,
+            Err(Error::BodyNotFound(0))
+        ));
+        assert!(instance.players.cache.is_empty());
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_13_sessions {
+    use std::time::Duration;
+
+    use crate::{auth::hash_password, error::Error, instance::Instance, protocol::state::Game};
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    #[tokio::test]
+    async fn case_01_suspend_resume() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (id, action_send, state_recv) = instance.register("test123".to_string(), "").await?;
+        let token = instance.open_session(id);
+        assert!(matches!(instance.resume(&token), Err(Error::SessionInUse)));
+
+        let notice = Game::Notice {
+            message: "unsent".to_string(),
+        };
+        instance.suspend(id, action_send, state_recv, Some(notice));
+        instance.update(0.1).await;
+        let (resumed_id, suspended) = instance.resume(&token)?;
+        assert_eq!(id, resumed_id);
+        assert!(matches!(suspended.pending, Some(Game::Notice { message }) if message == "unsent"));
+        assert!(instance.players.cache.contains_key(&id));
+
+        let new_token = instance.open_session(id);
+        assert_ne!(token, new_token);
+        assert!(matches!(instance.resume(&token), Err(Error::InvalidSessionToken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_grace_period_over() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        instance.set_resume_grace_period(Duration::ZERO);
+        let (id, action_send, state_recv) = instance.register("test123".to_string(), "").await?;
+        let token = instance.open_session(id);
+        instance.suspend(id, action_send, state_recv, None);
+
+        instance.update(0.1).await;
+        assert!(instance.players.cache.is_empty());
+        assert!(instance.borrow_galaxy().system_ids().is_empty());
+        assert!(matches!(instance.resume(&token), Err(Error::InvalidSessionToken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_login_ends_suspended_session() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let hash = hash_password("password123".to_string()).await?;
+        let (id, action_send, state_recv) = instance.register("test123".to_string(), &hash).await?;
+        let token = instance.open_session(id);
+        assert!(matches!(
+            instance.authenticate("test123".to_string(), "password123").await,
+            Err(Error::PlayerAlreadyAuthenticated)
+        ));
+
+        instance.suspend(id, action_send, state_recv, None);
+        let (id_later, _, _) = instance.authenticate("test123".to_string(), "password123").await?;
+        assert_eq!(id, id_later);
+        assert!(matches!(instance.resume(&token), Err(Error::InvalidSessionToken)));
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_14_spectators {
+    use std::collections::HashSet;
+
+    use crate::{
+        instance::Instance,
+        outbox::OutboxReceiver,
+        protocol::{
+            state::{ErrorCode, Game},
+            Action, ShipState, Target, CAPABILITY_SHIPS,
+        },
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn states(state_recv: &mut OutboxReceiver) -> Vec<Game> {
+        let mut states = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            states.push(state);
+        }
+        states
+    }
+
+    fn entered(states: &[Game]) -> usize {
+        states
+            .iter()
+            .map(|state| match state {
+                Game::EnvEnter(env) => env.bodies.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn case_01_watch_busiest_system() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (player_id, _action, _state) = instance.register("test123".to_string(), "").await?;
+        let star_id = instance.borrow_galaxy().system_ids()[0];
+        let capabilities = HashSet::from([CAPABILITY_SHIPS.to_string()]);
+        let (id_1, _action_1, mut state_1) = instance.spectate(None, capabilities.clone());
+        let (id_2, _action_2, mut state_2) = instance.spectate(Some(Target::System(star_id)), capabilities);
+        assert_ne!(id_1, id_2);
+
+        instance.update(0.1).await;
+        let bodies_count = instance.borrow_galaxy().borrow_system(star_id).unwrap().len();
+        for state_recv in [&mut state_1, &mut state_2] {
+            let received = states(state_recv);
+            assert_eq!(bodies_count, entered(&received));
+            assert!(received
+                .iter()
+                .any(|state| matches!(state, Game::Ships(ships) if ships.len() == 1 && ships[0].id == player_id)));
+        }
+        assert_eq!(1, instance.players.cache.len());
+        assert_eq!(vec![star_id], instance.borrow_galaxy().system_ids());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_watch_region() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (_, _action, _state) = instance.register("test123".to_string(), "").await?;
+        let star_id = instance.borrow_galaxy().system_ids()[0];
+        let star = instance.borrow_galaxy().body(star_id).unwrap().coords;
+        let (_, action_send, mut state_recv) = instance.spectate(
+            Some(Target::Region {
+                center: [star.x, star.y, star.z],
+                radius: 100f64,
+            }),
+            HashSet::new(),
+        );
+
+        instance.update(0.1).await;
+        let in_region = entered(&states(&mut state_recv));
+        assert!(in_region >= 1);
+        assert!(in_region < instance.borrow_galaxy().borrow_system(star_id).unwrap().len());
+
+        action_send.send(Action::Watch(Some(Target::System(u32::MAX)))).await?;
+        instance.update(0.1).await;
+        let left: usize = states(&mut state_recv)
+            .iter()
+            .map(|state| match state {
+                Game::EnvLeave(ids) => ids.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(in_region, left);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_read_only() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (id, action_send, mut state_recv) = instance.spectate(None, HashSet::new());
+        action_send
+            .send(Action::ShipState(ShipState {
+                throttle_up: true,
+                brake: false,
+                direction: [1f64, 0f64, 0f64],
+            }))
+            .await?;
+
+        instance.update(0.1).await;
+        let errors: Vec<ErrorCode> = states(&mut state_recv)
+            .into_iter()
+            .filter_map(|state| match state {
+                Game::Error { code, .. } => Some(code),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![ErrorCode::UnexpectedAction], errors);
+        assert!(instance.players.cache.is_empty());
+
+        instance.leave_spectator(id);
+        assert_eq!(0, instance.spectator_count());
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_15_admin {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use crate::{
+        error::Error,
+        instance::Instance,
+        player::Latency,
+        protocol::{
+            state::{ErrorCode, Game},
+            Command, Role,
+        },
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn get_random_db_path() -> String {
+        format!(
+            "{}space_build_tests_{}.db",
+            env::temp_dir().to_str().unwrap(),
+            Uuid::new_v4()
+        )
+    }
+
+    #[tokio::test]
+    async fn case_01_role_persists() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        {
+            let mut instance = Instance::from_path(&db_path).await?;
+            let (id, _, _) = instance.register("test123".to_string(), "").await?;
+            assert_eq!(Role::Player, instance.role(id));
+            instance.set_role("test123", Role::Moderator).await?;
+            assert_eq!(Role::Moderator, instance.role(id));
+            assert!(matches!(
+                instance.set_role("test456", Role::Admin).await,
+                Err(Error::UnknownPlayer(_))
+            ));
+            instance.leave(id).await;
+            instance.save_all().await?;
+        }
+
+        let mut instance = Instance::from_path(&db_path).await?;
+        let (id, _, _) = instance.authenticate_verified("test123".to_string()).await?;
+        assert_eq!(Role::Moderator, instance.role(id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_ban() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        instance.register("admin".to_string(), "").await?;
+        let (id, _, mut state_recv) = instance.register("test123".to_string(), "").await?;
+
+        let ban = Command::Ban {
+            nickname: "test123".to_string(),
+            reason: Some("griefing".to_string()),
+            duration: None,
+        };
+        instance.execute("admin", &ban).await?;
+        assert!(!instance.players.cache.contains_key(&id));
+        assert!(matches!(
+            state_recv.recv().await,
+            Some(Game::Error { code: ErrorCode::Kicked, message }) if message == "griefing"
+        ));
+        assert!(matches!(
+            instance.authenticate_verified("test123".to_string()).await,
+            Err(Error::Banned(reason)) if reason == "griefing"
+        ));
+        let unknown = Command::Ban {
+            nickname: "nobody".to_string(),
+            reason: None,
+            duration: None,
+        };
+        assert!(matches!(
+            instance.execute("admin", &unknown).await,
+            Err(Error::UnknownPlayer(_))
+        ));
+
+        let unban = Command::Unban {
+            nickname: "test123".to_string(),
+        };
+        instance.execute("admin", &unban).await?;
+        instance.authenticate_verified("test123".to_string()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_commands() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        instance.register("admin".to_string(), "").await?;
+        let (id, _, mut state_recv) = instance.register("test123".to_string(), "").await?;
+
+        let teleport = Command::Teleport {
+            nickname: "test123".to_string(),
+            coords: [1000., 2000., 3000.],
+        };
+        instance.execute("admin", &teleport).await?;
+        let player = instance.players.cache.get(&id).unwrap();
+        assert_eq!(
+            [1000., 2000., 3000.],
+            [player.coords.x, player.coords.y, player.coords.z]
+        );
+
+        // Nobody is in the new system, it is saved and unloaded right away.
+        let systems = instance.borrow_galaxy().system_ids();
+        let answer = instance
+            .execute("admin", &Command::SpawnSystem { coords: [1e6, 0., 0.] })
+            .await?;
+        assert_eq!(systems, instance.borrow_galaxy().system_ids());
+        let star_id: u32 = answer.trim_start_matches("Spawned system ").parse()?;
+        instance.save_all().await?;
+        assert_eq!(star_id, instance.bodies.load_body(star_id).await?.gravity_center);
+
+        instance
+            .execute(
+                "admin",
+                &Command::Broadcast {
+                    message: "hello".to_string(),
+                },
+            )
+            .await?;
+        assert!(matches!(state_recv.recv().await, Some(Game::Notice { message }) if message == "hello"));
+
+        let kick = Command::Kick {
+            nickname: "nobody".to_string(),
+            reason: None,
+        };
+        let result = instance.execute("admin", &kick).await;
+        assert!(matches!(result, Err(Error::PlayerNotLoggedIn(_))));
+        instance.audit("admin", &kick, &result).await;
+        instance
+            .audit("admin", &teleport, &Ok("Teleported test123".to_string()))
+            .await;
+
+        let entries = instance.borrow_audit().entries().await?;
+        assert_eq!(2, entries.len());
+        assert_eq!("admin", entries[0].actor);
+        assert!(entries[0].outcome.starts_with("error: "));
+        assert!(matches!(&entries[1].command, Command::Teleport { nickname, .. } if nickname == "test123"));
+        assert_eq!("ok: Teleported test123", entries[1].outcome);
+
+        let latency = Command::Latency {
+            nickname: "test123".to_string(),
+        };
+        assert_eq!(
+            "test123: no pong received yet",
+            instance.execute("admin", &latency).await?
+        );
+        instance.players.cache.get_mut(&id).unwrap().latency = Some(Latency {
+            rtt: 0.042,
+            clock_offset: Some(-0.003),
+        });
+        assert_eq!(
+            "test123: round trip 42.0 ms, clock offset -3.0 ms",
+            instance.execute("admin", &latency).await?
+        );
+        assert_eq!(Role::Admin, latency.required_role());
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_16_bans {
+    use std::{env, net::IpAddr, str::FromStr};
+
+    use uuid::Uuid;
+
+    use crate::{
+        auth::hash_password,
+        ban::parse_address,
+        error::Error,
+        instance::Instance,
+        protocol::{
+            state::{ErrorCode, Game},
+            Command, Role,
+        },
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn get_random_db_path() -> String {
+        format!(
+            "{}space_build_tests_{}.db",
+            env::temp_dir().to_str().unwrap(),
+            Uuid::new_v4()
+        )
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        IpAddr::from_str(address).unwrap()
+    }
+
+    #[tokio::test]
+    async fn case_01_expiry() -> anyhow::Result<()> {
+        let instance = Instance::in_memory().await?;
+        instance.bans.ban("test123", "over", "admin", Some(-1.)).await?;
+        instance.bans.check("test123").await?;
+        assert!(instance.bans.list().await?.is_empty());
+
+        instance
+            .bans
+            .ban("test123", "for an hour", "admin", Some(3600.))
+            .await?;
+        assert!(matches!(
+            instance.bans.check("test123").await,
+            Err(Error::Banned(reason)) if reason == "for an hour"
+        ));
+        let bans = instance.bans.list().await?;
+        assert_eq!(1, bans.len());
+        assert_eq!(Some("test123".to_string()), bans[0].nickname);
+        assert!(bans[0].expires_at.unwrap() > bans[0].banned_at);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_addresses() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        {
+            let instance = Instance::from_path(&db_path).await?;
+            instance
+                .bans
+                .ban_address(parse_address("10.0.0.7/24")?, "spam", "admin", None)
+                .await?;
+            instance
+                .bans
+                .ban_address(parse_address("2001:db8::1")?, "spam", "admin", Some(-1.))
+                .await?;
+        }
+
+        let instance = Instance::from_path(&db_path).await?;
+        assert!(instance.bans.check_address(ip("10.0.0.5")).is_err());
+        assert!(instance.bans.check_address(ip("::ffff:10.0.0.5")).is_err());
+        instance.bans.check_address(ip("10.0.1.5"))?;
+        instance.bans.check_address(ip("2001:db8::1"))?;
+        assert!(parse_address("10.0.0.300").is_err());
+
+        assert!(!instance.bans.unban_address(parse_address("10.0.0.5")?).await?);
+        assert!(instance.bans.unban_address(parse_address("10.0.0.0/24")?).await?);
+        instance.bans.check_address(ip("10.0.0.5"))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_address_ban_kicks() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (id_1, _, mut state_recv) = instance.register("test123".to_string(), "").await?;
+        let (id_2, _, _) = instance.register("test456".to_string(), "").await?;
+        instance.set_address(id_1, ip("192.168.1.10"));
+        instance.set_address(id_2, ip("192.168.2.10"));
+
+        let ban = Command::BanAddress {
+            address: "192.168.1.0/24".to_string(),
+            reason: None,
+            duration: Some(60.),
+        };
+        instance.execute("console", &ban).await?;
+        assert!(!instance.players.cache.contains_key(&id_1));
+        assert!(instance.players.cache.contains_key(&id_2));
+        assert!(matches!(
+            state_recv.recv().await,
+            Some(Game::Error {
+                code: ErrorCode::Kicked,
+                ..
+            })
+        ));
+        let list = instance.execute("console", &Command::ListBans).await?;
+        assert!(list.starts_with("192.168.1.0/24: Banned by a moderator (by console, "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_05_nickname_case() -> anyhow::Result<()> {
+        for db_path in [None, Some(get_random_db_path())] {
+            let mut instance = match db_path {
+                Some(db_path) => Instance::from_path(&db_path).await?,
+                None => Instance::in_memory().await?,
+            };
+            let hash = hash_password("password123".to_string()).await?;
+            let (id, _, mut state_recv) = instance.register("alice".to_string(), &hash).await?;
+
+            let ban = Command::Ban {
+                nickname: "Alice".to_string(),
+                reason: None,
+                duration: None,
+            };
+            instance.execute("console", &ban).await?;
+            assert!(!instance.players.cache.contains_key(&id));
+            assert!(matches!(
+                state_recv.recv().await,
+                Some(Game::Error {
+                    code: ErrorCode::Kicked,
+                    ..
+                })
+            ));
+            for nickname in ["alice", "ALICE"] {
+                assert!(matches!(
+                    instance.authenticate(nickname.to_string(), "password123").await,
+                    Err(Error::Banned(_))
+                ));
+            }
+
+            let unban = Command::Unban {
+                nickname: "aLiCe".to_string(),
+            };
+            assert_eq!("Unbanned aLiCe", instance.execute("console", &unban).await?);
+            instance.authenticate("alice".to_string(), "password123").await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn case_04_console_commands() -> anyhow::Result<()> {
+        assert_eq!(
+            Command::Ban {
+                nickname: "test123".to_string(),
+                reason: Some("no cheating".to_string()),
+                duration: Some(7. * 86400.),
+            },
+            Command::from_str("ban test123 7d no cheating")?
+        );
+        assert_eq!(
+            Command::BanAddress {
+                address: "10.0.0.0/8".to_string(),
+                reason: None,
+                duration: None,
+            },
+            Command::from_str("ban-ip 10.0.0.0/8")?
+        );
+        assert_eq!(Command::ListBans, Command::from_str(" bans ")?);
+        assert_eq!(
+            Command::SetRole {
+                nickname: "test123".to_string(),
+                role: Role::Moderator,
+            },
+            Command::from_str("set-role test123 moderator")?
+        );
+        assert_eq!(
+            Command::Teleport {
+                nickname: "test123".to_string(),
+                coords: [1., -2., 3.5],
+            },
+            Command::from_str("teleport test123 1 -2 3.5")?
+        );
+        assert_eq!(
+            Command::Latency {
+                nickname: "test123".to_string(),
+            },
+            Command::from_str("latency test123")?
+        );
+        assert!(matches!(
+            Command::from_str("teleport test123 1 2"),
+            Err(Error::InvalidCommand(_))
+        ));
+        assert!(matches!(Command::from_str("unban"), Err(Error::InvalidCommand(_))));
+        assert!(matches!(
+            Command::from_str("set-role test123 god"),
+            Err(Error::InvalidRole(_))
+        ));
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_17_limits {
+    use std::time::{Duration, Instant};
+
+    use crate::limits::{FloodPolicy, RateLimiter, RateLimits, Verdict, Violations};
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn limits(policy: FloodPolicy) -> RateLimits {
+        RateLimits {
+            messages_per_second: 10f64,
+            message_burst: 5f64,
+            bytes_per_second: 1000f64,
+            byte_burst: 1000f64,
+            max_message_size: 1000,
+            policy,
+        }
+    }
+
+    #[test]
+    fn case_01_drop() {
+        let mut limiter = RateLimiter::new(&limits(FloodPolicy::Drop));
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(matches!(limiter.check(10, now), Verdict::Accept));
+        }
+        assert!(matches!(limiter.check(10, now), Verdict::Drop));
+        assert!(matches!(limiter.check(10, now), Verdict::Drop));
+
+        // Dropped messages are not charged, one message per 100ms.
+        let later = now + Duration::from_millis(100);
+        assert!(matches!(limiter.check(10, later), Verdict::Accept));
+        assert!(matches!(limiter.check(10, later), Verdict::Drop));
+
+        // Bytes are limited on their own.
+        let much_later = now + Duration::from_secs(10);
+        assert!(matches!(limiter.check(900, much_later), Verdict::Accept));
+        assert!(matches!(limiter.check(200, much_later), Verdict::Drop));
+    }
+
+    #[test]
+    fn case_02_throttle() {
+        let mut limiter = RateLimiter::new(&limits(FloodPolicy::Throttle));
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(matches!(limiter.check(10, now), Verdict::Accept));
+        }
+        let Verdict::Throttle(first) = limiter.check(10, now) else {
+            panic!("Not throttled");
+        };
+        let Verdict::Throttle(second) = limiter.check(10, now) else {
+            panic!("Not throttled");
+        };
+        assert!((first.as_secs_f64() - 0.1).abs() < 1e-6);
+        assert!((second.as_secs_f64() - 0.2).abs() < 1e-6);
+
+        // The debt of the throttled messages is paid before the next one.
+        assert!(matches!(limiter.check(10, now + second), Verdict::Throttle(_)));
+        let later = now + Duration::from_millis(400);
+        assert!(matches!(limiter.check(10, later), Verdict::Accept));
+    }
+
+    #[test]
+    fn case_03_disconnect_and_counters() {
+        let mut limiter = RateLimiter::new(&limits(FloodPolicy::Disconnect));
+        let violations = Violations::default();
+        let now = Instant::now();
+        for _ in 0..7 {
+            violations.record(limiter.check(10, now));
+        }
+        violations.record(Verdict::Drop);
+        violations.record_oversized();
+        assert_eq!(2, violations.disconnected());
+        assert_eq!(1, violations.dropped());
+        assert_eq!(0, violations.throttled());
+        assert_eq!(1, violations.oversized());
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_18_outbox {
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    use crate::{
+        instance::Instance,
+        outbox::{outbox, OutboxReceiver, OUTBOX_CAPACITY},
+        player::ENV_CHUNK_SIZE,
+        protocol::state::{BodyUpdate, EnvUpdate, ErrorCode, Game, Ping, Player},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn states(state_recv: &mut OutboxReceiver) -> Vec<Game> {
+        let mut states = vec![];
+        while let Ok(state) = state_recv.try_recv() {
+            states.push(state);
+        }
+        states
+    }
+
+    fn notice(message: &str) -> Game {
+        Game::Notice {
+            message: message.to_string(),
+        }
+    }
+
+    fn env_update(time: f64, bodies: impl Iterator<Item = BodyUpdate>) -> Game {
+        Game::EnvUpdate(EnvUpdate {
+            time,
+            bodies: bodies.collect(),
+        })
+    }
+
+    #[test]
+    fn case_01_snapshots_coalesce() {
+        let (state_send, mut state_recv) = outbox(4);
+        for seq in 0..10 {
+            assert!(state_send.push(Game::Ping(Ping { seq, server_time: 0f64 })));
+            assert!(state_send.push(Game::Player(Player {
+                coords: [seq as f64, 0f64, 0f64],
+                velocity: [0f64; 3],
+                rtt: None,
+            })));
+            assert!(state_send.push(Game::Ships(vec![])));
+        }
+        assert!(state_send.push(notice("first")));
+        assert!(!state_send.push(notice("second")));
+        assert!(state_send.is_overflowed());
+
+        let (state_send, mut state_recv_2) = outbox(4);
+        state_send.push(Game::Ping(Ping {
+            seq: 0,
+            server_time: 0f64,
+        }));
+        state_send.push(notice("between"));
+        state_send.push(Game::Ping(Ping {
+            seq: 1,
+            server_time: 0f64,
+        }));
+        let received = states(&mut state_recv_2);
+        assert_eq!(2, received.len());
+        // The latest snapshot takes the place of the queued one, ahead of the states queued after it.
+        assert!(matches!(received[0], Game::Ping(Ping { seq: 1, .. })));
+        assert!(matches!(received[1], Game::Notice { .. }));
+
+        let received = states(&mut state_recv);
+        assert_eq!(1, received.len());
+        assert!(matches!(
+            &received[0],
+            Game::Error {
+                code: ErrorCode::SlowClient,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn case_02_env_updates_merge() {
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let update = |id, coords: Option<[f64; 3]>, mass: Option<f64>| BodyUpdate {
+            id,
+            coords,
+            mass,
+            ..Default::default()
+        };
+        state_send.push(env_update(1f64, [update(1, Some([1f64; 3]), Some(1f64))].into_iter()));
+        state_send.push(env_update(
+            2f64,
+            [update(1, Some([2f64; 3]), None), update(2, None, Some(2f64))].into_iter(),
+        ));
+
+        let received = states(&mut state_recv);
+        assert_eq!(1, received.len());
+        let Game::EnvUpdate(merged) = &received[0] else {
+            panic!("Not an env update");
+        };
+        assert_eq!(2f64, merged.time);
+        assert_eq!(
+            vec![update(1, Some([2f64; 3]), Some(1f64)), update(2, None, Some(2f64))],
+            merged.bodies
+        );
+
+        // Updates never merge across another state nor beyond a chunk.
+        state_send.push(env_update(
+            3f64,
+            (0..ENV_CHUNK_SIZE as u32).map(|id| update(id, None, Some(3f64))),
+        ));
+        state_send.push(env_update(
+            4f64,
+            [update(ENV_CHUNK_SIZE as u32, None, Some(4f64))].into_iter(),
+        ));
+        state_send.push(notice("between"));
+        state_send.push(env_update(5f64, [update(0, None, Some(5f64))].into_iter()));
+        assert_eq!(4, states(&mut state_recv).len());
+    }
+
+    #[tokio::test]
+    async fn case_03_receiver_ends_with_sender() {
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        let receiving = tokio::spawn(async move {
+            let mut received = vec![];
+            while let Some(state) = state_recv.recv().await {
+                received.push(state);
+            }
+            received
+        });
+        state_send.push(notice("first"));
+        tokio::task::yield_now().await;
+        state_send.push(notice("second"));
+        drop(state_send);
+        let received = tokio::time::timeout(std::time::Duration::from_secs(TIMEOUT_DURATION), receiving)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, received.len());
+
+        let (state_send, state_recv) = outbox(OUTBOX_CAPACITY);
+        drop(state_recv);
+        assert!(!state_send.push(notice("lost")));
+
+        let (state_send, mut state_recv) = outbox(OUTBOX_CAPACITY);
+        assert!(matches!(state_recv.try_recv(), Err(TryRecvError::Empty)));
+        drop(state_send);
+        assert!(matches!(state_recv.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn case_04_slow_player_removed() -> anyhow::Result<()> {
+        let mut instance = Instance::in_memory().await?;
+        let (id, _action, mut state_recv) = instance.register("test123".to_string(), "").await?;
+        let (other_id, _other_action, mut other_recv) = instance.register("test456".to_string(), "").await?;
+
+        let player = instance.players.cache.get(&id).unwrap();
+        for _ in 0..=OUTBOX_CAPACITY {
+            player.state_send.push(notice("unread"));
+        }
+        instance.update(0.1).await;
+        assert!(!instance.players.cache.contains_key(&id));
+        assert!(instance.players.cache.contains_key(&other_id));
+        assert!(!states(&mut other_recv).is_empty());
+
+        let received = states(&mut state_recv);
+        assert_eq!(1, received.len());
+        assert!(matches!(
+            &received[0],
+            Game::Error {
+                code: ErrorCode::SlowClient,
+                ..
+            }
+        ));
+        assert!(matches!(state_recv.try_recv(), Err(TryRecvError::Disconnected)));
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_19_migrations {
+    use std::{env, fs::File, sync::Arc};
+
+    use scilib::coordinate::cartesian::Cartesian;
+    use sqlx::SqlitePool;
+    use uuid::Uuid;
+
+    use crate::{
+        cache::{BodyCache, PlayerCache},
+        error::Error,
+        instance::Instance,
+        migration::{self, MIGRATIONS},
+        sqldb::SqlDb,
+        sqlstorage::SqlStorage,
+        storage::Storage,
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn get_random_db_path() -> String {
+        format!(
+            "{}space_build_tests_{}.db",
+            env::temp_dir().to_str().unwrap(),
+            Uuid::new_v4()
+        )
+    }
+
+    async fn open(db_path: &str) -> SqlDb {
+        SqlDb::new(SqlitePool::connect(db_path).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn case_01_numbered_and_idempotent() -> anyhow::Result<()> {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(i as u32 + 1, migration.version);
+        }
+
+        let db_path = get_random_db_path();
+        File::create(&db_path)?;
+        let mut db = open(&db_path).await;
+        assert_eq!(0, db.schema_version().await?);
+        assert_eq!(migration::schema_version(), migration::migrate(&mut db).await?);
+        assert_eq!(migration::schema_version(), db.schema_version().await?);
+        assert_eq!(migration::schema_version(), migration::migrate(&mut db).await?);
+
+        drop(db);
+        let instance = Instance::from_path(&db_path).await?;
+        assert!(instance.borrow_bans().list().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_database_before_migrations() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        File::create(&db_path)?;
+        let pool = SqlitePool::connect(&db_path).await?;
+        // Tables of the first release, before ship velocity and body mass.
+        for sql_str in [
+            "CREATE TABLE Body (id INTEGER PRIMARY KEY AUTOINCREMENT, type INTEGER, coord_x REAL, coord_y REAL, coord_z REAL, rotating_speed REAL, gravity_center INTEGER, FOREIGN KEY (gravity_center) REFERENCES Body (id))",
+            "CREATE TABLE Player (id INTEGER PRIMARY KEY, nickname TEXT, coord_x REAL, coord_y REAL, coord_z REAL, direction_x REAL, direction_y REAL, direction_z REAL, current_system INTEGER)",
+            "INSERT INTO Body VALUES (1, 1, 2, 4, 6, 0.5, 1)",
+            "INSERT INTO Body VALUES (2, 2, -198, 404, 406, 0.01, 1)",
+            "INSERT INTO Player VALUES (1, 'test123', 3, 5, 7, 0, 0, 1, 1)",
+        ] {
+            sqlx::query(sql_str).execute(&pool).await?;
+        }
+        pool.close().await;
+
+        let storage: Arc<dyn Storage> = Arc::new(SqlStorage::open(&db_path).await?);
+        let mut bodies = BodyCache::new(storage.clone());
+        let body = bodies.load_body(1).await?;
+        assert_eq!(Cartesian::from(2, 4, 6), body.coords);
+        assert_eq!(0.5, body.rotating_speed);
+        assert_eq!(0f64, body.mass);
+
+        // The planet keeps its distance to the star, on a circular orbit starting where it was.
+        let planet = bodies.load_body(2).await?;
+        assert_eq!(600f64, planet.orbit.semi_major_axis);
+        assert_eq!(0f64, planet.orbit.eccentricity);
+        assert!((planet.local_position(0f64) - Cartesian::from(-200, 400, 400)).norm() < 1e-6);
+        assert!((planet.local_position(100f64).norm() - 600f64).abs() < 1e-6);
+
+        let mut players = PlayerCache::new(storage);
+        assert_eq!(None, players.password_hash("test123").await?);
+        let (id, _, _) = players.load("test123".to_string()).await?;
+        let player = players.get_player(id);
+        assert_eq!(Cartesian::from(3, 5, 7), player.coords);
+        assert_eq!(Cartesian::default(), player.velocity);
+        assert_eq!(1, player.current_system);
+        players.save(id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_newer_database_refused() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        drop(Instance::from_path(&db_path).await?);
+        let pool = SqlitePool::connect(&db_path).await?;
+        sqlx::query("UPDATE schema_version SET version=version+1")
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+
+        let newer = migration::schema_version() + 1;
+        assert!(matches!(
+            Instance::from_path(&db_path).await,
+            Err(Error::DbSchemaTooNew(version, supported)) if version == newer && supported == newer - 1
+        ));
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_20_saves {
+    use std::{env, fs::File, sync::Arc};
+
+    use scilib::coordinate::cartesian::Cartesian;
+    use sqlx::{Row, SqlitePool};
+    use uuid::Uuid;
+
+    use crate::{
+        body::Body,
+        instance::Instance,
+        migration,
+        persistence::Persistence,
+        sqldb::{Rows, SqlDb},
+        sqlstorage::SqlStorage,
+        storage::{MemoryStorage, Snapshot, Storage},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn get_random_db_path() -> String {
+        format!(
+            "{}space_build_tests_{}.db",
+            env::temp_dir().to_str().unwrap(),
+            Uuid::new_v4()
+        )
+    }
+
+    #[tokio::test]
+    async fn case_01_only_changes() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        let mut instance = Instance::from_path(&db_path).await?;
+        let (id, _action, _state) = instance.register("test123".to_string(), "").await?;
+        // The new system is queued once generated, leaving the new player.
+        assert_eq!(1, instance.save_all().await?);
+        assert_eq!(0, instance.save_all().await?);
+
+        // Orbiting bodies move, their coordinates following from the time.
+        instance.update(1f64).await;
+        instance.bodies.sync(instance.galaxy.bodies_now());
+        assert!(instance.bodies.changes().is_empty());
+
+        instance.players.cache.get_mut(&id).unwrap().coords = Cartesian::from(1, 2, 3);
+        assert_eq!(1, instance.save_all().await?);
+        assert_eq!(0, instance.save_all().await?);
+
+        let pool = SqlitePool::connect(&db_path).await?;
+        let journal_mode: String = sqlx::query("PRAGMA journal_mode").fetch_one(&pool).await?.get(0);
+        assert_eq!("wal", journal_mode);
+        let coord_y: f64 = sqlx::query("SELECT coord_y FROM Player WHERE id=?")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?
+            .get(0);
+        assert_eq!(2f64, coord_y);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_all_or_nothing() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        File::create(&db_path)?;
+        let mut db = SqlDb::new(SqlitePool::connect(&db_path).await?);
+        migration::migrate(&mut db).await?;
+
+        let player = |id: u32| vec![id.into(), format!("test{}", id).into()];
+        let result = db
+            .write(&[
+                Rows {
+                    table_name: "Player",
+                    columns: &["id", "nickname"],
+                    values: vec![player(1), player(2)],
+                    upserts: &["nickname"],
+                },
+                Rows {
+                    table_name: "Nowhere",
+                    columns: &["id", "nickname"],
+                    values: vec![player(3)],
+                    upserts: &[],
+                },
+            ])
+            .await;
+        assert!(result.is_err());
+        assert!(db.select_all_from("Player").await?.is_empty());
+
+        db.write(&[Rows {
+            table_name: "Player",
+            columns: &["id", "nickname"],
+            values: vec![player(1), player(2)],
+            upserts: &["nickname"],
+        }])
+        .await?;
+        assert_eq!(2, db.select_all_from("Player").await?.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_written_by_the_persistence_task() -> anyhow::Result<()> {
+        let db_path = get_random_db_path();
+        let mut instance = Instance::from_path(&db_path).await?;
+        let (id, _action, _state) = instance.register("test123".to_string(), "").await?;
+        assert_eq!(1, instance.save());
+        assert_eq!(0, instance.save_all().await?);
+        let pool = SqlitePool::connect(&db_path).await?;
+        let written: i64 = sqlx::query("SELECT COUNT(*) FROM Body").fetch_one(&pool).await?.get(0);
+        assert_eq!(instance.bodies.cache.len(), written as usize);
+
+        // Unloaded with its system, the player is written before being loaded again.
+        instance.players.cache.get_mut(&id).unwrap().coords = Cartesian::from(1, 2, 3);
+        instance.leave(id).await;
+        assert!(instance.bodies.cache.is_empty());
+        let (id, _action, _state) = instance.authenticate_verified("test123".to_string()).await?;
+        assert_eq!(
+            Cartesian::from(1, 2, 3),
+            instance.players.cache.get(&id).unwrap().coords
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_04_loaded_before_written() -> anyhow::Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut instance = Instance::with_storage(storage.clone()).await?;
+        let (id, _action, _state) = instance.register("test123".to_string(), "").await?;
+        instance.players.cache.get_mut(&id).unwrap().coords = Cartesian::from(1, 2, 3);
+        instance.leave(id).await;
+
+        // Nothing yielded to the writer yet, the player and its system are loaded as they were queued.
+        assert_eq!(
+            Cartesian::default(),
+            storage.find_player("test123").await?.unwrap().state.coords
+        );
+        assert!(storage.load_body(1).await?.is_none());
+        let (id, _action, _state) = instance.authenticate_verified("test123".to_string()).await?;
+        assert_eq!(
+            Cartesian::from(1, 2, 3),
+            instance.players.cache.get(&id).unwrap().coords
+        );
+        assert!(instance.bodies.cache.contains_key(&1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_05_refused_snapshot_dropped() -> anyhow::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(SqlStorage::open(&get_random_db_path()).await?);
+        let persistence = Persistence::spawn(storage.clone());
+        let queued = persistence.queued(storage.clone());
+        let snapshot = |id, gravity_center| Snapshot {
+            bodies: vec![Body {
+                id,
+                gravity_center,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        persistence.write(snapshot(1, 1));
+        // Orbits a body that doesn't exist, which the database refuses every time.
+        persistence.write(snapshot(2, 42));
+        persistence.write(snapshot(3, 1));
+        assert!(persistence.flush().await.is_err());
+        assert!(storage.load_body(1).await?.is_some());
+        assert!(storage.load_body(2).await?.is_none());
+        assert!(queued.load_body(2).await?.is_none());
+        assert!(storage.load_body(3).await?.is_some());
+
+        // Later saves go on being written.
+        persistence.write(snapshot(4, 3));
+        persistence.flush().await?;
+        assert_eq!(3, storage.load_body(4).await?.unwrap().gravity_center);
+        Ok(())
+    }
+}
+
+#[before_all]
+#[cfg(test)]
+mod test_21_storage {
+    use std::{env, sync::Arc};
+
+    use scilib::coordinate::cartesian::Cartesian;
+    use uuid::Uuid;
+
+    use crate::{
+        audit::AuditEntry,
+        ban::{parse_address, Ban},
+        body::Body,
+        instance::Instance,
+        protocol::{Command, Role},
+        sqlstorage::SqlStorage,
+        storage::{MemoryStorage, PlayerState, Snapshot, Storage},
+    };
+
+    pub fn before_all() {
+        spacebuild_log!(info, "test", "Timeout is {}s", TIMEOUT_DURATION);
+    }
+
+    const TIMEOUT_DURATION: u64 = 10;
+
+    fn get_random_db_path() -> String {
+        format!(
+            "{}space_build_tests_{}.db",
+            env::temp_dir().to_str().unwrap(),
+            Uuid::new_v4()
+        )
+    }
+
+    /// Every backend, each case checking they behave the same.
+    async fn backends() -> Vec<Arc<dyn Storage>> {
+        vec![
+            Arc::new(MemoryStorage::default()),
+            Arc::new(SqlStorage::open(&get_random_db_path()).await.unwrap()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn case_01_bodies() -> anyhow::Result<()> {
+        for storage in backends().await {
+            let new_body = |id, body_type| Body {
+                id,
+                body_type,
+                ..Default::default()
+            };
+            assert_eq!(0, storage.last_body_id().await?);
+            storage
+                .write(&[Snapshot {
+                    bodies: vec![new_body(1, 1), new_body(2, 2), new_body(3, 2)],
+                    ..Default::default()
+                }])
+                .await?;
+            assert_eq!(3, storage.last_body_id().await?);
+            storage
+                .write(&[Snapshot {
+                    bodies: vec![new_body(4, 4)],
+                    ..Default::default()
+                }])
+                .await?;
+            assert_eq!(4, storage.last_body_id().await?);
+            assert_eq!(2, storage.load_body(3).await?.unwrap().body_type);
+            assert!(storage.load_body(5).await?.is_none());
+
+            let mut bodies: Vec<Body> = vec![];
+            for id in 1..=3 {
+                let mut body = storage.load_body(id).await?.unwrap();
+                body.gravity_center = 1;
+                body.coords = Cartesian::from(id, 0, 0);
+                bodies.push(body);
+            }
+            storage
+                .write(&[Snapshot {
+                    bodies,
+                    ..Default::default()
+                }])
+                .await?;
+            let mut ids: Vec<u32> = storage.load_gravitings(1).await?.iter().map(|body| body.id).collect();
+            ids.sort();
+            assert_eq!(vec![1, 2, 3], ids);
+            assert_eq!(Cartesian::from(3, 0, 0), storage.load_body(3).await?.unwrap().coords);
+            assert_eq!(0, storage.load_body(4).await?.unwrap().gravity_center);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_02_players() -> anyhow::Result<()> {
+        for storage in backends().await {
+            assert_eq!(1, storage.insert_player("Test123", "hash123").await?);
+            assert_eq!(2, storage.insert_player("test_23", "hash456").await?);
+            let account = storage.find_player("test123").await?.unwrap();
+            assert_eq!(1, account.state.id);
+            assert_eq!("Test123", account.state.nickname);
+            assert_eq!(Cartesian::default(), account.state.coords);
+            assert_eq!(Some("hash123".to_string()), account.password_hash);
+            assert_eq!(Role::Player, account.role);
+            assert!(storage.find_player("test%").await?.is_none());
+            assert!(storage.find_player("test123 ").await?.is_none());
+
+            storage.set_role("TEST123", Role::Moderator).await?;
+            storage.set_password_hash("test123", "hash789").await?;
+            let state = PlayerState {
+                coords: Cartesian::from(1, 2, 3),
+                velocity: Cartesian::from(4, 5, 6),
+                current_system: 7,
+                ..account.state
+            };
+            storage
+                .write(&[Snapshot {
+                    players: vec![state.clone()],
+                    ..Default::default()
+                }])
+                .await?;
+            let account = storage.find_player("test123").await?.unwrap();
+            assert_eq!(state, account.state);
+            assert_eq!(Some("hash789".to_string()), account.password_hash);
+            assert_eq!(Role::Moderator, account.role);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_03_bans_and_audit() -> anyhow::Result<()> {
+        for storage in backends().await {
+            let ban = Ban {
+                id: 0,
+                nickname: Some("test123".to_string()),
+                address: None,
+                reason: "spam".to_string(),
+                banned_by: "admin".to_string(),
+                banned_at: 1f64,
+                expires_at: Some(2f64),
+            };
+            let address_ban = Ban {
+                nickname: None,
+                address: Some(parse_address("10.0.0.0/24")?),
+                expires_at: None,
+                ..ban.clone()
+            };
+            assert_eq!(1, storage.insert_ban(&ban).await?);
+            assert_eq!(2, storage.insert_ban(&address_ban).await?);
+            assert_eq!(3, storage.insert_ban(&ban).await?);
+            assert_eq!(3, storage.bans().await?.len());
+            let bans = storage.bans_of("test123").await?;
+            assert_eq!(vec![1, 3], bans.iter().map(|ban| ban.id).collect::<Vec<_>>());
+            assert_eq!(Some(2f64), bans[0].expires_at);
+            assert_eq!(2, storage.bans_of("Test123").await?.len());
+
+            assert_eq!(0, storage.delete_address_bans(parse_address("10.0.0.1")?).await?);
+            assert_eq!(1, storage.delete_address_bans(parse_address("10.0.0.0/24")?).await?);
+            assert_eq!(2, storage.delete_bans("TEST123").await?);
+            assert!(storage.bans().await?.is_empty());
+
+            let entry = AuditEntry {
+                time: 1f64,
+                actor: "admin".to_string(),
+                command: Command::Unban {
+                    nickname: "test123".to_string(),
+                },
+                outcome: "ok: unbanned".to_string(),
+            };
+            storage.insert_audit(&entry).await?;
+            assert_eq!(vec![entry], storage.audit_entries().await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_04_instance_in_memory() -> anyhow::Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut instance = Instance::with_storage(storage.clone()).await?;
+        let (id, _action, _state) = instance.register("test123".to_string(), "").await?;
+        let bodies = instance.bodies.cache.len();
+        assert_eq!(1, instance.save_all().await?);
+
+        instance.players.cache.get_mut(&id).unwrap().coords = Cartesian::from(1, 2, 3);
+        instance.leave(id).await;
+        assert!(instance.bodies.cache.is_empty());
+        assert!(storage.load_body(1).await?.is_some());
+        let (id, _action, _state) = instance.authenticate_verified("test123".to_string()).await?;
+        assert_eq!(
+            Cartesian::from(1, 2, 3),
+            instance.players.cache.get(&id).unwrap().coords
+        );
+        assert_eq!(bodies, instance.galaxy.bodies_now().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn case_05_clock_kept_across_restarts() -> anyhow::Result<()> {
+        for storage in backends().await {
+            assert_eq!(0f64, storage.galaxy_time().await?);
+            let mut instance = Instance::with_storage(storage.clone()).await?;
+            let _session = instance.register("test123".to_string(), "").await?;
+            instance.update(2f64).await;
+            let time = instance.galaxy.time();
+            assert!(time > 0f64);
+            instance.save_all().await?;
+            drop(instance);
+
+            let instance = Instance::with_storage(storage.clone()).await?;
+            assert_eq!(time, instance.galaxy.time());
+            // Snapshots without a time leave the clock alone.
+            storage.write(&[Snapshot::default()]).await?;
+            assert_eq!(time, storage.galaxy_time().await?);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn case_06_hierarchy_across_batches() -> anyhow::Result<()> {
+        // Moons, then planets, then their star, more than one statement apart.
+        const MOONS: u32 = 5000;
+        let body = |id, gravity_center| Body {
+            id,
+            gravity_center,
+            ..Default::default()
+        };
+        let mut bodies: Vec<Body> = (12..12 + MOONS).map(|id| body(id, 2 + id % 10)).collect();
+        bodies.extend((2..12).map(|id| body(id, 1)));
+        bodies.push(body(1, 1));
+        for storage in backends().await {
+            storage
+                .write(&[Snapshot {
+                    bodies: bodies.clone(),
+                    ..Default::default()
+                }])
+                .await?;
+            assert_eq!(11 + MOONS, storage.last_body_id().await?);
+            assert_eq!(11, storage.load_gravitings(1).await?.len());
+            assert_eq!(MOONS as usize / 10, storage.load_gravitings(2).await?.len());
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// What happens to a message over the rate limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FloodPolicy {
+    /// The message is ignored.
+    #[default]
+    Drop,
+    /// The message is processed once the limits allow it, delaying the connection meanwhile.
+    Throttle,
+    /// The client is sent `ErrorCode::RateLimited` and disconnected.
+    Disconnect,
+}
+
+/// Limits on the messages of each connection, see `Instance::set_rate_limits`.
+///
+/// Messages over `max_message_size` are refused by the websocket itself and always disconnect the client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    pub messages_per_second: f64,
+    pub message_burst: f64,
+    pub bytes_per_second: f64,
+    pub byte_burst: f64,
+    pub max_message_size: usize,
+    pub policy: FloodPolicy,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 50f64,
+            message_burst: 100f64,
+            bytes_per_second: 64f64 * 1024f64,
+            byte_burst: 256f64 * 1024f64,
+            max_message_size: 64 * 1024,
+            policy: FloodPolicy::Drop,
+        }
+    }
+}
+
+/// Violations of the rate limits since the instance started, all connections included.
+#[derive(Debug, Default)]
+pub struct Violations {
+    dropped: AtomicU64,
+    throttled: AtomicU64,
+    disconnected: AtomicU64,
+    oversized: AtomicU64,
+}
+
+impl Violations {
+    /// Messages ignored, including the ones a full action queue could not take.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Clients disconnected for flooding, oversized messages excluded.
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    pub fn oversized(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, verdict: Verdict) {
+        match verdict {
+            Verdict::Accept => {}
+            Verdict::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Verdict::Throttle(_) => {
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+            }
+            Verdict::Disconnect => {
+                self.disconnected.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn record_oversized(&self) {
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` tokens are available, zero if they are already.
+    fn wait_for(&self, amount: f64) -> Duration {
+        if self.tokens >= amount {
+            Duration::ZERO
+        } else if self.rate > 0f64 {
+            Duration::from_secs_f64((amount - self.tokens) / self.rate)
+        } else {
+            Duration::MAX
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Verdict {
+    Accept,
+    Drop,
+    /// The message is accepted after waiting for the duration.
+    Throttle(Duration),
+    Disconnect,
+}
+
+/// Token buckets of a connection, one for messages and one for bytes.
+pub(crate) struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    policy: FloodPolicy,
+    pub(crate) max_message_size: usize,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: &RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: TokenBucket::new(limits.messages_per_second, limits.message_burst, now),
+            bytes: TokenBucket::new(limits.bytes_per_second, limits.byte_burst, now),
+            policy: limits.policy,
+            max_message_size: limits.max_message_size,
+        }
+    }
+
+    /// Charges a message of `size` bytes received at `now`.
+    ///
+    /// Throttled messages are charged in advance, the buckets going into debt until the wait is over.
+    pub(crate) fn check(&mut self, size: usize, now: Instant) -> Verdict {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        let size = size as f64;
+        let wait = self.messages.wait_for(1f64).max(self.bytes.wait_for(size));
+        if wait.is_zero() || self.policy == FloodPolicy::Throttle {
+            self.messages.tokens -= 1f64;
+            self.bytes.tokens -= size;
+        }
+        match (wait.is_zero(), self.policy) {
+            (true, _) => Verdict::Accept,
+            (false, FloodPolicy::Drop) => Verdict::Drop,
+            (false, FloodPolicy::Throttle) => Verdict::Throttle(wait),
+            (false, FloodPolicy::Disconnect) => Verdict::Disconnect,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::error::Error;
+use crate::orbit::Orbit;
+use crate::sqldb::{SqlDb, SqlValue};
+use crate::{spacebuild_log, Result};
+use scilib::coordinate::cartesian::Cartesian;
+use sqlx::sqlite::SqliteRow;
+use sqlx::Row;
+
+/// Change of a migration.
+pub enum Step {
+    Sql(&'static str),
+    /// `ALTER TABLE {0} ADD COLUMN {1}`, skipped if the table already has the column as databases predating the
+    /// migrations may.
+    AddColumn(&'static str, &'static str),
+    /// Runs `update` with the values `values` computes from each row of `select`, for what SQL can't compute.
+    Update {
+        select: &'static str,
+        update: &'static str,
+        values: fn(&SqliteRow) -> sqlx::Result<Vec<SqlValue>>,
+    },
+}
+
+/// Bodies without orbit and their positions relative to their gravity centers.
+const ORBITING_BODIES: &str = "SELECT body.id AS id, body.coord_x - center.coord_x AS x, \
+                               body.coord_y - center.coord_y AS y, body.coord_z - center.coord_z AS z \
+                               FROM Body body JOIN Body center ON center.id = body.gravity_center \
+                               WHERE body.id <> body.gravity_center AND body.semi_major_axis = 0";
+
+/// Circular orbit of a body through its position at the time of the migration, the clock starting then.
+fn circular_orbit(row: &SqliteRow) -> sqlx::Result<Vec<SqlValue>> {
+    let coord = |name| row.try_get::<Option<f64>, _>(name).map(Option::unwrap_or_default);
+    let local = Cartesian::from(coord("x")?, coord("y")?, coord("z")?);
+    let orbit = Orbit::circular_through(local);
+    Ok(vec![
+        orbit.semi_major_axis.into(),
+        orbit.inclination.into(),
+        orbit.ascending_node.into(),
+        orbit.mean_anomaly_epoch.into(),
+        row.try_get::<u32, _>("id")?.into(),
+    ])
+}
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+/// Every change of the schema since the first release, in order, a database being at the version of the last
+/// migration applied to it.
+///
+/// Migrations are never edited once released: a change of the schema is a new migration at the end of the list.
+/// Databases predating the migrations are at version 0, the steps being written to skip what they already have.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "bodies and players",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS Body (id INTEGER PRIMARY KEY AUTOINCREMENT, type INTEGER, coord_x REAL, \
+                 coord_y REAL, coord_z REAL, rotating_speed REAL, gravity_center INTEGER, \
+                 FOREIGN KEY (gravity_center) REFERENCES Body (id))",
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS id_index_Body ON Body (id)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS gravity_center_index_Body ON Body (gravity_center)"),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS Player (id INTEGER PRIMARY KEY, nickname TEXT, coord_x REAL, \
+                 coord_y REAL, coord_z REAL, direction_x REAL, direction_y REAL, direction_z REAL, \
+                 current_system INTEGER)",
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS id_index_Player ON Player (id)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS nickname_index_Player ON Player (nickname)"),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "ship velocity",
+        steps: &[
+            Step::AddColumn("Player", "velocity_x REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Player", "velocity_y REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Player", "velocity_z REAL NOT NULL DEFAULT 0"),
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "body mass",
+        steps: &[Step::AddColumn("Body", "mass REAL NOT NULL DEFAULT 0")],
+    },
+    Migration {
+        version: 4,
+        description: "body orbits",
+        steps: &[
+            Step::AddColumn("Body", "semi_major_axis REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Body", "eccentricity REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Body", "inclination REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Body", "ascending_node REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Body", "arg_periapsis REAL NOT NULL DEFAULT 0"),
+            Step::AddColumn("Body", "mean_anomaly_epoch REAL NOT NULL DEFAULT 0"),
+            // Orbiting bodies would otherwise collapse onto their gravity centers.
+            Step::Update {
+                select: ORBITING_BODIES,
+                update: "UPDATE Body SET semi_major_axis=?, inclination=?, ascending_node=?, mean_anomaly_epoch=? \
+                         WHERE id=?",
+                values: circular_orbit,
+            },
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "player passwords",
+        steps: &[Step::AddColumn("Player", "password_hash TEXT")],
+    },
+    Migration {
+        version: 6,
+        description: "roles, bans and audit log",
+        steps: &[
+            Step::AddColumn("Player", "role TEXT"),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS Ban (id INTEGER PRIMARY KEY, nickname TEXT, reason TEXT, \
+                 banned_by TEXT, banned_at REAL)",
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS nickname_index_Ban ON Ban (nickname)"),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS Audit (id INTEGER PRIMARY KEY, time REAL, actor TEXT, command TEXT, \
+                 outcome TEXT)",
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS actor_index_Audit ON Audit (actor)"),
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "address bans and expiry",
+        steps: &[
+            Step::AddColumn("Ban", "address TEXT"),
+            Step::AddColumn("Ban", "expires_at REAL"),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "galaxy clock",
+        steps: &[Step::Sql(
+            "CREATE TABLE IF NOT EXISTS Galaxy (id INTEGER PRIMARY KEY, time REAL NOT NULL)",
+        )],
+    },
+];
+
+/// Version of the schema this build reads and writes.
+pub fn schema_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Brings the database to the schema of this build, refusing one written by a newer build.
+///
+/// Each migration is applied in its own transaction along with the new version, an interrupted upgrade resuming
+/// from the last migration completed.
+pub async fn migrate(db: &mut SqlDb) -> Result<u32> {
+    let current = db.schema_version().await?;
+    if current > schema_version() {
+        return Err(Error::DbSchemaTooNew(current, schema_version()));
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        spacebuild_log!(
+            info,
+            "db",
+            "Migrating to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        db.apply(migration).await?;
+    }
+    Ok(schema_version())
+}
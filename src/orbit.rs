@@ -0,0 +1,85 @@
+use scilib::coordinate::cartesian::Cartesian;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{PI, TAU};
+
+const KEPLER_MAX_ITERATIONS: usize = 32;
+const KEPLER_TOLERANCE: f64 = 1e-12;
+
+/// Keplerian orbital elements of a body around its gravity center.
+///
+/// The reference plane of the galaxy is the xz plane, y pointing "north".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Orbit {
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub ascending_node: f64,
+    pub arg_periapsis: f64,
+    pub mean_anomaly_epoch: f64,
+}
+
+impl Orbit {
+    pub fn eccentric_anomaly(&self, mean_anomaly: f64) -> f64 {
+        let mean_anomaly = (mean_anomaly + PI).rem_euclid(TAU) - PI;
+        let e = self.eccentricity;
+        let mut eccentric_anomaly = if e < 0.8 {
+            mean_anomaly
+        } else {
+            PI.copysign(mean_anomaly)
+        };
+
+        for _ in 0..KEPLER_MAX_ITERATIONS {
+            let step =
+                (eccentric_anomaly - e * eccentric_anomaly.sin() - mean_anomaly) / (1f64 - e * eccentric_anomaly.cos());
+            eccentric_anomaly -= step;
+            if step.abs() < KEPLER_TOLERANCE {
+                break;
+            }
+        }
+        eccentric_anomaly
+    }
+
+    /// Upper bound of the orbital speed, reached at periapsis.
+    pub fn max_speed(&self, mean_motion: f64) -> f64 {
+        let e = self.eccentricity.min(1f64 - f64::EPSILON);
+        (self.semi_major_axis * mean_motion * ((1f64 + e) / (1f64 - e)).sqrt()).abs()
+    }
+
+    /// Circular orbit going through `local`, a position relative to the gravity center, at its epoch.
+    pub fn circular_through(local: Cartesian) -> Self {
+        let radius = local.norm();
+        if radius == 0f64 {
+            return Self::default();
+        }
+        // At a mean anomaly of a quarter turn, the body is at the top of the orbit: along the line of nodes rotated
+        // by the inclination.
+        Self {
+            semi_major_axis: radius,
+            eccentricity: 0f64,
+            inclination: (local.y / radius).clamp(-1f64, 1f64).asin(),
+            ascending_node: (-local.x).atan2(local.z),
+            arg_periapsis: 0f64,
+            mean_anomaly_epoch: PI / 2f64,
+        }
+    }
+
+    /// Position relative to the gravity center for the given mean anomaly.
+    pub fn position(&self, mean_anomaly: f64) -> Cartesian {
+        let eccentric_anomaly = self.eccentric_anomaly(mean_anomaly);
+        let a = self.semi_major_axis;
+        let e = self.eccentricity;
+
+        let x_orb = a * (eccentric_anomaly.cos() - e);
+        let y_orb = a * (1f64 - e * e).sqrt() * eccentric_anomaly.sin();
+
+        let (sin_w, cos_w) = self.arg_periapsis.sin_cos();
+        let (sin_o, cos_o) = self.ascending_node.sin_cos();
+        let (sin_i, cos_i) = self.inclination.sin_cos();
+
+        let x = (cos_o * cos_w - sin_o * sin_w * cos_i) * x_orb + (-cos_o * sin_w - sin_o * cos_w * cos_i) * y_orb;
+        let y = (sin_o * cos_w + cos_o * sin_w * cos_i) * x_orb + (-sin_o * sin_w + cos_o * cos_w * cos_i) * y_orb;
+        let z = (sin_w * sin_i) * x_orb + (cos_w * sin_i) * y_orb;
+
+        Cartesian { x, y: z, z: y }
+    }
+}
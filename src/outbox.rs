@@ -0,0 +1,175 @@
+use crate::player::ENV_CHUNK_SIZE;
+use crate::protocol::state::{EnvUpdate, ErrorCode, Game};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::Notify;
+
+/// Game states a client can be behind on before it is disconnected, once coalesced.
+pub const OUTBOX_CAPACITY: usize = 1024;
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Game>,
+    overflowed: bool,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    capacity: usize,
+}
+
+/// Bounded queue of the game states of a client, filled by the instance and emptied by the service.
+///
+/// Pushing never waits: snapshots (player state, ships, pings) replace the unsent one of the same kind and
+/// consecutive env updates are merged. If the queue is full nonetheless, the unsent states are replaced by a
+/// `ErrorCode::SlowClient` error and the outbox stops accepting states, for the instance to remove the client.
+pub fn outbox(capacity: usize) -> (OutboxSender, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue::default()),
+        notify: Notify::new(),
+        capacity,
+    });
+    (
+        OutboxSender {
+            shared: Arc::clone(&shared),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+/// Merges `update` into `last` if the result stays within a chunk.
+fn merge_env_update(last: &mut EnvUpdate, update: &mut EnvUpdate) -> bool {
+    let added = update
+        .bodies
+        .iter()
+        .filter(|body| last.bodies.iter().all(|known| known.id != body.id))
+        .count();
+    if last.bodies.len() + added > ENV_CHUNK_SIZE {
+        return false;
+    }
+    for body in update.bodies.drain(..) {
+        match last.bodies.iter_mut().find(|known| known.id == body.id) {
+            Some(known) => known.merge(body),
+            None => last.bodies.push(body),
+        }
+    }
+    last.time = update.time;
+    true
+}
+
+/// Folds `game` into the queued states, giving it back if it has to be queued.
+fn coalesce(messages: &mut VecDeque<Game>, mut game: Game) -> Option<Game> {
+    let same_kind: fn(&Game) -> bool = match &mut game {
+        Game::Player(_) => |queued| matches!(queued, Game::Player(_)),
+        Game::Ships(_) => |queued| matches!(queued, Game::Ships(_)),
+        Game::Ping(_) => |queued| matches!(queued, Game::Ping(_)),
+        // Only with the last state, the updates of a body leaving and entering again staying in order.
+        Game::EnvUpdate(update) => {
+            if let Some(Game::EnvUpdate(last)) = messages.back_mut() {
+                if merge_env_update(last, update) {
+                    return None;
+                }
+            }
+            return Some(game);
+        }
+        _ => return Some(game),
+    };
+    match messages.iter_mut().find(|queued| same_kind(queued)) {
+        Some(queued) => {
+            *queued = game;
+            None
+        }
+        None => Some(game),
+    }
+}
+
+pub struct OutboxSender {
+    shared: Arc<Shared>,
+}
+
+impl OutboxSender {
+    /// Queues a state for the client, returning false if it is gone or too slow to receive it.
+    pub fn push(&self, game: Game) -> bool {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.receiver_dropped || queue.overflowed {
+            return false;
+        }
+        let Some(game) = coalesce(&mut queue.messages, game) else {
+            return true;
+        };
+        if queue.messages.len() < self.shared.capacity {
+            queue.messages.push_back(game);
+            drop(queue);
+            self.shared.notify.notify_one();
+            return true;
+        }
+
+        queue.overflowed = true;
+        queue.messages.clear();
+        queue.messages.push_back(Game::Error {
+            code: ErrorCode::SlowClient,
+            message: "Too slow to receive the game states".to_string(),
+        });
+        drop(queue);
+        self.shared.notify.notify_one();
+        false
+    }
+
+    pub fn is_overflowed(&self) -> bool {
+        self.shared.queue.lock().unwrap().overflowed
+    }
+
+    /// States waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for OutboxSender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().sender_dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboxReceiver {
+    /// Next state to send, `None` once the sender is dropped and every state was received.
+    ///
+    /// Cancel safe: a state is only taken from the queue when it is returned.
+    pub async fn recv(&mut self) -> Option<Game> {
+        loop {
+            match self.try_recv() {
+                Ok(game) => return Some(game),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => self.shared.notify.notified().await,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<Game, TryRecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        match queue.messages.pop_front() {
+            Some(game) => Ok(game),
+            None if queue.sender_dropped => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().receiver_dropped = true;
+    }
+}
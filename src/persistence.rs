@@ -0,0 +1,309 @@
+use crate::audit::AuditEntry;
+use crate::ban::Ban;
+use crate::body::Body;
+use crate::error::Error;
+use crate::protocol::Role;
+use crate::storage::{Account, PlayerState, Snapshot, Storage};
+use crate::{spacebuild_log, Result};
+use futures::future::BoxFuture;
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+enum Job {
+    Write(u64, Snapshot),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Latest queued states not written yet, each with the number of the snapshot holding it.
+#[derive(Default)]
+struct Unwritten {
+    queued: u64,
+    bodies: HashMap<u32, (u64, Body)>,
+    players: HashMap<u32, (u64, PlayerState)>,
+}
+
+impl Unwritten {
+    fn queue(&mut self, snapshot: &Snapshot) -> u64 {
+        self.queued += 1;
+        for body in &snapshot.bodies {
+            self.bodies.insert(body.id, (self.queued, body.clone()));
+        }
+        for state in &snapshot.players {
+            self.players.insert(state.id, (self.queued, state.clone()));
+        }
+        self.queued
+    }
+
+    /// Forgets the states written with the snapshots up to `written`.
+    fn written(&mut self, written: u64) {
+        self.bodies.retain(|_, (queued, _)| *queued > written);
+        self.players.retain(|_, (queued, _)| *queued > written);
+    }
+}
+
+/// Handle on the task writing the snapshots to the storage, in the order they are queued.
+///
+/// Queueing never waits, so that the tick does not wait on the disk. Snapshots that fail to be written are kept
+/// and written again along with the next one, the later states overwriting the earlier ones, except for the ones
+/// the storage refuses for their data, which are dropped.
+pub(crate) struct Persistence {
+    send: mpsc::UnboundedSender<Job>,
+    unwritten: Arc<Mutex<Unwritten>>,
+}
+
+impl Persistence {
+    /// Spawns the writer, which stops once the handle is dropped and every snapshot was written.
+    pub(crate) fn spawn(storage: Arc<dyn Storage>) -> Self {
+        let (send, recv) = mpsc::unbounded_channel();
+        let unwritten = Arc::new(Mutex::new(Unwritten::default()));
+        tokio::spawn(Self::run(storage, recv, unwritten.clone()));
+        Self { send, unwritten }
+    }
+
+    /// `storage` as it is once the queued snapshots are written, to load again what was unloaded without waiting
+    /// for the writer.
+    pub(crate) fn queued(&self, storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
+        Arc::new(QueuedStorage {
+            storage,
+            unwritten: self.unwritten.clone(),
+        })
+    }
+
+    pub(crate) fn write(&self, snapshot: Snapshot) {
+        if snapshot.is_empty() {
+            return;
+        }
+        let queued = self.unwritten.lock().unwrap().queue(&snapshot);
+        if self.send.send(Job::Write(queued, snapshot)).is_err() {
+            spacebuild_log!(error, "persistence", "Writer is gone, changes are lost");
+        }
+    }
+
+    /// Waits for the snapshots queued so far to be written, failing if any could not be.
+    pub(crate) async fn flush(&self) -> Result<()> {
+        let (ack_send, ack_recv) = oneshot::channel();
+        self.send
+            .send(Job::Flush(ack_send))
+            .map_err(|_| Error::DbWriteFailed("writer is gone".to_string()))?;
+        ack_recv
+            .await
+            .map_err(|_| Error::DbWriteFailed("writer is gone".to_string()))?
+    }
+
+    async fn run(storage: Arc<dyn Storage>, mut recv: mpsc::UnboundedReceiver<Job>, states: Arc<Mutex<Unwritten>>) {
+        let mut writer = Writer {
+            storage,
+            states,
+            unwritten: vec![],
+            last: 0,
+            dropped: 0,
+        };
+        while let Some(job) = recv.recv().await {
+            match job {
+                Job::Write(number, snapshot) => {
+                    writer.unwritten.push(snapshot);
+                    writer.last = number;
+                    let _ = writer.write().await;
+                }
+                Job::Flush(ack_send) => {
+                    let result = if writer.unwritten.is_empty() {
+                        Ok(())
+                    } else {
+                        writer.write().await
+                    };
+                    let result = match std::mem::take(&mut writer.dropped) {
+                        0 => result,
+                        dropped => Err(Error::DbWriteFailed(format!(
+                            "{} snapshots refused and dropped",
+                            dropped
+                        ))),
+                    };
+                    let _ = ack_send.send(result);
+                }
+            }
+        }
+    }
+}
+
+/// State of the writer task.
+struct Writer {
+    storage: Arc<dyn Storage>,
+    states: Arc<Mutex<Unwritten>>,
+    unwritten: Vec<Snapshot>,
+    /// Number of the latest unwritten snapshot, the ones before it being numbered in sequence.
+    last: u64,
+    /// Snapshots dropped since the last flush.
+    dropped: usize,
+}
+
+impl Writer {
+    /// Writes the unwritten snapshots all at once, keeping them if it fails.
+    async fn write(&mut self) -> Result<()> {
+        match self.storage.write(&self.unwritten).await {
+            Ok(()) => {
+                spacebuild_log!(
+                    debug,
+                    "persistence",
+                    "Wrote {} bodies and players",
+                    self.unwritten.iter().map(Snapshot::len).sum::<usize>()
+                );
+                self.states.lock().unwrap().written(self.last);
+                self.unwritten.clear();
+                Ok(())
+            }
+            Err(err) if err.is_permanent() => self.write_each().await,
+            Err(err) => {
+                spacebuild_log!(error, "persistence", "Could not write changes, retrying later: {}", err);
+                Err(Error::DbWriteFailed(err.to_string()))
+            }
+        }
+    }
+
+    /// Writes the unwritten snapshots one at a time, dropping the ones the storage refuses so that they don't keep
+    /// the others from being written.
+    async fn write_each(&mut self) -> Result<()> {
+        let mut number = self.last + 1 - self.unwritten.len() as u64;
+        let mut snapshots = std::mem::take(&mut self.unwritten).into_iter();
+        while let Some(snapshot) = snapshots.next() {
+            match self.storage.write(std::slice::from_ref(&snapshot)).await {
+                Ok(()) => {}
+                Err(err) if err.is_permanent() => {
+                    spacebuild_log!(
+                        error,
+                        "persistence",
+                        "Dropping {} bodies and players the storage refuses: {}",
+                        snapshot.len(),
+                        err
+                    );
+                    self.dropped += 1;
+                }
+                Err(err) => {
+                    spacebuild_log!(error, "persistence", "Could not write changes, retrying later: {}", err);
+                    self.unwritten.push(snapshot);
+                    self.unwritten.extend(snapshots);
+                    return Err(Error::DbWriteFailed(err.to_string()));
+                }
+            }
+            self.states.lock().unwrap().written(number);
+            number += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Storage read with the queued states not written yet in place of the stored ones.
+///
+/// Snapshots are queued under the instance lock the caches read under, so nothing is queued during a read. Queued
+/// states are looked up first, one written meanwhile being stored by the time the storage is read.
+struct QueuedStorage {
+    storage: Arc<dyn Storage>,
+    unwritten: Arc<Mutex<Unwritten>>,
+}
+
+impl Storage for QueuedStorage {
+    fn last_body_id(&self) -> BoxFuture<'_, Result<u32>> {
+        let queued = self.unwritten.lock().unwrap().bodies.keys().max().copied().unwrap_or(0);
+        Box::pin(async move { Ok(self.storage.last_body_id().await?.max(queued)) })
+    }
+
+    fn load_body(&self, id: u32) -> BoxFuture<'_, Result<Option<Body>>> {
+        let queued = self
+            .unwritten
+            .lock()
+            .unwrap()
+            .bodies
+            .get(&id)
+            .map(|(_, body)| body.clone());
+        match queued {
+            Some(body) => Box::pin(async move { Ok(Some(body)) }),
+            None => self.storage.load_body(id),
+        }
+    }
+
+    fn load_gravitings(&self, id: u32) -> BoxFuture<'_, Result<Vec<Body>>> {
+        let (queued, moved): (Vec<Body>, HashSet<u32>) = {
+            let unwritten = self.unwritten.lock().unwrap();
+            let queued = unwritten
+                .bodies
+                .values()
+                .filter(|(_, body)| body.gravity_center == id)
+                .map(|(_, body)| body.clone())
+                .collect();
+            (queued, unwritten.bodies.keys().copied().collect())
+        };
+        Box::pin(async move {
+            let mut bodies: Vec<Body> = self.storage.load_gravitings(id).await?;
+            bodies.retain(|body| !moved.contains(&body.id));
+            bodies.extend(queued);
+            Ok(bodies)
+        })
+    }
+
+    fn find_player<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        let queued = self
+            .unwritten
+            .lock()
+            .unwrap()
+            .players
+            .values()
+            .find(|(_, state)| state.nickname.eq_ignore_ascii_case(nickname))
+            .map(|(_, state)| state.clone());
+        Box::pin(async move {
+            let account = self.storage.find_player(nickname).await?;
+            Ok(account.map(|account| Account {
+                state: queued.unwrap_or(account.state),
+                ..account
+            }))
+        })
+    }
+
+    fn insert_player<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<u32>> {
+        self.storage.insert_player(nickname, password_hash)
+    }
+
+    fn set_password_hash<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.storage.set_password_hash(nickname, password_hash)
+    }
+
+    fn set_role<'a>(&'a self, nickname: &'a str, role: Role) -> BoxFuture<'a, Result<()>> {
+        self.storage.set_role(nickname, role)
+    }
+
+    fn write<'a>(&'a self, snapshots: &'a [Snapshot]) -> BoxFuture<'a, Result<()>> {
+        self.storage.write(snapshots)
+    }
+
+    fn galaxy_time(&self) -> BoxFuture<'_, Result<f64>> {
+        self.storage.galaxy_time()
+    }
+
+    fn insert_ban<'a>(&'a self, ban: &'a Ban) -> BoxFuture<'a, Result<u32>> {
+        self.storage.insert_ban(ban)
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<Ban>>> {
+        self.storage.bans()
+    }
+
+    fn bans_of<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Vec<Ban>>> {
+        self.storage.bans_of(nickname)
+    }
+
+    fn delete_bans<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<u64>> {
+        self.storage.delete_bans(nickname)
+    }
+
+    fn delete_address_bans(&self, address: IpNet) -> BoxFuture<'_, Result<u64>> {
+        self.storage.delete_address_bans(address)
+    }
+
+    fn insert_audit<'a>(&'a self, entry: &'a AuditEntry) -> BoxFuture<'a, Result<()>> {
+        self.storage.insert_audit(entry)
+    }
+
+    fn audit_entries(&self) -> BoxFuture<'_, Result<Vec<AuditEntry>>> {
+        self.storage.audit_entries()
+    }
+}
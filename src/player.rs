@@ -1,25 +1,86 @@
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use scilib::coordinate::{cartesian::Cartesian, spherical::Spherical};
-use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
+use scilib::coordinate::cartesian::Cartesian;
+use tokio::sync::mpsc::{error::TryRecvError, Receiver};
 
 use crate::{
     body::Body,
-    protocol::{self, Action},
+    galaxy::TIME_SCALE,
+    history::History,
+    outbox::OutboxSender,
+    protocol::{self, state::ErrorCode, Action, Role},
     spacebuild_log,
+    view::ViewTracker,
 };
 
+pub const THRUST_ACCELERATION: f64 = 100f64;
+pub const BRAKE_DECELERATION: f64 = 150f64;
+pub const MAX_SPEED: f64 = 500f64;
+/// Maximum number of bodies in a single env message.
+pub const ENV_CHUNK_SIZE: usize = 50;
+/// Number of lag estimations averaged into `average_lag_value`.
+pub const LAG_SAMPLES: usize = 20;
+/// Seconds between two pings sent to the client.
+pub const PING_PERIOD: f64 = 1f64;
+/// Pings still waiting for a pong beyond this count are considered lost.
+pub const PENDING_PINGS: usize = 8;
+/// Weight of a new sample in the smoothed round-trip time and clock offset.
+pub const RTT_SMOOTHING: f64 = 0.125;
+
+/// Latency of a player as measured by ping/pong exchanges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Latency {
+    /// Smoothed round-trip time, in seconds.
+    pub rtt: f64,
+    /// Smoothed difference between the client and the server clocks, in seconds.
+    pub clock_offset: Option<f64>,
+}
+
+pub(crate) struct PendingPing {
+    seq: u32,
+    sent_at: Instant,
+    server_time: f64,
+}
+
+pub(crate) fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn smooth(previous: Option<f64>, sample: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + RTT_SMOOTHING * (sample - previous),
+        None => sample,
+    }
+}
+
 pub struct Player {
     pub(crate) id: u32,
     pub(crate) nickname: String,
     pub(crate) coords: Cartesian,
+    pub(crate) velocity: Cartesian,
     pub(crate) direction: Cartesian,
+    pub(crate) throttle_up: bool,
+    pub(crate) brake: bool,
     pub(crate) current_system: u32,
     pub(crate) action_recv: Receiver<Action>,
-    pub(crate) state_send: Sender<protocol::state::Game>,
+    pub(crate) state_send: OutboxSender,
     pub(crate) first_state_sent: bool,
-    pub(crate) prev_lag_values: Vec<f64>,
+    pub(crate) prev_lag_values: VecDeque<f64>,
     pub(crate) average_lag_value: f64,
+    pub(crate) latency: Option<Latency>,
+    ships_in_view: bool,
+    pub(crate) view: ViewTracker,
+    pub(crate) capabilities: HashSet<String>,
+    pub(crate) role: Role,
+    pub(crate) address: Option<IpAddr>,
+    next_ping_seq: u32,
+    since_last_ping: f64,
+    pub(crate) pending_pings: VecDeque<PendingPing>,
 }
 
 impl PartialEq for Player {
@@ -29,79 +90,244 @@ impl PartialEq for Player {
 }
 
 impl Player {
-    pub(crate) fn new(
-        nickname: String,
-        state_send: Sender<protocol::state::Game>,
-        action_recv: Receiver<Action>,
-    ) -> Self {
+    pub(crate) fn new(nickname: String, state_send: OutboxSender, action_recv: Receiver<Action>) -> Self {
         Self {
             average_lag_value: 0f64,
             id: 0,
             nickname,
             coords: Cartesian::default(),
+            velocity: Cartesian::default(),
             direction: Cartesian::default(),
+            throttle_up: false,
+            brake: false,
             current_system: 0,
             action_recv,
             state_send,
             first_state_sent: false,
-            prev_lag_values: Vec::new(),
+            prev_lag_values: VecDeque::with_capacity(LAG_SAMPLES),
+            latency: None,
+            ships_in_view: false,
+            view: ViewTracker::default(),
+            capabilities: protocol::FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            role: Role::default(),
+            address: None,
+            next_ping_seq: 0,
+            since_last_ping: PING_PERIOD,
+            pending_pings: VecDeque::with_capacity(PENDING_PINGS),
         }
     }
 
-    pub async fn update(&mut self, delta: f64, env: Vec<&Body>, history: &Vec<HashMap<u32, Body>>) {
-        let mut direction = Cartesian::default();
-        let mut throttle_up = false;
+    fn apply_physics(&mut self, delta: f64, env: &[&Body]) {
+        let mut acceleration = Cartesian::default();
+        for body in env {
+            acceleration += body.gravity_at(self.coords);
+        }
+        if self.throttle_up && self.direction.norm() > 0f64 {
+            acceleration += self.direction / self.direction.norm() * THRUST_ACCELERATION;
+        }
+        self.velocity += acceleration * delta;
+
+        let speed = self.velocity.norm();
+        if self.brake && speed > 0f64 {
+            let new_speed = (speed - BRAKE_DECELERATION * delta).max(0f64);
+            self.velocity = self.velocity / speed * new_speed;
+        }
+
+        let speed = self.velocity.norm();
+        if speed > MAX_SPEED {
+            self.velocity = self.velocity / speed * MAX_SPEED;
+        }
+
+        self.coords += self.velocity * delta;
+    }
+
+    pub fn latency(&self) -> Option<Latency> {
+        self.latency
+    }
+
+    fn send_ping(&mut self) {
+        let ping = PendingPing {
+            seq: self.next_ping_seq,
+            sent_at: Instant::now(),
+            server_time: unix_time(),
+        };
+        self.next_ping_seq = self.next_ping_seq.wrapping_add(1);
+
+        let sent = self.state_send.push(protocol::state::Game::Ping(protocol::state::Ping {
+            seq: ping.seq,
+            server_time: ping.server_time,
+        }));
+        if !sent {
+            spacebuild_log!(warn, self.nickname, "Failed to send ping");
+            return;
+        }
+
+        if self.pending_pings.len() == PENDING_PINGS {
+            self.pending_pings.pop_front();
+        }
+        self.pending_pings.push_back(ping);
+    }
+
+    fn record_pong(&mut self, pong: protocol::Pong) {
+        let Some(index) = self.pending_pings.iter().position(|ping| ping.seq == pong.seq) else {
+            spacebuild_log!(debug, self.nickname, "Pong for unknown ping {}", pong.seq);
+            return;
+        };
+        let ping = self.pending_pings.remove(index).unwrap();
+        // Older pings are answered in order: if they were not, they are lost.
+        self.pending_pings.drain(..index);
+
+        let received_at = pong.received_at.unwrap_or_else(Instant::now);
+        let rtt = received_at.duration_since(ping.sent_at).as_secs_f64();
+        let previous = self.latency;
+        let clock_offset = match pong.client_time {
+            Some(client_time) => Some(smooth(
+                previous.and_then(|latency| latency.clock_offset),
+                client_time - (ping.server_time + rtt / 2f64),
+            )),
+            None => previous.and_then(|latency| latency.clock_offset),
+        };
+        self.latency = Some(Latency {
+            rtt: smooth(previous.map(|latency| latency.rtt), rtt),
+            clock_offset,
+        });
+    }
 
+    pub fn ship(&self) -> protocol::state::Ship {
+        let direction = if self.direction.norm() > 0f64 {
+            self.direction / self.direction.norm()
+        } else {
+            Cartesian::default()
+        };
+        protocol::state::Ship {
+            id: self.id,
+            nickname: self.nickname.clone(),
+            coords: [self.coords.x, self.coords.y, self.coords.z],
+            velocity: [self.velocity.x, self.velocity.y, self.velocity.z],
+            direction: [direction.x, direction.y, direction.z],
+        }
+    }
+
+    /// Sends the ships in view, or an empty list once when the last one has left the view.
+    pub(crate) fn send_ships(&mut self, ships: Vec<protocol::state::Ship>) {
+        if !self.capabilities.contains(protocol::CAPABILITY_SHIPS) || (ships.is_empty() && !self.ships_in_view) {
+            return;
+        }
+        self.ships_in_view = !ships.is_empty();
+        if !self.state_send.push(protocol::state::Game::Ships(ships)) {
+            spacebuild_log!(warn, self.nickname, "Failed to send ships");
+        }
+    }
+
+    pub(crate) fn send_error(&mut self, code: ErrorCode, message: &str) {
+        let sent = self.state_send.push(protocol::state::Game::Error {
+            code,
+            message: message.to_string(),
+        });
+        if !sent {
+            spacebuild_log!(warn, self.nickname, "Failed to send error");
+        }
+    }
+
+    pub(crate) fn send_notice(&mut self, message: &str) {
+        let sent = self.state_send.push(protocol::state::Game::Notice {
+            message: message.to_string(),
+        });
+        if !sent {
+            spacebuild_log!(warn, self.nickname, "Failed to send notice");
+        }
+    }
+
+    fn record_lag(&mut self, lag: f64) {
+        if self.prev_lag_values.len() == LAG_SAMPLES {
+            self.prev_lag_values.pop_front();
+        }
+        self.prev_lag_values.push_back(lag);
+        self.average_lag_value = self.prev_lag_values.iter().sum::<f64>() / self.prev_lag_values.len() as f64;
+    }
+
+    /// Sends what changed around the player since the previous update.
+    fn send_env(&mut self, time: f64, env: &[&Body]) {
+        let diff = self.view.diff(env);
+        if diff.is_empty() {
+            return;
+        }
+        spacebuild_log!(
+            trace,
+            format!("{}:{}", self.id, self.nickname),
+            "Sending {} entering, {} updated and {} leaving bodies",
+            diff.entered.len(),
+            diff.updated.len(),
+            diff.left.len()
+        );
+
+        for message in diff.into_messages(time, ENV_CHUNK_SIZE) {
+            if !self.state_send.push(message) {
+                spacebuild_log!(warn, self.nickname, "Failed to send env");
+                return;
+            }
+        }
+    }
+
+    /// Applies the actions received and queues the states of the tick, without ever waiting on the client.
+    pub fn update(&mut self, delta: f64, time: f64, env: Vec<&Body>, history: &History) {
         loop {
             match self.action_recv.try_recv() {
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => unreachable!(),
                 Ok(action) => match action {
                     Action::ShipState(ship_state) => {
-                        if ship_state.throttle_up {
-                            throttle_up = ship_state.throttle_up;
-                            direction = Cartesian::from(
-                                ship_state.direction[0],
-                                ship_state.direction[1],
-                                ship_state.direction[2],
-                            );
-                            direction /= direction.norm();
+                        self.throttle_up = ship_state.throttle_up;
+                        self.brake = ship_state.brake;
+                        let direction = Cartesian::from(
+                            ship_state.direction[0],
+                            ship_state.direction[1],
+                            ship_state.direction[2],
+                        );
+                        if direction.norm() > 0f64 {
+                            self.direction = direction / direction.norm();
                         }
                     }
-                    Action::Ping((entity_id, entity_rot_angle)) => {
-                        for cache in history {
-                            let ent = cache.get(&entity_id).unwrap();
-                            let gravity_center = cache.get(&ent.gravity_center).unwrap();
-                            let local_coordinates_car = ent.coords - gravity_center.coords;
-                            let local_coordinates_sph = Spherical::from_coord(local_coordinates_car);
-                            let delta_angle = entity_rot_angle - local_coordinates_sph.theta;
-                            let lag_value = delta_angle / ent.rotating_speed;
-                            self.prev_lag_values.push(lag_value);
-                            self.average_lag_value =
-                                self.prev_lag_values.iter().sum::<f64>() / self.prev_lag_values.len() as f64;
+                    Action::Ping((entity_id, entity_angle)) => {
+                        let seen_at = env
+                            .iter()
+                            .find(|body| body.id == entity_id)
+                            .and_then(|body| history.seen_at(body, entity_angle));
+                        let lag = history
+                            .newest()
+                            .zip(seen_at)
+                            .map(|(newest, seen_at)| (newest - seen_at) / TIME_SCALE);
+                        match lag {
+                            Some(lag) => self.record_lag(lag),
+                            None => {
+                                spacebuild_log!(debug, self.nickname, "Unable to estimate lag from body {}", entity_id)
+                            }
                         }
                     }
-                    _ => todo!(),
+                    Action::Pong(pong) => self.record_pong(pong),
+                    Action::Login(_) | Action::Register(_) | Action::Resume { .. } | Action::Spectate(_) => {
+                        self.send_error(ErrorCode::UnexpectedAction, "Already logged in")
+                    }
+                    Action::Watch(_) => self.send_error(ErrorCode::UnexpectedAction, "Only spectators can watch"),
+                    // Commands are authorized and carried out by the service.
+                    Action::Admin(_) => self.send_error(ErrorCode::UnexpectedAction, "Unexpected command"),
                 },
             }
         }
 
-        // let mut coords = coordinates.clone();
-
-        if direction.norm() > 0f64 {
-            self.coords += direction / direction.norm() * 100f64 * delta;
-        }
+        self.apply_physics(delta, &env);
 
-        if throttle_up || !self.first_state_sent {
+        if self.throttle_up || self.brake || self.velocity.norm() > 0f64 || !self.first_state_sent {
             spacebuild_log!(trace, "player", "Sending ");
-            let result = self
+            let sent = self
                 .state_send
-                .send(protocol::state::Game::Player(protocol::state::Player {
+                .push(protocol::state::Game::Player(protocol::state::Player {
                     coords: [self.coords.x, self.coords.y, self.coords.z],
-                }))
-                .await;
+                    velocity: [self.velocity.x, self.velocity.y, self.velocity.z],
+                    rtt: self.latency.map(|latency| latency.rtt),
+                }));
 
-            if result.is_err() {
+            if !sent {
                 spacebuild_log!(warn, self.nickname, "Failed to send player info");
             }
         }
@@ -109,31 +335,13 @@ impl Player {
         if !self.first_state_sent {
             self.first_state_sent = true
         }
-        let mut bodies: Vec<protocol::state::Body> = Vec::new();
-
-        for celestial in env {
-            bodies.push(celestial.clone().into());
-
-            if bodies.len() == 50 {
-                spacebuild_log!(
-                    trace,
-                    format!("{}:{}", self.id, self.nickname),
-                    "Sending {} bodies state data",
-                    bodies.len()
-                );
-                self.state_send
-                    .send(protocol::state::Game::Env(bodies.clone()))
-                    .await
-                    .unwrap();
-                bodies.clear();
-            }
-        }
-        if !bodies.is_empty() {
-            self.state_send
-                .send(protocol::state::Game::Env(bodies.clone()))
-                .await
-                .unwrap();
+
+        self.since_last_ping += delta;
+        if self.since_last_ping >= PING_PERIOD && self.capabilities.contains(protocol::CAPABILITY_PING) {
+            self.since_last_ping = 0f64;
+            self.send_ping();
         }
+        self.send_env(time, &env);
 
         // (coords, direction, speed)
     }
@@ -1,4 +1,7 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Instant;
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::error::Error;
@@ -18,41 +21,458 @@ where
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Wire format of the messages: JSON in text frames or MessagePack in binary frames.
+///
+/// Clients choose it through the WebSocket subprotocol header or, without one, by the frame type of their
+/// login action. Incoming frames are always decoded according to their type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    pub const JSON_SUBPROTOCOL: &'static str = "spacebuild.json";
+    pub const MESSAGE_PACK_SUBPROTOCOL: &'static str = "spacebuild.msgpack";
+
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Encoding::Json => Self::JSON_SUBPROTOCOL,
+            Encoding::MessagePack => Self::MESSAGE_PACK_SUBPROTOCOL,
+        }
+    }
+
+    /// First supported encoding of a `Sec-WebSocket-Protocol` header value.
+    pub fn from_subprotocols(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .map(str::trim)
+            .find_map(|subprotocol| match subprotocol {
+                Self::JSON_SUBPROTOCOL => Some(Encoding::Json),
+                Self::MESSAGE_PACK_SUBPROTOCOL => Some(Encoding::MessagePack),
+                _ => None,
+            })
+    }
+
+    /// Encoding of a data frame, `None` for control frames.
+    pub fn of(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(_) => Some(Encoding::Json),
+            Message::Binary(_) => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Message> {
+        match self {
+            Encoding::Json => Ok(Message::text(
+                serde_json::to_string(value).map_err(Error::SerializeError)?,
+            )),
+            Encoding::MessagePack => Ok(Message::binary(
+                rmp_serde::to_vec_named(value).map_err(Error::MessagePackEncodeError)?,
+            )),
+        }
+    }
+
+    /// Decodes a data frame according to its type.
+    pub fn decode<T: DeserializeOwned>(message: &Message) -> Result<T> {
+        match message {
+            Message::Text(text) => {
+                serde_json::from_str(text).map_err(|err| Error::DeserializeError(text.to_string(), err))
+            }
+            Message::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(Error::MessagePackDecodeError),
+            _ => Err(Error::NotADataMessage),
+        }
+    }
+}
+
+/// Version of the protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version still accepted.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Server sends `state::Game::Ping` and expects `Action::Pong`.
+pub const CAPABILITY_PING: &str = "ping";
+/// Server sends `state::Game::Ships`.
+pub const CAPABILITY_SHIPS: &str = "ships";
+/// Server speaks MessagePack, see `Encoding`.
+pub const CAPABILITY_MESSAGE_PACK: &str = "msgpack";
+/// Features supported by this build, a client enabling the ones it declares at login.
+pub const FEATURES: &[&str] = &[CAPABILITY_PING, CAPABILITY_SHIPS, CAPABILITY_MESSAGE_PACK];
+
+/// Credentials of a player, sent as `Action::Login` or `Action::Register`.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Login {
     pub nickname: String,
+    #[serde(default)]
+    pub password: String,
+    /// Protocol version of the client, clients predating versioning sending none.
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl std::fmt::Debug for Login {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Login")
+            .field("nickname", &self.nickname)
+            .field("password", &"***")
+            .field("protocol_version", &self.protocol_version)
+            .field("capabilities", &self.capabilities)
+            .finish()
+    }
+}
+
+impl Login {
+    /// Login of a client of this build, declaring every feature.
+    pub fn new(nickname: &str, password: &str) -> Self {
+        Self {
+            nickname: nickname.to_string(),
+            password: password.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+}
+
+/// What a spectator watches.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Target {
+    /// The star system of the star `id`, while it is loaded.
+    System(u32),
+    /// Loaded bodies within `radius` of `center`, the radius being capped by the server.
+    Region { center: [f64; 3], radius: f64 },
+}
+
+/// Read-only login, creating no player.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Spectate {
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Watches the loaded system with the most players when `None`.
+    #[serde(default)]
+    pub target: Option<Target>,
+}
+
+impl Spectate {
+    /// Spectate action of a client of this build, declaring every feature.
+    pub fn new(target: Option<Target>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            target,
+        }
+    }
+}
+
+/// Permissions of a player, each role including the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    #[default]
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    /// Name stored in the `role` column of the `Player` table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(role: &str) -> Result<Self> {
+        match role {
+            "player" => Ok(Role::Player),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(Error::InvalidRole(role.to_string())),
+        }
+    }
+}
+
+/// Privileged action, see `Command::required_role`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Disconnects a player logged in.
+    Kick {
+        nickname: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Refuses the logins of a player, disconnecting it if logged in.
+    Ban {
+        nickname: String,
+        #[serde(default)]
+        reason: Option<String>,
+        /// Seconds before the ban expires, forever if `None`.
+        #[serde(default)]
+        duration: Option<f64>,
+    },
+    Unban {
+        nickname: String,
+    },
+    /// Refuses the connections from an IP address or a CIDR block, disconnecting the players using it.
+    BanAddress {
+        address: String,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        duration: Option<f64>,
+    },
+    UnbanAddress {
+        address: String,
+    },
+    /// Lists the bans in force.
+    ListBans,
+    /// Sends a notice to everyone connected.
+    Broadcast {
+        message: String,
+    },
+    /// Moves a player logged in, stopping its ship.
+    Teleport {
+        nickname: String,
+        coords: [f64; 3],
+    },
+    /// Generates a new star system around `coords`.
+    SpawnSystem {
+        coords: [f64; 3],
+    },
+    SetRole {
+        nickname: String,
+        role: Role,
+    },
+    /// Answers the round-trip time and clock offset measured for a player logged in.
+    Latency {
+        nickname: String,
+    },
+}
+
+impl Command {
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::Kick { .. }
+            | Command::Ban { .. }
+            | Command::Unban { .. }
+            | Command::BanAddress { .. }
+            | Command::UnbanAddress { .. }
+            | Command::ListBans
+            | Command::Broadcast { .. } => Role::Moderator,
+            Command::Teleport { .. }
+            | Command::SpawnSystem { .. }
+            | Command::SetRole { .. }
+            | Command::Latency { .. } => Role::Admin,
+        }
+    }
+}
+
+/// Seconds of a duration like `90s`, `30m`, `12h` or `7d`.
+fn parse_duration(duration: &str) -> Option<f64> {
+    let unit = match duration.chars().last()? {
+        's' => 1f64,
+        'm' => 60f64,
+        'h' => 3600f64,
+        'd' => 86400f64,
+        _ => return None,
+    };
+    let value = f64::from_str(&duration[..duration.len() - 1]).ok()?;
+    (value.is_finite() && value > 0f64).then_some(value * unit)
+}
+
+/// Optional duration then optional reason, as in `ban test123 7d cheating`.
+fn parse_sanction(args: &[&str]) -> (Option<f64>, Option<String>) {
+    let duration = args.first().and_then(|arg| parse_duration(arg));
+    let reason = &args[duration.map_or(0, |_| 1)..];
+    (duration, (!reason.is_empty()).then(|| reason.join(" ")))
+}
+
+fn parse_coords(args: &[&str]) -> Option<[f64; 3]> {
+    match args {
+        [x, y, z] => Some([x.parse().ok()?, y.parse().ok()?, z.parse().ok()?]),
+        _ => None,
+    }
+}
+
+/// Parses a command typed in the server console, such as `ban test123 7d cheating` or `ban-ip 10.0.0.0/8`.
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["kick", nickname, reason @ ..] => Some(Command::Kick {
+                nickname: nickname.to_string(),
+                reason: (!reason.is_empty()).then(|| reason.join(" ")),
+            }),
+            ["ban", nickname, args @ ..] => {
+                let (duration, reason) = parse_sanction(args);
+                Some(Command::Ban {
+                    nickname: nickname.to_string(),
+                    reason,
+                    duration,
+                })
+            }
+            ["unban", nickname] => Some(Command::Unban {
+                nickname: nickname.to_string(),
+            }),
+            ["ban-ip", address, args @ ..] => {
+                let (duration, reason) = parse_sanction(args);
+                Some(Command::BanAddress {
+                    address: address.to_string(),
+                    reason,
+                    duration,
+                })
+            }
+            ["unban-ip", address] => Some(Command::UnbanAddress {
+                address: address.to_string(),
+            }),
+            ["bans"] => Some(Command::ListBans),
+            ["broadcast", message @ ..] if !message.is_empty() => Some(Command::Broadcast {
+                message: message.join(" "),
+            }),
+            ["teleport", nickname, coords @ ..] => parse_coords(coords).map(|coords| Command::Teleport {
+                nickname: nickname.to_string(),
+                coords,
+            }),
+            ["spawn-system", coords @ ..] => parse_coords(coords).map(|coords| Command::SpawnSystem { coords }),
+            ["set-role", nickname, role] => Some(Command::SetRole {
+                nickname: nickname.to_string(),
+                role: Role::from_str(role)?,
+            }),
+            ["latency", nickname] => Some(Command::Latency {
+                nickname: nickname.to_string(),
+            }),
+            _ => None,
+        };
+        command.ok_or_else(|| Error::InvalidCommand(line.to_string()))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ShipState {
     pub throttle_up: bool,
+    #[serde(default)]
+    pub brake: bool,
     pub direction: [f64; 3],
 }
 
+/// Answer to a `state::Game::Ping`, `client_time` being the client clock in seconds since the UNIX epoch.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pong {
+    pub seq: u32,
+    #[serde(default)]
+    pub client_time: Option<f64>,
+    #[serde(skip)]
+    pub(crate) received_at: Option<Instant>,
+}
+
+impl Pong {
+    pub fn new(seq: u32, client_time: Option<f64>) -> Self {
+        Self {
+            seq,
+            client_time,
+            received_at: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Action {
     Login(Login),
+    /// Creates the account before logging in.
+    Register(Login),
+    /// Re-attaches a player whose connection dropped, `token` coming from the last `state::Auth`.
+    Resume {
+        token: String,
+    },
+    Spectate(Spectate),
+    /// Changes what a spectator watches.
+    Watch(Option<Target>),
+    /// Privileged command, answered by a `state::Game::Answer` or a `state::Game::Error`.
+    Admin(Command),
     Ping((u32, f64)),
+    Pong(Pong),
     ShipState(ShipState),
 }
 
 pub mod state {
     use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
 
     use crate::body;
+    use crate::orbit::Orbit;
 
     #[derive(Serialize, Deserialize, Clone, Debug)]
     pub struct Player {
         pub coords: [f64; 3],
+        pub velocity: [f64; 3],
+        /// Smoothed round-trip time in seconds, once measured.
+        #[serde(default)]
+        pub rtt: Option<f64>,
     }
 
-    #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+    /// Another player's ship, `direction` being its normalized heading.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Ship {
+        pub id: u32,
+        pub nickname: String,
+        pub coords: [f64; 3],
+        pub velocity: [f64; 3],
+        pub direction: [f64; 3],
+    }
+
+    /// Sent periodically by the server, to be answered with an `Action::Pong` of the same `seq`.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct Ping {
+        pub seq: u32,
+        /// Server clock in seconds since the UNIX epoch.
+        pub server_time: f64,
+    }
+
+    /// A body as first sent to a client, `coords` being its position at the galaxy time of the message.
+    ///
+    /// Orbiting bodies move along `orbit` around their gravity center: clients extrapolate their position
+    /// from the galaxy time and are not sent it again.
+    #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
     pub struct Body {
         pub id: u32,
         pub coords: [f64; 3],
         pub rotating_speed: f64,
         pub gravity_center: u32,
         pub body_type: String,
+        #[serde(default)]
+        pub mass: f64,
+        #[serde(default)]
+        pub orbit: Orbit,
+    }
+
+    impl Body {
+        pub fn is_orbiting(&self) -> bool {
+            self.gravity_center != self.id && self.gravity_center != 0
+        }
+
+        /// Position at the given galaxy time, following the gravity centers among `bodies`.
+        pub fn position_at(&self, bodies: &HashMap<u32, Body>, time: f64) -> Option<[f64; 3]> {
+            if !self.is_orbiting() {
+                return Some(self.coords);
+            }
+            let center = bodies.get(&self.gravity_center)?.position_at(bodies, time)?;
+            let local = self
+                .orbit
+                .position(self.orbit.mean_anomaly_epoch + self.rotating_speed * time);
+            Some([center[0] + local.x, center[1] + local.y, center[2] + local.z])
+        }
     }
 
     impl From<body::Body> for Body {
@@ -63,18 +483,201 @@ pub mod state {
                 gravity_center: value.gravity_center,
                 rotating_speed: value.rotating_speed,
                 body_type: value.body_type.to_string(),
+                mass: value.mass,
+                orbit: value.orbit,
             }
         }
     }
+
+    /// Changed fields of a body already known by the client.
+    #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+    pub struct BodyUpdate {
+        pub id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub coords: Option<[f64; 3]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub rotating_speed: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub gravity_center: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub body_type: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub mass: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub orbit: Option<Orbit>,
+    }
+
+    fn changed<T: PartialEq + Clone>(known: &T, current: &T) -> Option<T> {
+        (known != current).then(|| current.clone())
+    }
+
+    impl BodyUpdate {
+        /// Fields of `current` differing from `known`, `None` if the client is up to date.
+        ///
+        /// Coordinates of orbiting bodies are extrapolated by clients and only compared for fixed bodies.
+        pub fn between(known: &Body, current: &Body) -> Option<Self> {
+            let update = Self {
+                id: current.id,
+                coords: if current.is_orbiting() {
+                    None
+                } else {
+                    changed(&known.coords, &current.coords)
+                },
+                rotating_speed: changed(&known.rotating_speed, &current.rotating_speed),
+                gravity_center: changed(&known.gravity_center, &current.gravity_center),
+                body_type: changed(&known.body_type, &current.body_type),
+                mass: changed(&known.mass, &current.mass),
+                orbit: changed(&known.orbit, &current.orbit),
+            };
+            (update
+                != Self {
+                    id: current.id,
+                    ..Default::default()
+                })
+            .then_some(update)
+        }
+
+        pub fn apply(&self, body: &mut Body) {
+            if let Some(coords) = self.coords {
+                body.coords = coords;
+            }
+            if let Some(rotating_speed) = self.rotating_speed {
+                body.rotating_speed = rotating_speed;
+            }
+            if let Some(gravity_center) = self.gravity_center {
+                body.gravity_center = gravity_center;
+            }
+            if let Some(body_type) = &self.body_type {
+                body.body_type = body_type.clone();
+            }
+            if let Some(mass) = self.mass {
+                body.mass = mass;
+            }
+            if let Some(orbit) = self.orbit {
+                body.orbit = orbit;
+            }
+        }
+
+        /// Folds a later update of the same body into this one.
+        pub fn merge(&mut self, later: BodyUpdate) {
+            self.coords = later.coords.or(self.coords);
+            self.rotating_speed = later.rotating_speed.or(self.rotating_speed);
+            self.gravity_center = later.gravity_center.or(self.gravity_center);
+            self.body_type = later.body_type.or(self.body_type.take());
+            self.mass = later.mass.or(self.mass);
+            self.orbit = later.orbit.or(self.orbit);
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct Env {
+        /// Galaxy time the bodies are given at.
+        pub time: f64,
+        pub bodies: Vec<Body>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct EnvUpdate {
+        pub time: f64,
+        pub bodies: Vec<BodyUpdate>,
+    }
+
+    /// Server description sent along a successful login.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Hello {
+        pub server_version: String,
+        pub protocol_version: u32,
+        /// Game updates per second.
+        pub tick_rate: f64,
+        /// Features supported by the server.
+        pub features: Vec<String>,
+        /// Features enabled for this client, among the ones it declared.
+        pub capabilities: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub enum Rejection {
+        /// The client protocol version is not within `min..=max`.
+        IncompatibleProtocol {
+            client: u32,
+            min: u32,
+            max: u32,
+        },
+        AuthenticationFailed,
+        /// Unknown nickname or wrong password, the server not telling which.
+        InvalidCredentials,
+        NicknameTaken,
+        /// The password is shorter than `auth::MIN_PASSWORD_LENGTH`.
+        WeakPassword,
+        /// The session token is unknown or its grace period is over.
+        InvalidSessionToken,
+        /// The session is still attached to another connection.
+        SessionInUse,
+        /// The account is banned, for the given reason.
+        Banned(String),
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct Auth {
         pub(crate) success: bool,
         pub(crate) message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(crate) hello: Option<Hello>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(crate) rejection: Option<Rejection>,
+        /// Token for `Action::Resume`, valid until the next one is issued or the session ends.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(crate) token: Option<String>,
+    }
+
+    /// Why an action was refused or a client disconnected.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ErrorCode {
+        /// A message could not be decoded.
+        InvalidMessage,
+        /// The first message was not a login action.
+        NotALoginAction,
+        /// The action is not allowed at this point of the session.
+        UnexpectedAction,
+        AuthenticationFailed,
+        IncompatibleProtocol,
+        Internal,
+        /// The role of the player does not allow the command.
+        Forbidden,
+        /// The command targets an unknown player or can't be carried out.
+        InvalidCommand,
+        /// A moderator disconnected the player.
+        Kicked,
+        /// The client did not keep up with the game states and was disconnected.
+        SlowClient,
+        /// The client sent more than the rate limits of the server allow.
+        RateLimited,
+        MessageTooLarge,
     }
 
     #[derive(Serialize, Deserialize, Clone, Debug)]
     pub enum Game {
         Player(Player),
-        Env(Vec<Body>),
+        /// Bodies entering the view of the player.
+        EnvEnter(Env),
+        /// Bodies in view whose description changed.
+        EnvUpdate(EnvUpdate),
+        /// Ids of the bodies leaving the view of the player.
+        EnvLeave(Vec<u32>),
+        Ships(Vec<Ship>),
+        Ping(Ping),
+        /// An action was refused, or the server is about to close the connection.
+        Error {
+            code: ErrorCode,
+            message: String,
+        },
+        /// Informational message to display to the player.
+        Notice {
+            message: String,
+        },
+        /// Answer to an `Action::Admin` command carried out, refused ones being answered by an `Error`.
+        Answer {
+            message: String,
+        },
     }
 }
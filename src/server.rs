@@ -7,12 +7,17 @@ use crate::tls::ClientPki;
 use crate::tls::ServerPki;
 use crate::Result;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 
+/// Period of the game updates.
+pub const TICK_PERIOD: Duration = Duration::from_millis(100);
+
 pub enum InstanceConfig {
     UserInstance(Arc<Mutex<Instance>>),
     UserSqliteDb { path: String },
+    InMemory,
 }
 
 pub enum TcpConfig {
@@ -42,6 +47,10 @@ pub async fn run(
             spacebuild_log!(info, "server", "Loading {}", path);
             Arc::new(Mutex::new(Instance::from_path(path.as_str()).await?))
         }
+        InstanceConfig::InMemory => {
+            spacebuild_log!(info, "server", "Keeping the galaxy in memory only");
+            Arc::new(Mutex::new(Instance::in_memory().await?))
+        }
     };
 
     let listener = match server_config.tcp {
@@ -58,7 +67,7 @@ pub async fn run(
     };
 
     let mut ref_instant = tokio::time::Instant::now();
-    let tick_value = std::time::Duration::from_millis(100);
+    let tick_value = TICK_PERIOD;
     let mut update_tick_delay = tokio::time::interval(tick_value);
     let mut save_tick_delay = tokio::time::interval(std::time::Duration::from_secs(30));
 
@@ -91,7 +100,10 @@ pub async fn run(
                 instance.lock().await.update(delta.as_secs_f64()).await;
 
                 if must_stop{
-                    instance.lock().await.save_all().await;
+                    instance.lock().await.notify_all("Server is shutting down").await;
+                    if let Err(err) = instance.lock().await.save_all().await {
+                        spacebuild_log!(error, "server", "Could not save before stopping: {}", err);
+                    }
                     spacebuild_log!(info, "server", "Server loop stops now (on stop channel)!");
                     return Ok(())
                 }
@@ -100,13 +112,21 @@ pub async fn run(
             // ON SAVE TICK DELAY----------------------------------
             _ = save_tick_delay.tick() => {
 
-                instance.lock().await.save_all().await;
+                // Written by the persistence task, the tick never waiting on the disk.
+                let saved = instance.lock().await.save();
+                spacebuild_log!(debug, "server", "Saving {} bodies and players", saved);
             },
             // ----------------------------------------------------
             // ON TCP ACCEPT---------------------------------------
             Ok((stream, addr)) = listener.accept() => {
                 spacebuild_log!(info, "server", "TCP accept from: {}", addr);
 
+                if let Err(err) = instance.lock().await.borrow_bans().check_address(addr.ip()) {
+                    spacebuild_log!(info, "server", "Refused connection from {}: {}", addr, err);
+                    drop(stream);
+                    continue;
+                }
+
                 let cln = Arc::clone(&instance);
                 if let Some(tls_acceptor) = tls_acceptor.clone() {
                     let acceptor = tls_acceptor.clone();
@@ -1,24 +1,46 @@
+use crate::auth;
 use crate::error::Error;
 use crate::instance::Instance;
-use crate::protocol::Action;
+use crate::limits::{RateLimiter, RateLimits, Verdict, Violations};
+use crate::outbox::OutboxReceiver;
+use crate::protocol::state::{Auth, Game, Hello};
+use crate::protocol::Encoding;
+use crate::protocol::{Action, Command, Login, Spectate};
+use crate::protocol::{CAPABILITY_PING, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::server::TICK_PERIOD;
 use futures::SinkExt;
 use futures::StreamExt;
 // use tokio_tungstenite::tungstenite::Message;
+use hyper_tungstenite::tungstenite;
 use hyper_tungstenite::tungstenite::Message;
 use hyper_tungstenite::WebSocketStream;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
-use tokio_stream::wrappers::ReceiverStream;
 // use tokio_tungstenite::WebSocketStream;
 extern crate scopeguard;
 use crate::spacebuild_log;
 use crate::Result;
 
+/// Channels of a logged in player, with the state left unsent by a previous connection.
+struct Session {
+    id: u32,
+    action_send: Sender<Action>,
+    state_recv: OutboxReceiver,
+    capabilities: Vec<String>,
+    pending: Option<Game>,
+    spectator: bool,
+}
+
+/// Time given to a client to acknowledge the closing of its connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub(crate) struct Service<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -27,97 +49,348 @@ where
     websocket: WebSocketStream<S>,
     address: SocketAddr,
     instance: Arc<Mutex<Instance>>,
+    encoding: Option<Encoding>,
+    spectator: bool,
+    limiter: RateLimiter,
+    violations: Arc<Violations>,
 }
 
 impl<S> Service<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn new(websocket: WebSocketStream<S>, instance: Arc<Mutex<Instance>>, address: SocketAddr) -> Service<S> {
+    pub fn new(
+        websocket: WebSocketStream<S>,
+        instance: Arc<Mutex<Instance>>,
+        address: SocketAddr,
+        encoding: Option<Encoding>,
+        limits: &RateLimits,
+        violations: Arc<Violations>,
+    ) -> Service<S> {
         Service::<S> {
             websocket,
             instance,
             id: u32::MAX,
             address,
+            encoding,
+            spectator: false,
+            limiter: RateLimiter::new(limits),
+            violations,
         }
     }
 
-    async fn handle_message_for_auth(
-        &mut self,
-        message: Message,
-    ) -> Result<(Sender<Action>, Receiver<crate::protocol::state::Game>)> {
-        match message {
-            Message::Text(msg) => {
-                let maybe_action: serde_json::Result<Action> = serde_json::from_str(msg.as_str());
-
-                let mut auth_info = crate::protocol::state::Auth {
-                    success: false,
-                    message: "".to_string(),
-                };
-                if maybe_action.is_err() {
-                    return Err(Error::InvalidJson(maybe_action.err().unwrap()));
-                }
+    async fn handle_message_for_auth(&mut self, message: Message) -> Result<Session> {
+        let Some(encoding) = self.encoding.or(Encoding::of(&message)) else {
+            spacebuild_log!(warn, self.address, "Not a data message, closing client...");
+            return Err(self.close_with(Error::NotADataMessage).await);
+        };
+        self.encoding = Some(encoding);
 
-                let maybe_login = maybe_action.unwrap();
+        let maybe_login = match Encoding::decode::<Action>(&message) {
+            Ok(action) => action,
+            Err(err) => return Err(self.close_with(err).await),
+        };
 
-                if let Action::Login(login) = maybe_login {
-                    let mut guard = self.instance.lock().await;
+        let mut auth_info = crate::protocol::state::Auth {
+            success: false,
+            message: "".to_string(),
+            hello: None,
+            rejection: None,
+            token: None,
+        };
 
-                    spacebuild_log!(info, self.address, "Login request for {}", login.nickname);
-                    let maybe_data = guard.authenticate(login.nickname).await;
-                    if maybe_data.is_err() {
-                        auth_info.message = format!("{}", maybe_data.err().unwrap());
-                        spacebuild_log!(warn, self.address, "Login error: {}", auth_info.message);
-                        return Err(Error::AuthenticationError(auth_info.message));
-                    }
+        // The address may have been banned since the connection was accepted.
+        let banned = self
+            .instance
+            .lock()
+            .await
+            .borrow_bans()
+            .check_address(self.address.ip());
+        let maybe_session = match maybe_login {
+            Action::Login(_) | Action::Register(_) | Action::Resume { .. } | Action::Spectate(_) if banned.is_err() => {
+                Err(banned.unwrap_err())
+            }
+            Action::Login(login) => self.log_in(login, false).await,
+            Action::Register(login) => self.log_in(login, true).await,
+            Action::Resume { token } => self.resume(&token).await,
+            Action::Spectate(spectate) => self.spectate(spectate).await,
+            _ => {
+                spacebuild_log!(warn, self.address, "Not an login action, closing client...");
+                return Err(self.close_with(Error::NotALoginAction).await);
+            }
+        };
+        let session = match maybe_session {
+            Ok(session) => session,
+            Err(err) => {
+                auth_info.message = err.to_string();
+                auth_info.rejection = Some(err.rejection());
+                return Err(self.reject(encoding, auth_info).await);
+            }
+        };
 
-                    let (id, action_send, state_recv) = maybe_data.unwrap();
+        self.id = session.id;
+        self.spectator = session.spectator;
 
-                    self.id = id;
+        spacebuild_log!(debug, self.address, "Login success for {}", self.id);
 
-                    spacebuild_log!(debug, self.address, "Login success for {}", self.id);
+        auth_info.success = true;
+        auth_info.message = self.id.to_string();
+        auth_info.hello = Some(Hello {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            tick_rate: 1f64 / TICK_PERIOD.as_secs_f64(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            capabilities: session.capabilities.clone(),
+        });
+        if !session.spectator {
+            auth_info.token = Some(self.instance.lock().await.open_session(self.id));
+        }
 
-                    auth_info.success = true;
-                    auth_info.message = self.id.to_string();
+        let result = self.websocket.send(encoding.encode(&auth_info)?).await;
+        if result.is_err() {
+            spacebuild_log!(warn, self.address, "Message send error: {}", result.err().unwrap());
+        }
 
-                    let maybe_login_info_str = serde_json::to_string(&auth_info);
-                    assert!(maybe_login_info_str.is_ok());
-                    let result = self.websocket.send(Message::text(maybe_login_info_str.unwrap())).await;
-                    if result.is_err() {
-                        spacebuild_log!(warn, self.address, "Message send error: {}", result.err().unwrap());
-                    }
+        Ok(session)
+    }
 
-                    Ok((action_send, state_recv))
-                } else {
-                    spacebuild_log!(warn, self.address, "Not an login action, closing client...");
-                    let _ = self.websocket.close(None).await;
-                    return Err(Error::NotALoginAction);
-                }
+    async fn log_in(&mut self, login: Login, register: bool) -> Result<Session> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&login.protocol_version) {
+            return Err(Error::IncompatibleProtocol(login.protocol_version));
+        }
+
+        let capabilities: Vec<String> = login
+            .capabilities
+            .into_iter()
+            .filter(|capability| FEATURES.contains(&capability.as_str()))
+            .collect();
+
+        spacebuild_log!(
+            info,
+            self.address,
+            "{} request for {}",
+            if register { "Register" } else { "Login" },
+            login.nickname
+        );
+        let (id, action_send, state_recv) = self
+            .sign_in(
+                login.nickname,
+                login.password,
+                register,
+                capabilities.iter().cloned().collect(),
+            )
+            .await?;
+        Ok(Session {
+            id,
+            action_send,
+            state_recv,
+            capabilities,
+            pending: None,
+            spectator: false,
+        })
+    }
+
+    async fn spectate(&mut self, spectate: Spectate) -> Result<Session> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&spectate.protocol_version) {
+            return Err(Error::IncompatibleProtocol(spectate.protocol_version));
+        }
+
+        // Spectators are not pinged.
+        let capabilities: Vec<String> = spectate
+            .capabilities
+            .into_iter()
+            .filter(|capability| FEATURES.contains(&capability.as_str()) && capability != CAPABILITY_PING)
+            .collect();
+
+        spacebuild_log!(info, self.address, "Spectate request for {:?}", spectate.target);
+        let (id, action_send, state_recv) = self
+            .instance
+            .lock()
+            .await
+            .spectate(spectate.target, capabilities.iter().cloned().collect());
+        Ok(Session {
+            id,
+            action_send,
+            state_recv,
+            capabilities,
+            pending: None,
+            spectator: true,
+        })
+    }
+
+    async fn resume(&mut self, token: &str) -> Result<Session> {
+        spacebuild_log!(info, self.address, "Resume request");
+        let mut guard = self.instance.lock().await;
+        let (id, suspended) = guard.resume(token)?;
+        guard.set_address(id, self.address.ip());
+        Ok(Session {
+            id,
+            action_send: suspended.action_send,
+            state_recv: suspended.state_recv,
+            capabilities: guard.capabilities(id),
+            pending: suspended.pending,
+            spectator: false,
+        })
+    }
+
+    /// Registers or logs a player in, hashing and checking passwords without holding the instance.
+    async fn sign_in(
+        &mut self,
+        nickname: String,
+        password: String,
+        register: bool,
+        capabilities: HashSet<String>,
+    ) -> Result<(u32, Sender<Action>, OutboxReceiver)> {
+        // Capabilities are set under the same lock, before a tick can send anything to the player.
+        if register {
+            let password_hash = auth::hash_password(password).await?;
+            let mut guard = self.instance.lock().await;
+            let data = guard.register(nickname, &password_hash).await?;
+            guard.set_capabilities(data.0, capabilities);
+            guard.set_address(data.0, self.address.ip());
+            Ok(data)
+        } else {
+            let password_hash = self.instance.lock().await.password_hash(&nickname).await;
+            let password_hash = match password_hash {
+                Err(Error::InvalidCredentials) => return Err(auth::refuse_unknown(password).await),
+                password_hash => password_hash?.ok_or(Error::PasswordNotSet)?,
+            };
+            auth::verify_password(password, password_hash).await?;
+            let mut guard = self.instance.lock().await;
+            let data = guard.authenticate_verified(nickname).await?;
+            guard.set_capabilities(data.0, capabilities);
+            guard.set_address(data.0, self.address.ip());
+            Ok(data)
+        }
+    }
+
+    /// Tells the client why it is disconnected and closes the connection.
+    async fn close_with(&mut self, err: Error) -> Error {
+        let state = Game::Error {
+            code: err.code(),
+            message: err.to_string(),
+        };
+        if let Ok(message) = self.encoding.unwrap_or_default().encode(&state) {
+            let _ = self.websocket.send(message).await;
+        }
+        self.close_gracefully().await;
+        err
+    }
+
+    /// Closes the connection and waits for the client to acknowledge it, so that the last states are not lost to a reset.
+    async fn close_gracefully(&mut self) {
+        let _ = self.websocket.close(None).await;
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while let Some(Ok(_)) = self.websocket.next().await {}
+        })
+        .await;
+    }
+
+    /// Answers a failed login with its reason and closes the connection.
+    async fn reject(&mut self, encoding: Encoding, auth_info: Auth) -> Error {
+        spacebuild_log!(warn, self.address, "Login error: {}", auth_info.message);
+        if let Ok(message) = encoding.encode(&auth_info) {
+            let _ = self.websocket.send(message).await;
+        }
+        self.close_gracefully().await;
+        Error::AuthenticationError(auth_info.message)
+    }
+
+    async fn leave(&mut self) {
+        let mut guard = self.instance.lock().await;
+        if self.spectator {
+            guard.leave_spectator(self.id);
+        } else {
+            guard.leave(self.id).await;
+        }
+    }
+
+    /// Keeps the player loaded for a resume after its connection dropped, spectators simply leaving.
+    async fn suspend(&mut self, send: Sender<Action>, recv: OutboxReceiver, pending: Option<Game>) {
+        if self.spectator {
+            self.leave().await;
+        } else {
+            self.instance.lock().await.suspend(self.id, send, recv, pending);
+        }
+    }
+
+    /// Sends a game state, states that can't be encoded being dropped.
+    async fn send_game_info(&mut self, game_info: &Game) -> std::result::Result<(), tungstenite::Error> {
+        let message = match self.encoding.unwrap_or_default().encode(game_info) {
+            Ok(message) => message,
+            Err(err) => {
+                spacebuild_log!(
+                    warn,
+                    self.address,
+                    "Could not encode data for client {}: {}",
+                    self.id,
+                    err
+                );
+                return Ok(());
             }
-            _ => {
-                spacebuild_log!(warn, self.address, "Not a text message, closing client...");
-                let _ = self.websocket.close(None).await;
-                Err(Error::NotTextMessage)
+        };
+        self.websocket.send(message).await
+    }
+
+    /// Checks the role of the player against the command, runs it and records the attempt in the audit log.
+    async fn handle_command(&mut self, command: &Command) -> Game {
+        let (actor, role, audit) = {
+            let guard = self.instance.lock().await;
+            let actor = guard.nickname(self.id).unwrap_or_default().to_string();
+            (actor, guard.role(self.id), guard.borrow_audit().clone())
+        };
+        // Bans and audit entries are written without holding the instance, for the ticks not to wait on the storage.
+        let result = if role >= command.required_role() {
+            Instance::execute_shared(&self.instance, &actor, command).await
+        } else {
+            Err(Error::Forbidden(command.required_role()))
+        };
+        audit.record_result(&actor, command, &result).await;
+
+        match result {
+            Ok(message) => Game::Answer { message },
+            Err(err) => {
+                spacebuild_log!(
+                    warn,
+                    self.address,
+                    "Command {:?} of {} failed: {}",
+                    command,
+                    self.id,
+                    err
+                );
+                Game::Error {
+                    code: err.code(),
+                    message: err.to_string(),
+                }
             }
         }
     }
 
-    async fn handle_message_for_gameplay(
-        &mut self,
-        send: Sender<Action>,
-        recv: Receiver<crate::protocol::state::Game>,
-    ) -> Result<()> {
-        let mut stream = ReceiverStream::new(recv);
+    async fn handle_message_for_gameplay(&mut self, session: Session) -> Result<()> {
+        let send = session.action_send;
+        let mut outbox = session.state_recv;
+
+        if let Some(game_info) = session.pending {
+            if let Err(err) = self.send_game_info(&game_info).await {
+                spacebuild_log!(warn, self.address, "Could not send data to client {}: {}", self.id, err);
+                self.suspend(send, outbox, Some(game_info)).await;
+                return Ok(());
+            }
+        }
 
         loop {
             tokio::select! {
-                Some(game_info) = stream.next() => {
+                game_info = outbox.recv() => {
                     // let _ = self.mutex.lock().await;
-                    let str = serde_json::to_string(&game_info).unwrap();
-                    let result = self.websocket.send(Message::text(str)).await;
+                    let Some(game_info) = game_info else {
+                        spacebuild_log!(info, self.address, "Player {} removed from the instance: closing client", self.id);
+                        self.close_gracefully().await;
+                        return Ok(());
+                    };
+                    let result = self.send_game_info(&game_info).await;
                     if result.is_err() {
                         spacebuild_log!(warn, self.address, "Could not send data to client {}: {}", self.id, result.err().unwrap());
-                        self.instance.lock().await.leave(self.id).await;
+                        self.suspend(send, outbox, Some(game_info)).await;
                         let _ = self.websocket.close(None).await;
                         return Ok(());
                     }
@@ -125,31 +398,81 @@ where
                 Some(message) = self.websocket.next() => {
                     // let _ = self.mutex.lock().await;
                     spacebuild_log!(trace, self.address, "Message received");
-                    if message.is_err() {
-                        spacebuild_log!(info, self.address, "Websocket read error: {}", message.err().unwrap());
-                        self.instance.lock().await.leave(self.id).await;
-                        return Ok(());
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(tungstenite::Error::Capacity(err)) => {
+                            spacebuild_log!(warn, self.address, "Oversized message from {}: {}", self.id, err);
+                            self.violations.record_oversized();
+                            self.leave().await;
+                            self.close_with(Error::MessageTooLarge(self.limiter.max_message_size)).await;
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            spacebuild_log!(info, self.address, "Websocket read error: {}", err);
+                            self.suspend(send, outbox, None).await;
+                            return Ok(());
+                        }
+                    };
+                    if !matches!(message, Message::Close(_)) {
+                        let verdict = self.limiter.check(message.len(), Instant::now());
+                        self.violations.record(verdict);
+                        match verdict {
+                            Verdict::Accept => {}
+                            Verdict::Drop => continue,
+                            Verdict::Throttle(wait) => tokio::time::sleep(wait).await,
+                            Verdict::Disconnect => {
+                                spacebuild_log!(warn, self.address, "Rate limits exceeded by {}: closing client", self.id);
+                                self.leave().await;
+                                self.close_with(Error::RateLimited).await;
+                                return Ok(());
+                            }
+                        }
                     }
-                    match message.unwrap() {
-                        Message::Text(msg) => {
-                            let maybe_action: serde_json::Result<Action> =
-                                serde_json::from_str(msg.as_str());
+                    match message {
+                        message @ (Message::Text(_) | Message::Binary(_)) => {
+                            let maybe_action = Encoding::decode::<Action>(&message);
 
-                            if maybe_action.is_err() {
-                                spacebuild_log!(warn, self.address, "bad JSON received");
+                            if let Err(err) = maybe_action {
+                                spacebuild_log!(warn, self.address, "bad message received: {}", err);
+                                self.leave().await;
+                                self.close_with(err).await;
                                 return Ok(());
                             }
 
-                            send.send(maybe_action.unwrap()).await.unwrap();
+                            let mut action = maybe_action.unwrap();
+                            if let Action::Pong(pong) = &mut action {
+                                pong.received_at = Some(Instant::now());
+                            }
+                            if let (Action::Admin(command), false) = (&action, self.spectator) {
+                                let answer = self.handle_command(command).await;
+                                if let Err(err) = self.send_game_info(&answer).await {
+                                    spacebuild_log!(warn, self.address, "Could not send data to client {}: {}", self.id, err);
+                                }
+                                continue;
+                            }
+                            match send.try_send(action) {
+                                Ok(()) => {}
+                                Err(TrySendError::Full(_)) => {
+                                    spacebuild_log!(warn, self.address, "Actions of {} are not consumed: dropping", self.id);
+                                    self.violations.record(Verdict::Drop);
+                                }
+                                Err(TrySendError::Closed(_)) => {
+                                    spacebuild_log!(info, self.address, "Player {} removed from the instance: closing client", self.id);
+                                    self.close_gracefully().await;
+                                    return Ok(());
+                                }
+                            }
 
                         }
                         Message::Close(_) => {
-                            self.instance.lock().await.leave(self.id).await;
+                            self.leave().await;
                             return Ok(());
                         }
+                        Message::Ping(_) | Message::Pong(_) => {}
                         _ => {
                             spacebuild_log!(info, self.address, "Unexpected message type received: closing client");
-                            self.instance.lock().await.leave(self.id).await;
+                            self.leave().await;
+                            self.close_with(Error::NotADataMessage).await;
                             return Ok(());
                         }
                     }
@@ -164,9 +487,18 @@ where
         if message.is_none() {
             return Ok(());
         }
-        let message = message.unwrap();
-        let (send, recv) = self.handle_message_for_auth(message.unwrap()).await?;
-        self.handle_message_for_gameplay(send, recv).await?;
+        let message = match message.unwrap() {
+            Ok(message) => message,
+            Err(tungstenite::Error::Capacity(_)) => {
+                self.violations.record_oversized();
+                return Err(self
+                    .close_with(Error::MessageTooLarge(self.limiter.max_message_size))
+                    .await);
+            }
+            Err(err) => return Err(Error::WsCantRead(err)),
+        };
+        let session = self.handle_message_for_auth(message).await?;
+        self.handle_message_for_gameplay(session).await?;
         Ok(())
     }
 }
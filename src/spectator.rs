@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+
+use tokio::sync::mpsc::{error::TryRecvError, Receiver};
+
+use crate::{
+    body::Body,
+    outbox::OutboxSender,
+    player::ENV_CHUNK_SIZE,
+    protocol::{self, state::ErrorCode, Action, Target},
+    spacebuild_log,
+    view::ViewTracker,
+};
+
+/// Read-only client watching a system or a region, with no player nor row in the database.
+pub struct Spectator {
+    pub(crate) id: u32,
+    pub(crate) target: Option<Target>,
+    pub(crate) action_recv: Receiver<Action>,
+    pub(crate) state_send: OutboxSender,
+    pub(crate) view: ViewTracker,
+    pub(crate) capabilities: HashSet<String>,
+    ships_in_view: bool,
+}
+
+impl Spectator {
+    pub(crate) fn new(
+        id: u32,
+        target: Option<Target>,
+        state_send: OutboxSender,
+        action_recv: Receiver<Action>,
+    ) -> Self {
+        Self {
+            id,
+            target,
+            action_recv,
+            state_send,
+            view: ViewTracker::default(),
+            capabilities: HashSet::new(),
+            ships_in_view: false,
+        }
+    }
+
+    /// Applies the target changes, any other action being refused.
+    pub(crate) fn handle_actions(&mut self) {
+        loop {
+            match self.action_recv.try_recv() {
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => unreachable!(),
+                Ok(Action::Watch(target)) => {
+                    spacebuild_log!(debug, "spectator", "{} now watches {:?}", self.id, target);
+                    self.target = target;
+                }
+                Ok(_) => self.send_error(ErrorCode::UnexpectedAction, "Spectators are read-only"),
+            }
+        }
+    }
+
+    pub(crate) fn send_notice(&mut self, message: &str) {
+        let sent = self.state_send.push(protocol::state::Game::Notice {
+            message: message.to_string(),
+        });
+        if !sent {
+            spacebuild_log!(warn, "spectator", "Failed to send notice to {}", self.id);
+        }
+    }
+
+    fn send_error(&mut self, code: ErrorCode, message: &str) {
+        let sent = self.state_send.push(protocol::state::Game::Error {
+            code,
+            message: message.to_string(),
+        });
+        if !sent {
+            spacebuild_log!(warn, "spectator", "Failed to send error to {}", self.id);
+        }
+    }
+
+    /// Sends what changed in the watched area since the previous update.
+    pub(crate) fn update(&mut self, time: f64, env: &[&Body], ships: Vec<protocol::state::Ship>) {
+        let mut messages = self.view.diff(env).into_messages(time, ENV_CHUNK_SIZE);
+        if self.capabilities.contains(protocol::CAPABILITY_SHIPS) && (!ships.is_empty() || self.ships_in_view) {
+            self.ships_in_view = !ships.is_empty();
+            messages.push(protocol::state::Game::Ships(ships));
+        }
+        for message in messages {
+            if !self.state_send.push(message) {
+                spacebuild_log!(warn, "spectator", "Failed to send env to {}", self.id);
+                return;
+            }
+        }
+    }
+}
@@ -1,8 +1,79 @@
 use crate::error::Error;
+use crate::migration::{Migration, Step};
 use crate::Result;
-use sqlx::sqlite::SqliteRow;
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteConnection, SqliteRow};
 use sqlx::{Pool, Sqlite};
 
+/// Host parameters SQLite accepts in a statement, `SQLITE_MAX_VARIABLE_NUMBER` since 3.32.
+const MAX_VARIABLES: usize = 32766;
+
+/// Value of a column, bound to its statement rather than written in the SQL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl SqlValue {
+    fn bind<'q>(&'q self, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+        match self {
+            SqlValue::Null => query.bind(None::<i64>),
+            SqlValue::Integer(value) => query.bind(*value),
+            SqlValue::Real(value) => query.bind(*value),
+            SqlValue::Text(value) => query.bind(value.as_str()),
+        }
+    }
+}
+
+impl From<u8> for SqlValue {
+    fn from(value: u8) -> Self {
+        SqlValue::Integer(value.into())
+    }
+}
+
+impl From<u32> for SqlValue {
+    fn from(value: u32) -> Self {
+        SqlValue::Integer(value.into())
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(value: f64) -> Self {
+        SqlValue::Real(value)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(value: &str) -> Self {
+        SqlValue::Text(value.to_string())
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(value: String) -> Self {
+        SqlValue::Text(value)
+    }
+}
+
+impl<T: Into<SqlValue>> From<Option<T>> for SqlValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(SqlValue::Null, Into::into)
+    }
+}
+
+/// Rows to insert in a table, see `SqlDb::write`.
+pub struct Rows<'a> {
+    pub table_name: &'a str,
+    pub columns: &'a [&'a str],
+    pub values: Vec<Vec<SqlValue>>,
+    /// Columns updated on an id conflict, as in `SqlDb::insert_rows_into`.
+    pub upserts: &'a [&'a str],
+}
+
+#[derive(Clone)]
 pub struct SqlDb {
     pool: Pool<Sqlite>,
 }
@@ -12,130 +83,291 @@ impl SqlDb {
         SqlDb { pool }
     }
 
-    pub async fn create_table(&mut self, name: &str, entries: Vec<&str>, indexes: Vec<&str>) -> Result<()> {
-        if self
-            .select_from_where_equals("sqlite_master ", "name", name)
+    /// Version of the schema, tracked from 0 in a new `schema_version` table for databases predating it.
+    pub async fn schema_version(&mut self) -> Result<u32> {
+        let error = |err| Error::DbCreateTableError("schema_version".to_string(), err);
+        sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .execute(&self.pool)
             .await
-            .len()
-            > 0
-        {
-            return Ok(());
-        }
-
-        let mut sql_str = format!("CREATE TABLE {} (", name);
-        for entry in entries {
-            sql_str += format!("{},", entry).as_str();
-        }
-        sql_str = sql_str.strip_suffix(",").unwrap().to_string();
-        sql_str += ");";
-
-        sqlx::query(&sql_str)
+            .map_err(error)?;
+        sqlx::query("INSERT INTO schema_version (version) SELECT 0 WHERE NOT EXISTS (SELECT * FROM schema_version)")
             .execute(&self.pool)
             .await
-            .map_err(|err| Error::DbCreateTableError(name.to_string(), err))?;
+            .map_err(error)?;
+        sqlx::query_scalar("SELECT version FROM schema_version")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(error)
+    }
 
-        for index in indexes {
-            sqlx::query(format!("CREATE INDEX {}_index_{} ON {} ({})", index, name, name, index).as_str())
-                .execute(&self.pool)
-                .await
-                .map_err(|err| Error::DbCreateTableError(name.to_string(), err))?;
+    /// Applies a migration and records its version, all or nothing.
+    pub async fn apply(&mut self, migration: &Migration) -> Result<()> {
+        let error = |err| Error::DbMigrationError(migration.version, err);
+        let mut transaction = self.pool.begin().await.map_err(error)?;
+        for step in migration.steps {
+            let sql_str = match step {
+                Step::Sql(sql_str) => sql_str.to_string(),
+                Step::Update { select, update, values } => {
+                    let rows = sqlx::query(select).fetch_all(&mut *transaction).await.map_err(error)?;
+                    for row in rows.iter() {
+                        let values = values(row).map_err(error)?;
+                        let mut query = sqlx::query(update);
+                        for value in values.iter() {
+                            query = value.bind(query);
+                        }
+                        query.execute(&mut *transaction).await.map_err(error)?;
+                    }
+                    continue;
+                }
+                Step::AddColumn(table_name, entry) => {
+                    let column_name = entry.split_whitespace().next().unwrap_or_default();
+                    let exists = !sqlx::query("SELECT name FROM pragma_table_info(?) WHERE name=?")
+                        .bind(table_name)
+                        .bind(column_name)
+                        .fetch_all(&mut *transaction)
+                        .await
+                        .map_err(error)?
+                        .is_empty();
+                    if exists {
+                        continue;
+                    }
+                    format!("ALTER TABLE {} ADD COLUMN {}", table_name, entry)
+                }
+            };
+            sqlx::query(&sql_str).execute(&mut *transaction).await.map_err(error)?;
         }
+        sqlx::query("UPDATE schema_version SET version=?")
+            .bind(migration.version)
+            .execute(&mut *transaction)
+            .await
+            .map_err(error)?;
+        transaction.commit().await.map_err(error)
+    }
+
+    /// Updates the rows where `where_column` is `where_value` ignoring ASCII case.
+    pub async fn update_where_nocase(
+        &self,
+        table_name: &str,
+        column_name: &str,
+        value: &str,
+        where_column: &str,
+        where_value: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            format!(
+                "UPDATE {} SET {}=? WHERE {}=? COLLATE NOCASE",
+                table_name, column_name, where_column
+            )
+            .as_str(),
+        )
+        .bind(value)
+        .bind(where_value)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| Error::DbUpdateError(table_name.to_string(), where_column.to_string(), err))?;
         Ok(())
     }
-    pub async fn select_from_where_equals(&self, table_name: &str, column_name: &str, value: &str) -> Vec<SqliteRow> {
+
+    pub async fn select_from_where_equals(
+        &self,
+        table_name: &str,
+        column_name: &str,
+        value: &str,
+    ) -> Result<Vec<SqliteRow>> {
         sqlx::query(format!("SELECT * FROM {} WHERE {}=?", table_name, column_name).as_str())
             .bind(value)
             .fetch_all(&self.pool)
             .await
-            .unwrap()
+            .map_err(|err| Error::DbSelectFromWhereError(table_name.to_string(), column_name.to_string(), err))
     }
 
-    pub async fn select_from_where_like(&self, table_name: &str, column_name: &str, value: &str) -> Vec<SqliteRow> {
-        sqlx::query(format!("SELECT * FROM {} WHERE {} LIKE ?", table_name, column_name).as_str())
-            .bind(value)
+    /// Selects named columns only, a `SELECT *` prepared on a pooled connection missing a new column reading it wrong.
+    ///
+    /// Rows match where `column_name` is `value` ignoring ASCII case, with no wildcard unlike `LIKE`.
+    pub async fn select_columns_from_where_nocase(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+        column_name: &str,
+        value: &str,
+    ) -> Result<Vec<SqliteRow>> {
+        sqlx::query(
+            format!(
+                "SELECT {} FROM {} WHERE {}=? COLLATE NOCASE",
+                columns.join(", "),
+                table_name,
+                column_name
+            )
+            .as_str(),
+        )
+        .bind(value)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| Error::DbSelectFromWhereError(table_name.to_string(), column_name.to_string(), err))
+    }
+
+    pub async fn select_columns_from_where_equals(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+        column_name: &str,
+        value: &str,
+    ) -> Result<Vec<SqliteRow>> {
+        sqlx::query(
+            format!(
+                "SELECT {} FROM {} WHERE {}=?",
+                columns.join(", "),
+                table_name,
+                column_name
+            )
+            .as_str(),
+        )
+        .bind(value)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| Error::DbSelectFromWhereError(table_name.to_string(), column_name.to_string(), err))
+    }
+
+    pub async fn select_columns_from(&self, table_name: &str, columns: &[&str]) -> Result<Vec<SqliteRow>> {
+        sqlx::query(format!("SELECT {} FROM {}", columns.join(", "), table_name).as_str())
             .fetch_all(&self.pool)
             .await
-            .unwrap()
+            .map_err(|err| Error::DbSelectError(table_name.to_string(), err))
     }
 
-    fn vec_to_insert_str(
-        table_name: &str,
-        columns: Option<Vec<String>>,
-        values: Vec<Vec<String>>,
-        upserts: Vec<(&str, &str)>,
-    ) -> String {
-        let mut insert_sql_str = format!("INSERT INTO {} ", table_name);
-
-        if columns.is_some() {
-            let columns = columns.unwrap();
-            if !columns.is_empty() {
-                insert_sql_str += "(";
-                for column in columns {
-                    insert_sql_str += format!("{}, ", column).as_str();
-                }
-                insert_sql_str = insert_sql_str.strip_suffix(", ").unwrap().to_string();
-                insert_sql_str += ") ";
-            }
-        }
+    pub async fn select_all_from(&self, table_name: &str) -> Result<Vec<SqliteRow>> {
+        sqlx::query(format!("SELECT * FROM {}", table_name).as_str())
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| Error::DbSelectError(table_name.to_string(), err))
+    }
 
-        insert_sql_str += "VALUES ";
+    /// Highest value of `column_name`, 0 for an empty table.
+    pub async fn select_max_from(&self, table_name: &str, column_name: &str) -> Result<u32> {
+        let max: Option<u32> = sqlx::query_scalar(format!("SELECT MAX({}) FROM {}", column_name, table_name).as_str())
+            .fetch_one(&self.pool)
+            .await
+            .map_err(Error::DbLastIdError)?;
+        Ok(max.unwrap_or(0))
+    }
 
-        for line in values {
-            insert_sql_str += "(";
-            for value in line {
-                insert_sql_str += value.as_str();
-                insert_sql_str += ",";
-            }
-            insert_sql_str = insert_sql_str.strip_suffix(",").unwrap().to_string();
-            insert_sql_str += "),";
-        }
+    /// Deletes the rows where `column_name` is `value`, returning how many were.
+    pub async fn delete_from_where_equals(&self, table_name: &str, column_name: &str, value: &str) -> Result<u64> {
+        self.delete_where(table_name, column_name, value, "").await
+    }
 
-        insert_sql_str = insert_sql_str.strip_suffix(",").unwrap().to_string();
+    /// Deletes the rows where `column_name` is `value` ignoring ASCII case, returning how many were.
+    pub async fn delete_from_where_nocase(&self, table_name: &str, column_name: &str, value: &str) -> Result<u64> {
+        self.delete_where(table_name, column_name, value, " COLLATE NOCASE")
+            .await
+    }
 
-        if !upserts.is_empty() {
-            insert_sql_str += "ON CONFLICT(id) DO UPDATE SET ";
-
-            for upsert in upserts {
-                insert_sql_str += upsert.0;
-                insert_sql_str += "=";
-                insert_sql_str += "excluded.";
-                insert_sql_str += upsert.1;
-                insert_sql_str += ",";
-            }
+    async fn delete_where(&self, table_name: &str, column_name: &str, value: &str, collate: &str) -> Result<u64> {
+        let result = sqlx::query(format!("DELETE FROM {} WHERE {}=?{}", table_name, column_name, collate).as_str())
+            .bind(value)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| Error::DbUpdateError(table_name.to_string(), column_name.to_string(), err))?;
+        Ok(result.rows_affected())
+    }
 
-            insert_sql_str.strip_suffix(",").unwrap().to_string()
-        } else {
-            insert_sql_str
+    fn insert_str(table_name: &str, columns: &[&str], rows: usize, upserts: &[&str]) -> String {
+        let row = format!("({})", vec!["?"; columns.len()].join(", "));
+        let mut insert_sql_str = format!(
+            "INSERT INTO {} ({}) VALUES {}",
+            table_name,
+            columns.join(", "),
+            vec![row; rows].join(", ")
+        );
+        if !upserts.is_empty() {
+            let updates: Vec<String> = upserts
+                .iter()
+                .map(|column| format!("{}=excluded.{}", column, column))
+                .collect();
+            insert_sql_str += format!(" ON CONFLICT(id) DO UPDATE SET {}", updates.join(", ")).as_str();
         }
+        insert_sql_str
     }
 
+    /// Inserts a row, returning its id, see `insert_rows_into`.
     pub async fn insert_row_into(
-        &mut self,
+        &self,
         table_name: &str,
-        columns: Option<Vec<String>>,
-        row: Vec<String>,
-        upserts: Vec<(&str, &str)>,
-    ) -> u32 {
-        let insert_sql_str = Self::vec_to_insert_str(table_name, columns, vec![row], upserts);
-
-        sqlx::query(&insert_sql_str)
-            .execute(&self.pool)
-            .await
-            .unwrap()
-            .last_insert_rowid() as u32
+        columns: &[&str],
+        row: Vec<SqlValue>,
+        upserts: &[&str],
+    ) -> Result<u32> {
+        self.insert_rows_into(table_name, columns, vec![row], upserts).await
     }
 
+    /// Inserts rows with every value bound rather than written in the SQL, returning the id of the last one.
+    ///
+    /// Rows are inserted in a transaction, in batches within the placeholders SQLite allows per statement. On an id
+    /// conflict, the `upserts` columns of the existing row are updated instead.
     pub async fn insert_rows_into(
         &self,
         table_name: &str,
-        columns: Option<Vec<String>>,
-        values: Vec<Vec<String>>,
-        upserts: Vec<(&str, &str)>,
-    ) -> u32 {
-        sqlx::query(&Self::vec_to_insert_str(table_name, columns, values, upserts))
-            .execute(&self.pool)
+        columns: &[&str],
+        rows: Vec<Vec<SqlValue>>,
+        upserts: &[&str],
+    ) -> Result<u32> {
+        let mut transaction = self.pool.begin().await.map_err(Error::DbTransactionError)?;
+        let last_id = Self::insert_batches(&mut transaction, table_name, columns, &rows, upserts).await?;
+        transaction.commit().await.map_err(Error::DbTransactionError)?;
+        Ok(last_id)
+    }
+
+    /// Inserts the rows of several tables in a single transaction, none being written if any fails.
+    ///
+    /// Foreign keys are checked at commit, rows being split in batches in any order, children before their parents.
+    pub async fn write(&self, writes: &[Rows<'_>]) -> Result<()> {
+        let mut transaction = self.pool.begin().await.map_err(Error::DbTransactionError)?;
+        sqlx::query("PRAGMA defer_foreign_keys = ON")
+            .execute(&mut *transaction)
             .await
-            .unwrap()
-            .last_insert_rowid() as u32
+            .map_err(Error::DbTransactionError)?;
+        for rows in writes.iter().filter(|rows| !rows.values.is_empty()) {
+            Self::insert_batches(
+                &mut transaction,
+                rows.table_name,
+                rows.columns,
+                &rows.values,
+                rows.upserts,
+            )
+            .await?;
+        }
+        transaction.commit().await.map_err(Error::DbTransactionError)
+    }
+
+    async fn insert_batches(
+        connection: &mut SqliteConnection,
+        table_name: &str,
+        columns: &[&str],
+        rows: &[Vec<SqlValue>],
+        upserts: &[&str],
+    ) -> Result<u32> {
+        let mut last_id = 0;
+        for batch in rows.chunks((MAX_VARIABLES / columns.len().max(1)).max(1)) {
+            let sql_str = Self::insert_str(table_name, columns, batch.len(), upserts);
+            let mut query = sqlx::query(&sql_str);
+            for row in batch {
+                assert_eq!(
+                    columns.len(),
+                    row.len(),
+                    "Row of {} values for {} columns",
+                    row.len(),
+                    columns.len()
+                );
+                for value in row {
+                    query = value.bind(query);
+                }
+            }
+            last_id = query
+                .execute(&mut *connection)
+                .await
+                .map_err(|err| Error::SqlDbInsertError(table_name.to_string(), err))?
+                .last_insert_rowid() as u32;
+        }
+        Ok(last_id)
     }
 }
@@ -0,0 +1,431 @@
+use crate::audit::AuditEntry;
+use crate::ban::{parse_address, Ban};
+use crate::body::Body;
+use crate::error::Error;
+use crate::migration;
+use crate::orbit::Orbit;
+use crate::protocol::Role;
+use crate::sqldb::{Rows, SqlDb, SqlValue};
+use crate::storage::{Account, PlayerState, Snapshot, Storage};
+use crate::Result;
+use futures::future::BoxFuture;
+use ipnet::IpNet;
+use scilib::coordinate::cartesian::Cartesian;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow, SqliteSynchronous};
+use sqlx::{Row, SqlitePool};
+use std::fs::File;
+use std::path::Path;
+
+const BODY_COLUMNS: [&str; 14] = [
+    "id",
+    "type",
+    "coord_x",
+    "coord_y",
+    "coord_z",
+    "rotating_speed",
+    "gravity_center",
+    "mass",
+    "semi_major_axis",
+    "eccentricity",
+    "inclination",
+    "ascending_node",
+    "arg_periapsis",
+    "mean_anomaly_epoch",
+];
+
+/// Columns saved with the player, the password hash and the role being updated on their own.
+const PLAYER_COLUMNS: [&str; 12] = [
+    "id",
+    "nickname",
+    "coord_x",
+    "coord_y",
+    "coord_z",
+    "velocity_x",
+    "velocity_y",
+    "velocity_z",
+    "direction_x",
+    "direction_y",
+    "direction_z",
+    "current_system",
+];
+
+const ACCOUNT_COLUMNS: [&str; 14] = [
+    "id",
+    "nickname",
+    "coord_x",
+    "coord_y",
+    "coord_z",
+    "velocity_x",
+    "velocity_y",
+    "velocity_z",
+    "direction_x",
+    "direction_y",
+    "direction_z",
+    "current_system",
+    "password_hash",
+    "role",
+];
+
+const BAN_COLUMNS: [&str; 7] = [
+    "id",
+    "nickname",
+    "address",
+    "reason",
+    "banned_by",
+    "banned_at",
+    "expires_at",
+];
+
+const AUDIT_COLUMNS: [&str; 4] = ["time", "actor", "command", "outcome"];
+
+/// Single row of the `Galaxy` table, holding the galaxy clock.
+const GALAXY_ID: u32 = 1;
+const GALAXY_COLUMNS: [&str; 2] = ["id", "time"];
+
+/// Row of `body`, a gravity center of 0 being stored as `NULL` for the foreign key to hold.
+fn body_row(body: &Body) -> Vec<SqlValue> {
+    vec![
+        body.id.into(),
+        body.body_type.into(),
+        body.coords.x.into(),
+        body.coords.y.into(),
+        body.coords.z.into(),
+        body.rotating_speed.into(),
+        (body.gravity_center != 0).then_some(body.gravity_center).into(),
+        body.mass.into(),
+        body.orbit.semi_major_axis.into(),
+        body.orbit.eccentricity.into(),
+        body.orbit.inclination.into(),
+        body.orbit.ascending_node.into(),
+        body.orbit.arg_periapsis.into(),
+        body.orbit.mean_anomaly_epoch.into(),
+    ]
+}
+
+/// Body of a row, the columns not written yet, as when a new body was not saved, reading as 0.
+fn body_from_row(row: &SqliteRow) -> Result<Body> {
+    let get = |column| {
+        row.try_get::<Option<f64>, _>(column)
+            .map(Option::unwrap_or_default)
+            .map_err(Error::DbLoadError)
+    };
+    let gravity_center: Option<u32> = row.try_get("gravity_center").map_err(Error::DbLoadError)?;
+    Ok(Body {
+        id: row.try_get("id").map_err(Error::DbLoadError)?,
+        body_type: row.try_get("type").map_err(Error::DbLoadError)?,
+        coords: Cartesian {
+            x: get("coord_x")?,
+            y: get("coord_y")?,
+            z: get("coord_z")?,
+        },
+        rotating_speed: get("rotating_speed")?,
+        gravity_center: gravity_center.unwrap_or_default(),
+        mass: get("mass")?,
+        orbit: Orbit {
+            semi_major_axis: get("semi_major_axis")?,
+            eccentricity: get("eccentricity")?,
+            inclination: get("inclination")?,
+            ascending_node: get("ascending_node")?,
+            arg_periapsis: get("arg_periapsis")?,
+            mean_anomaly_epoch: get("mean_anomaly_epoch")?,
+        },
+    })
+}
+
+fn player_row(state: &PlayerState) -> Vec<SqlValue> {
+    vec![
+        state.id.into(),
+        state.nickname.as_str().into(),
+        state.coords.x.into(),
+        state.coords.y.into(),
+        state.coords.z.into(),
+        state.velocity.x.into(),
+        state.velocity.y.into(),
+        state.velocity.z.into(),
+        state.direction.x.into(),
+        state.direction.y.into(),
+        state.direction.z.into(),
+        state.current_system.into(),
+    ]
+}
+
+/// Account of a row, the state of a new player not saved yet reading as 0.
+fn account_from_row(row: &SqliteRow) -> Result<Account> {
+    let get = |column| {
+        row.try_get::<Option<f64>, _>(column)
+            .map(Option::unwrap_or_default)
+            .map_err(Error::DbLoadError)
+    };
+    let role: Option<String> = row.try_get("role").map_err(Error::DbLoadError)?;
+    let current_system: Option<u32> = row.try_get("current_system").map_err(Error::DbLoadError)?;
+    Ok(Account {
+        state: PlayerState {
+            id: row.try_get("id").map_err(Error::DbLoadError)?,
+            nickname: row.try_get("nickname").map_err(Error::DbLoadError)?,
+            coords: Cartesian {
+                x: get("coord_x")?,
+                y: get("coord_y")?,
+                z: get("coord_z")?,
+            },
+            velocity: Cartesian {
+                x: get("velocity_x")?,
+                y: get("velocity_y")?,
+                z: get("velocity_z")?,
+            },
+            direction: Cartesian {
+                x: get("direction_x")?,
+                y: get("direction_y")?,
+                z: get("direction_z")?,
+            },
+            current_system: current_system.unwrap_or_default(),
+        },
+        password_hash: row.try_get("password_hash").map_err(Error::DbLoadError)?,
+        role: role.and_then(|role| role.parse().ok()).unwrap_or_default(),
+    })
+}
+
+fn ban_row(ban: &Ban) -> Vec<SqlValue> {
+    vec![
+        ban.nickname.clone().into(),
+        ban.address.map(|address| address.to_string()).into(),
+        ban.reason.as_str().into(),
+        ban.banned_by.as_str().into(),
+        ban.banned_at.into(),
+        ban.expires_at.into(),
+    ]
+}
+
+fn ban_from_row(row: &SqliteRow) -> Result<Ban> {
+    let address: Option<String> = row.try_get("address").map_err(Error::DbLoadError)?;
+    Ok(Ban {
+        id: row.try_get("id").map_err(Error::DbLoadError)?,
+        nickname: row.try_get("nickname").map_err(Error::DbLoadError)?,
+        address: address.as_deref().map(parse_address).transpose()?,
+        reason: row.try_get("reason").map_err(Error::DbLoadError)?,
+        banned_by: row.try_get("banned_by").map_err(Error::DbLoadError)?,
+        banned_at: row.try_get("banned_at").map_err(Error::DbLoadError)?,
+        expires_at: row.try_get("expires_at").map_err(Error::DbLoadError)?,
+    })
+}
+
+fn audit_from_row(row: &SqliteRow) -> Result<AuditEntry> {
+    let command: String = row.try_get("command").map_err(Error::DbLoadError)?;
+    Ok(AuditEntry {
+        time: row.try_get("time").map_err(Error::DbLoadError)?,
+        actor: row.try_get("actor").map_err(Error::DbLoadError)?,
+        command: serde_json::from_str(&command).map_err(|err| Error::DeserializeError(command.clone(), err))?,
+        outcome: row.try_get("outcome").map_err(Error::DbLoadError)?,
+    })
+}
+
+/// Storage in a SQLite database, the default.
+pub struct SqlStorage {
+    db: SqlDb,
+}
+
+impl SqlStorage {
+    /// Opens the database at `db_path`, creating it if needed, and brings it to the schema of this build.
+    pub async fn open(db_path: &str) -> Result<Self> {
+        if !Path::new(db_path).exists() {
+            File::create(db_path).map_err(Error::DbFileCreationError)?;
+        }
+
+        // Write-ahead logging, for the saves not to block the reads.
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .map_err(|err| Error::DbOpenError(db_path.to_string(), err))?;
+
+        let mut db = SqlDb::new(pool);
+        migration::migrate(&mut db).await?;
+        Ok(Self { db })
+    }
+}
+
+impl Storage for SqlStorage {
+    fn last_body_id(&self) -> BoxFuture<'_, Result<u32>> {
+        Box::pin(async move { self.db.select_max_from("Body", "id").await })
+    }
+
+    fn load_body(&self, id: u32) -> BoxFuture<'_, Result<Option<Body>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from_where_equals("Body", &BODY_COLUMNS, "id", &id.to_string())
+                .await?
+                .first()
+                .map(body_from_row)
+                .transpose()
+        })
+    }
+
+    fn load_gravitings(&self, id: u32) -> BoxFuture<'_, Result<Vec<Body>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from_where_equals("Body", &BODY_COLUMNS, "gravity_center", &id.to_string())
+                .await?
+                .iter()
+                .map(body_from_row)
+                .collect()
+        })
+    }
+
+    fn find_player<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from_where_nocase("Player", &ACCOUNT_COLUMNS, "nickname", nickname)
+                .await?
+                .first()
+                .map(account_from_row)
+                .transpose()
+        })
+    }
+
+    fn insert_player<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<u32>> {
+        Box::pin(async move {
+            self.db
+                .insert_row_into(
+                    "Player",
+                    &["nickname", "password_hash"],
+                    vec![nickname.into(), password_hash.into()],
+                    &[],
+                )
+                .await
+        })
+    }
+
+    fn set_password_hash<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.db
+                .update_where_nocase("Player", "password_hash", password_hash, "nickname", nickname)
+                .await
+        })
+    }
+
+    fn set_role<'a>(&'a self, nickname: &'a str, role: Role) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.db
+                .update_where_nocase("Player", "role", role.as_str(), "nickname", nickname)
+                .await
+        })
+    }
+
+    fn write<'a>(&'a self, snapshots: &'a [Snapshot]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut writes: Vec<_> = snapshots
+                .iter()
+                .flat_map(|snapshot| {
+                    [
+                        Rows {
+                            table_name: "Body",
+                            columns: &BODY_COLUMNS,
+                            values: snapshot.bodies.iter().map(body_row).collect(),
+                            upserts: &BODY_COLUMNS[1..],
+                        },
+                        Rows {
+                            table_name: "Player",
+                            columns: &PLAYER_COLUMNS,
+                            values: snapshot.players.iter().map(player_row).collect(),
+                            upserts: &PLAYER_COLUMNS[1..],
+                        },
+                    ]
+                })
+                .collect();
+            let time = snapshots.iter().rev().find_map(|snapshot| snapshot.time);
+            if let Some(time) = time {
+                writes.push(Rows {
+                    table_name: "Galaxy",
+                    columns: &GALAXY_COLUMNS,
+                    values: vec![vec![GALAXY_ID.into(), time.into()]],
+                    upserts: &GALAXY_COLUMNS[1..],
+                });
+            }
+            self.db.write(&writes).await
+        })
+    }
+
+    fn galaxy_time(&self) -> BoxFuture<'_, Result<f64>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from_where_equals("Galaxy", &GALAXY_COLUMNS, "id", &GALAXY_ID.to_string())
+                .await?
+                .first()
+                .map_or(Ok(0f64), |row| row.try_get("time").map_err(Error::DbLoadError))
+        })
+    }
+
+    fn insert_ban<'a>(&'a self, ban: &'a Ban) -> BoxFuture<'a, Result<u32>> {
+        Box::pin(async move {
+            self.db
+                .insert_row_into("Ban", &BAN_COLUMNS[1..], ban_row(ban), &[])
+                .await
+        })
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<Ban>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from("Ban", &BAN_COLUMNS)
+                .await?
+                .iter()
+                .map(ban_from_row)
+                .collect()
+        })
+    }
+
+    fn bans_of<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Vec<Ban>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from_where_nocase("Ban", &BAN_COLUMNS, "nickname", nickname)
+                .await?
+                .iter()
+                .map(ban_from_row)
+                .collect()
+        })
+    }
+
+    fn delete_bans<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move { self.db.delete_from_where_nocase("Ban", "nickname", nickname).await })
+    }
+
+    fn delete_address_bans(&self, address: IpNet) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(async move {
+            self.db
+                .delete_from_where_equals("Ban", "address", &address.to_string())
+                .await
+        })
+    }
+
+    fn insert_audit<'a>(&'a self, entry: &'a AuditEntry) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let command = serde_json::to_string(&entry.command).map_err(Error::SerializeError)?;
+            self.db
+                .insert_row_into(
+                    "Audit",
+                    &AUDIT_COLUMNS,
+                    vec![
+                        entry.time.into(),
+                        entry.actor.as_str().into(),
+                        command.into(),
+                        entry.outcome.as_str().into(),
+                    ],
+                    &[],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn audit_entries(&self) -> BoxFuture<'_, Result<Vec<AuditEntry>>> {
+        Box::pin(async move {
+            self.db
+                .select_columns_from("Audit", &AUDIT_COLUMNS)
+                .await?
+                .iter()
+                .map(audit_from_row)
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,281 @@
+use crate::audit::AuditEntry;
+use crate::ban::Ban;
+use crate::body::Body;
+use crate::protocol::Role;
+use crate::Result;
+use futures::future::BoxFuture;
+use ipnet::IpNet;
+use scilib::coordinate::cartesian::Cartesian;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// State of a player written at each save, its account details being changed on their own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerState {
+    pub id: u32,
+    pub nickname: String,
+    pub coords: Cartesian,
+    pub velocity: Cartesian,
+    pub direction: Cartesian,
+    pub current_system: u32,
+}
+
+impl PlayerState {
+    fn new(id: u32, nickname: &str) -> Self {
+        Self {
+            id,
+            nickname: nickname.to_string(),
+            coords: Cartesian::default(),
+            velocity: Cartesian::default(),
+            direction: Cartesian::default(),
+            current_system: 0,
+        }
+    }
+}
+
+/// Player as stored, `password_hash` being `None` for accounts created before passwords.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub state: PlayerState,
+    pub password_hash: Option<String>,
+    pub role: Role,
+}
+
+/// Changed bodies and players, taken from the caches under the instance lock.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub bodies: Vec<Body>,
+    pub players: Vec<PlayerState>,
+    /// Galaxy time the snapshot was taken at, `None` leaving the stored clock as it is.
+    pub time: Option<f64>,
+}
+
+impl Snapshot {
+    /// Bodies and players in the snapshot.
+    pub fn len(&self) -> usize {
+        self.bodies.len() + self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && self.time.is_none()
+    }
+}
+
+/// Where an instance keeps its galaxy, players, bans and audit log.
+///
+/// Nicknames are matched ignoring ASCII case. Futures are boxed for instances to hold any backend.
+pub trait Storage: Send + Sync {
+    /// Highest id of the stored bodies, 0 if there are none, new bodies being written with the ids following it.
+    fn last_body_id(&self) -> BoxFuture<'_, Result<u32>>;
+
+    fn load_body(&self, id: u32) -> BoxFuture<'_, Result<Option<Body>>>;
+
+    /// Bodies whose gravity center is `id`, including `id` itself if it is a star.
+    fn load_gravitings(&self, id: u32) -> BoxFuture<'_, Result<Vec<Body>>>;
+
+    fn find_player<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Option<Account>>>;
+
+    /// Creates an account, returning the id of its player.
+    fn insert_player<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<u32>>;
+
+    fn set_password_hash<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    fn set_role<'a>(&'a self, nickname: &'a str, role: Role) -> BoxFuture<'a, Result<()>>;
+
+    /// Writes the snapshots in order, all or nothing.
+    fn write<'a>(&'a self, snapshots: &'a [Snapshot]) -> BoxFuture<'a, Result<()>>;
+
+    /// Galaxy time of the latest snapshot written with one, 0 for a new galaxy.
+    fn galaxy_time(&self) -> BoxFuture<'_, Result<f64>>;
+
+    /// Stores a ban, returning its id.
+    fn insert_ban<'a>(&'a self, ban: &'a Ban) -> BoxFuture<'a, Result<u32>>;
+
+    /// Every ban, expired ones included, oldest first.
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<Ban>>>;
+
+    /// Bans of `nickname`, oldest first.
+    fn bans_of<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Vec<Ban>>>;
+
+    /// Deletes the bans of `nickname`, returning how many there were.
+    fn delete_bans<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<u64>>;
+
+    /// Deletes the bans of exactly `address`, returning how many there were.
+    fn delete_address_bans(&self, address: IpNet) -> BoxFuture<'_, Result<u64>>;
+
+    fn insert_audit<'a>(&'a self, entry: &'a AuditEntry) -> BoxFuture<'a, Result<()>>;
+
+    /// Every entry, oldest first.
+    fn audit_entries(&self) -> BoxFuture<'_, Result<Vec<AuditEntry>>>;
+}
+
+#[derive(Default)]
+struct Memory {
+    bodies: BTreeMap<u32, Body>,
+    players: BTreeMap<u32, Account>,
+    bans: BTreeMap<u32, Ban>,
+    audit: Vec<AuditEntry>,
+    time: f64,
+}
+
+impl Memory {
+    fn find_player(&mut self, nickname: &str) -> Option<&mut Account> {
+        self.players
+            .values_mut()
+            .find(|account| account.state.nickname.eq_ignore_ascii_case(nickname))
+    }
+}
+
+/// Storage lost when dropped, for tests and ephemeral servers.
+#[derive(Default)]
+pub struct MemoryStorage {
+    memory: Mutex<Memory>,
+}
+
+impl MemoryStorage {
+    fn with<T: Send + 'static>(&self, f: impl FnOnce(&mut Memory) -> T) -> BoxFuture<'_, Result<T>> {
+        let result = f(&mut self.memory.lock().unwrap());
+        Box::pin(async move { Ok(result) })
+    }
+}
+
+fn next_id<T>(map: &BTreeMap<u32, T>) -> u32 {
+    map.keys().next_back().map_or(1, |id| id + 1)
+}
+
+impl Storage for MemoryStorage {
+    fn last_body_id(&self) -> BoxFuture<'_, Result<u32>> {
+        self.with(|memory| memory.bodies.keys().next_back().copied().unwrap_or(0))
+    }
+
+    fn load_body(&self, id: u32) -> BoxFuture<'_, Result<Option<Body>>> {
+        self.with(|memory| memory.bodies.get(&id).cloned())
+    }
+
+    fn load_gravitings(&self, id: u32) -> BoxFuture<'_, Result<Vec<Body>>> {
+        self.with(|memory| {
+            memory
+                .bodies
+                .values()
+                .filter(|body| body.gravity_center == id)
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn find_player<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        self.with(|memory| memory.find_player(nickname).cloned())
+    }
+
+    fn insert_player<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<u32>> {
+        self.with(|memory| {
+            let id = next_id(&memory.players);
+            let account = Account {
+                state: PlayerState::new(id, nickname),
+                password_hash: Some(password_hash.to_string()),
+                role: Role::default(),
+            };
+            memory.players.insert(id, account);
+            id
+        })
+    }
+
+    fn set_password_hash<'a>(&'a self, nickname: &'a str, password_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.with(|memory| {
+            if let Some(account) = memory.find_player(nickname) {
+                account.password_hash = Some(password_hash.to_string());
+            }
+        })
+    }
+
+    fn set_role<'a>(&'a self, nickname: &'a str, role: Role) -> BoxFuture<'a, Result<()>> {
+        self.with(|memory| {
+            if let Some(account) = memory.find_player(nickname) {
+                account.role = role;
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, snapshots: &'a [Snapshot]) -> BoxFuture<'a, Result<()>> {
+        self.with(|memory| {
+            for snapshot in snapshots {
+                for body in &snapshot.bodies {
+                    memory.bodies.insert(body.id, body.clone());
+                }
+                for state in &snapshot.players {
+                    memory
+                        .players
+                        .entry(state.id)
+                        .and_modify(|account| account.state = state.clone())
+                        .or_insert_with(|| Account {
+                            state: state.clone(),
+                            password_hash: None,
+                            role: Role::default(),
+                        });
+                }
+                if let Some(time) = snapshot.time {
+                    memory.time = time;
+                }
+            }
+        })
+    }
+
+    fn galaxy_time(&self) -> BoxFuture<'_, Result<f64>> {
+        self.with(|memory| memory.time)
+    }
+
+    fn insert_ban<'a>(&'a self, ban: &'a Ban) -> BoxFuture<'a, Result<u32>> {
+        self.with(|memory| {
+            let id = next_id(&memory.bans);
+            memory.bans.insert(id, Ban { id, ..ban.clone() });
+            id
+        })
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<Ban>>> {
+        self.with(|memory| memory.bans.values().cloned().collect())
+    }
+
+    fn bans_of<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<Vec<Ban>>> {
+        self.with(|memory| {
+            memory
+                .bans
+                .values()
+                .filter(|ban| {
+                    ban.nickname
+                        .as_ref()
+                        .is_some_and(|banned| banned.eq_ignore_ascii_case(nickname))
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn delete_bans<'a>(&'a self, nickname: &'a str) -> BoxFuture<'a, Result<u64>> {
+        self.with(|memory| {
+            let count = memory.bans.len();
+            memory.bans.retain(|_, ban| {
+                !ban.nickname
+                    .as_ref()
+                    .is_some_and(|banned| banned.eq_ignore_ascii_case(nickname))
+            });
+            (count - memory.bans.len()) as u64
+        })
+    }
+
+    fn delete_address_bans(&self, address: IpNet) -> BoxFuture<'_, Result<u64>> {
+        self.with(|memory| {
+            let count = memory.bans.len();
+            memory.bans.retain(|_, ban| ban.address != Some(address));
+            (count - memory.bans.len()) as u64
+        })
+    }
+
+    fn insert_audit<'a>(&'a self, entry: &'a AuditEntry) -> BoxFuture<'a, Result<()>> {
+        self.with(|memory| memory.audit.push(entry.clone()))
+    }
+
+    fn audit_entries(&self) -> BoxFuture<'_, Result<Vec<AuditEntry>>> {
+        self.with(|memory| memory.audit.clone())
+    }
+}
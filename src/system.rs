@@ -0,0 +1,172 @@
+use crate::body::Body;
+use crate::galaxy::REINDEX_PERIOD;
+use rstar::{RTree, RTreeObject, AABB};
+use scilib::coordinate::cartesian::Cartesian;
+use std::collections::{HashMap, HashSet};
+
+struct IndexedBody {
+    id: u32,
+    coords: [f64; 3],
+}
+
+impl RTreeObject for IndexedBody {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.coords)
+    }
+}
+
+/// A star and everything orbiting it, directly or not.
+///
+/// Bodies are stored with their orbital elements only: positions are a pure function of the galaxy time
+/// and are evaluated on demand. The spatial index holds positions at `indexed_at` and is queried with a
+/// margin covering the distance any body may have travelled since then.
+pub struct StarSystem {
+    pub(crate) star_id: u32,
+    pub(crate) bodies: HashMap<u32, Body>,
+    pub(crate) players: HashSet<u32>,
+    index: RTree<IndexedBody>,
+    indexed_at: f64,
+    max_speed: f64,
+    radius: f64,
+}
+
+impl StarSystem {
+    pub fn new(star: Body, time: f64) -> Self {
+        let star_id = star.id;
+        let coords = star.coords;
+        let mut index = RTree::new();
+        index.insert(IndexedBody {
+            id: star_id,
+            coords: [coords.x, coords.y, coords.z],
+        });
+        Self {
+            star_id,
+            bodies: HashMap::from([(star_id, star)]),
+            players: HashSet::new(),
+            index,
+            indexed_at: time,
+            max_speed: 0f64,
+            radius: 0f64,
+        }
+    }
+
+    pub fn star_id(&self) -> u32 {
+        self.star_id
+    }
+
+    pub fn star_coords(&self) -> Cartesian {
+        self.bodies.get(&self.star_id).unwrap().coords
+    }
+
+    /// Distance from the star beyond which no body of the system can ever be.
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.bodies.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bodies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bodies.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, body: Body, time: f64) -> bool {
+        if !self.bodies.contains_key(&body.gravity_center) {
+            return false;
+        }
+        let id = body.id;
+        self.bodies.insert(id, body);
+        self.max_speed = self.max_speed.max(self.max_speed_of(id));
+        self.radius = self.radius.max(self.max_distance_of(id));
+        if let Some(coords) = self.position_of(id, time) {
+            self.index.insert(IndexedBody {
+                id,
+                coords: [coords.x, coords.y, coords.z],
+            });
+        }
+        true
+    }
+
+    pub fn position_of(&self, id: u32, time: f64) -> Option<Cartesian> {
+        let body = self.bodies.get(&id)?;
+        if !body.is_orbiting() {
+            return Some(body.coords);
+        }
+        Some(self.position_of(body.gravity_center, time)? + body.local_position(time))
+    }
+
+    pub fn body(&self, id: u32, time: f64) -> Option<Body> {
+        let mut body = self.bodies.get(&id)?.clone();
+        body.coords = self.position_of(id, time)?;
+        Some(body)
+    }
+
+    pub fn bodies_at(&self, time: f64) -> Vec<Body> {
+        self.bodies.keys().filter_map(|id| self.body(*id, time)).collect()
+    }
+
+    fn max_speed_of(&self, id: u32) -> f64 {
+        match self.bodies.get(&id) {
+            Some(body) if body.is_orbiting() => {
+                body.orbit.max_speed(body.rotating_speed) + self.max_speed_of(body.gravity_center)
+            }
+            _ => 0f64,
+        }
+    }
+
+    fn max_distance_of(&self, id: u32) -> f64 {
+        match self.bodies.get(&id) {
+            Some(body) if body.is_orbiting() => {
+                body.orbit.semi_major_axis * (1f64 + body.orbit.eccentricity)
+                    + self.max_distance_of(body.gravity_center)
+            }
+            _ => 0f64,
+        }
+    }
+
+    pub fn view(&self, center: Cartesian, radius: f64, time: f64) -> Vec<Body> {
+        let radius_sq = radius * radius;
+        let margin = radius + self.max_speed * (time - self.indexed_at);
+        let min = [center.x - margin, center.y - margin, center.z - margin];
+        let max = [center.x + margin, center.y + margin, center.z + margin];
+        self.index
+            .locate_in_envelope_intersecting(&AABB::from_corners(min, max))
+            .filter_map(|indexed| self.body(indexed.id, time))
+            .filter(|g| {
+                let d_sq =
+                    (g.coords.x - center.x).powi(2) + (g.coords.y - center.y).powi(2) + (g.coords.z - center.z).powi(2);
+                d_sq <= radius_sq
+            })
+            .collect()
+    }
+
+    pub fn reindex(&mut self, time: f64) {
+        let indexed = self
+            .bodies
+            .keys()
+            .filter_map(|id| {
+                let coords = self.position_of(*id, time)?;
+                Some(IndexedBody {
+                    id: *id,
+                    coords: [coords.x, coords.y, coords.z],
+                })
+            })
+            .collect();
+        self.index = RTree::bulk_load(indexed);
+        self.indexed_at = time;
+        self.max_speed = self.bodies.keys().map(|id| self.max_speed_of(*id)).fold(0f64, f64::max);
+    }
+
+    pub fn update(&mut self, time: f64) {
+        if time - self.indexed_at > REINDEX_PERIOD {
+            self.reindex(time);
+        }
+    }
+}
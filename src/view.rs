@@ -0,0 +1,90 @@
+use crate::body::Body;
+use crate::protocol::state::{self, BodyUpdate};
+use std::collections::{HashMap, HashSet};
+
+/// Bodies entering, changing in and leaving a view since the previous diff.
+#[derive(Default, Debug)]
+pub struct ViewDiff {
+    pub entered: Vec<state::Body>,
+    pub updated: Vec<BodyUpdate>,
+    pub left: Vec<u32>,
+}
+
+impl ViewDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entered.is_empty() && self.updated.is_empty() && self.left.is_empty()
+    }
+
+    /// Env messages carrying this diff, at most `chunk_size` bodies each.
+    pub fn into_messages(self, time: f64, chunk_size: usize) -> Vec<state::Game> {
+        let mut messages = vec![];
+        for bodies in self.entered.chunks(chunk_size) {
+            messages.push(state::Game::EnvEnter(state::Env {
+                time,
+                bodies: bodies.to_vec(),
+            }));
+        }
+        for bodies in self.updated.chunks(chunk_size) {
+            messages.push(state::Game::EnvUpdate(state::EnvUpdate {
+                time,
+                bodies: bodies.to_vec(),
+            }));
+        }
+        if !self.left.is_empty() {
+            messages.push(state::Game::EnvLeave(self.left));
+        }
+        messages
+    }
+}
+
+/// What a client was last sent of the bodies around it.
+#[derive(Default)]
+pub struct ViewTracker {
+    known: HashMap<u32, state::Body>,
+}
+
+impl ViewTracker {
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.known.contains_key(&id)
+    }
+
+    /// Compares the bodies now in view with the known ones and records them as sent.
+    pub fn diff(&mut self, in_view: &[&Body]) -> ViewDiff {
+        let mut diff = ViewDiff::default();
+        let mut seen = HashSet::with_capacity(in_view.len());
+
+        for body in in_view {
+            let current = state::Body::from((*body).clone());
+            seen.insert(current.id);
+            match self.known.get(&current.id) {
+                Some(known) => {
+                    if let Some(update) = BodyUpdate::between(known, &current) {
+                        diff.updated.push(update);
+                        self.known.insert(current.id, current);
+                    }
+                }
+                None => {
+                    diff.entered.push(current.clone());
+                    self.known.insert(current.id, current);
+                }
+            }
+        }
+
+        self.known.retain(|id, _| {
+            let in_view = seen.contains(id);
+            if !in_view {
+                diff.left.push(*id);
+            }
+            in_view
+        });
+        diff
+    }
+}
@@ -4,13 +4,25 @@ use test_helpers_async::*;
 #[before_all]
 #[cfg(test)]
 mod spacebuild_tests_game {
-    use std::{env, sync::Arc};
+    use std::sync::Arc;
 
     use futures_time::{future::FutureExt, time::Duration};
     use scilib::coordinate::cartesian::Cartesian;
-    use spacebuild::{bot, instance::Instance, protocol::state::Game, server, spacebuild_log, tls::ServerPki, tracing};
+    use spacebuild::{
+        bot,
+        instance::Instance,
+        limits::{FloodPolicy, RateLimits},
+        protocol::{
+            state::{ErrorCode, Game, Rejection},
+            Command, Encoding, Login, Role, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        },
+        server, spacebuild_log,
+        tls::ServerPki,
+        tracing,
+    };
     use tokio::{net::TcpListener, sync::Mutex, time::sleep};
-    use uuid::Uuid;
+
+    const PASSWORD: &str = "correct horse battery staple";
 
     const SERVER_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----
 MIIDMTCCAhmgAwIBAgIUPW2I5vQZWOxWMHqP1Pu73GfKvhUwDQYJKoZIhvcNAQEL
@@ -99,16 +111,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     const TIMEOUT_DURATION: u64 = 20;
 
-    pub fn get_random_db_path() -> String {
-        format!(
-            "{}space_build_tests_{}.sqlite",
-            env::temp_dir().to_str().unwrap(),
-            Uuid::new_v4().to_string()
-        )
-    }
-
     async fn bootstrap(
-        db_path: &String,
         tls: bool,
     ) -> anyhow::Result<(
         Arc<Mutex<Instance>>,
@@ -121,7 +124,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
         let port = addr.port();
 
         let instance = Arc::new(Mutex::new(
-            Instance::from_path(db_path.as_str())
+            Instance::in_memory()
                 .timeout(Duration::from_secs(TIMEOUT_DURATION))
                 .await??,
         ));
@@ -155,8 +158,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_01_connect() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let _client = test!(bot::connect_plain("localhost", port));
         send_stop.send(())?;
         test!(game_thread)??;
@@ -165,8 +167,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_02_connect_terminate() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
         test!(client.terminate())?;
         send_stop.send(())?;
@@ -176,8 +177,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_03_connect_connect() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let _client1 = test!(bot::connect_plain("localhost", port));
         let _client2 = test!(bot::connect_plain("localhost", port));
         send_stop.send(())?;
@@ -187,8 +187,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_04_connect_terminate_connect() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client1 = test!(bot::connect_plain("localhost", port))?;
         test!(client1.terminate())?;
         let _client2 = test!(bot::connect_plain("localhost", port));
@@ -199,8 +198,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_05_connect_connect_terminate_terminate() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client1 = test!(bot::connect_plain("localhost", port))?;
         let mut client2 = test!(bot::connect_plain("localhost", port))?;
         test!(client1.terminate())?;
@@ -212,10 +210,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_06_login() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test213"))?;
+        test!(client.register("test213", PASSWORD))?;
         send_stop.send(())?;
         test!(game_thread)??;
         Ok(())
@@ -223,10 +220,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_07_login_terminate() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test213"))?;
+        test!(client.register("test213", PASSWORD))?;
         test!(client.terminate())?;
         send_stop.send(())?;
         test!(game_thread)??;
@@ -235,12 +231,11 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_08_login_login() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test123"))?;
+        test!(client.register("test123", PASSWORD))?;
         let mut client2 = test!(bot::connect_plain("localhost", port))?;
-        test!(client2.login("test456"))?;
+        test!(client2.register("test456", PASSWORD))?;
         send_stop.send(())?;
         test!(game_thread)??;
         Ok(())
@@ -248,15 +243,14 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_09_login_terminate_login() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        let id = test!(client.login("test123"))?;
+        let id = test!(client.register("test123", PASSWORD))?;
         test!(client.terminate())?;
         tokio::time::sleep(*Duration::from_millis(1000)).await;
         let mut client2 = test!(bot::connect_plain("localhost", port))?;
         tokio::time::sleep(*Duration::from_millis(2000)).await;
-        let id_later = test!(client2.login("test123"))?;
+        let id_later = test!(client2.login("test123", PASSWORD))?;
         tokio::time::sleep(*Duration::from_millis(2000)).await;
         assert_eq!(id, id_later);
         test!(client2.terminate())?;
@@ -267,10 +261,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_10_first_game_info() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test213"))?;
+        test!(client.register("test213", PASSWORD))?;
         let _game_info = test!(client.next_game_info())?;
         send_stop.send(())?;
         test!(game_thread)??;
@@ -279,10 +272,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_11_test_first_game_info() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test213"))?;
+        test!(client.register("test213", PASSWORD))?;
         let game_info = test!(client.next_game_info())?;
         match game_info {
             Game::Player(player_info) => {
@@ -299,10 +291,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_12_move_1() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = test!(bootstrap(&db_path, false))?;
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
         let mut client = test!(bot::connect_plain("localhost", port))?;
-        test!(client.login("test213"))?;
+        test!(client.register("test213", PASSWORD))?;
         let game_info = test!(client.next_game_info())?;
         let coords = match game_info {
             Game::Player(player_info) => {
@@ -315,7 +306,7 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
         };
         let game_info = test!(client.next_game_info())?;
         match game_info {
-            Game::Env(_bodies) => {}
+            Game::EnvEnter(_env) => {}
             _ => unreachable!(),
         }
 
@@ -329,7 +320,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
                 assert!(player_info.coords[0].is_normal());
                 assert!(player_info.coords[1].is_normal());
                 assert!(player_info.coords[2].is_normal());
-                break player_info.coords;
+                if player_info.coords[2] > coords[2] {
+                    break player_info.coords;
+                }
             } else {
                 i = i + 1;
                 if i > 1000 {
@@ -339,8 +332,6 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
         };
         assert!(i > 0);
         assert!(i < 1000);
-        assert_eq!(coords_later[0], coords[0]);
-        assert_eq!(coords_later[1], coords[1]);
         assert!(coords_later[2] > coords[2]);
         send_stop.send(())?;
         test!(game_thread)??;
@@ -349,10 +340,9 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
 
     #[tokio::test(flavor = "multi_thread")]
     async fn case_13_move_2() -> anyhow::Result<()> {
-        let db_path = get_random_db_path();
-        let (_, send_stop, game_thread, port) = bootstrap(&db_path, false).await?;
+        let (_, send_stop, game_thread, port) = bootstrap(false).await?;
         let mut client = bot::connect_plain("localhost", port).await?;
-        client.login("test213").await?;
+        client.register("test213", PASSWORD).await?;
         let game_info = client.next_game_info().await?;
 
         let mut coords = if let Game::Player(player_info) = game_info {
@@ -365,11 +355,12 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
             direction.x = 1.;
             client.move_in_space(direction).await?;
 
-            let coords_later = client.until_player_info().await?.coords;
-
-            assert!(coords_later[0] > coords[0]);
-            assert_eq!(coords_later[1], coords[1]);
-            assert_eq!(coords_later[2], coords[2]);
+            let coords_later = loop {
+                let coords_later = test!(client.until_player_info())?.coords;
+                if coords_later[0] > coords[0] {
+                    break coords_later;
+                }
+            };
             coords = coords_later;
         }
         {
@@ -377,11 +368,14 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
             direction.y = 1.;
             client.move_in_space(direction).await?;
 
-            let coords_later = client.until_player_info().await?.coords;
+            let coords_later = loop {
+                let coords_later = test!(client.until_player_info())?.coords;
+                if coords_later[1] > coords[1] {
+                    break coords_later;
+                }
+            };
 
-            assert_eq!(coords_later[0], coords[0]);
-            assert!(coords_later[1] > coords[1]);
-            assert_eq!(coords_later[2], coords[2]);
+            assert!(coords_later[0] > coords[0]);
             coords = coords_later;
         }
         {
@@ -389,12 +383,638 @@ lBjhUjWT859gkyO6pYSTfndSpnWAdtQK9zsTYociBQ==
             direction.z = 1.;
             client.move_in_space(direction).await?;
 
-            let coords_later = client.until_player_info().await?.coords;
+            let coords_later = loop {
+                let coords_later = test!(client.until_player_info())?.coords;
+                if coords_later[2] > coords[2] {
+                    break coords_later;
+                }
+            };
+
+            assert!(coords_later[0] > coords[0]);
+            assert!(coords_later[1] > coords[1]);
+        }
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_14_unload_deserted_system() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        test!(client.next_game_info())?;
+
+        let (star_id, bodies_count) = {
+            let instance = instance.lock().await;
+            let galaxy = instance.borrow_galaxy();
+            let star_id = galaxy.system_ids()[0];
+            (star_id, galaxy.borrow_system(star_id).unwrap().len())
+        };
+
+        test!(client.terminate())?;
+        test!(async {
+            while !instance.lock().await.borrow_galaxy().system_ids().is_empty() {
+                sleep(*Duration::from_millis(50)).await;
+            }
+        });
+
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.login("test213", PASSWORD))?;
+        test!(client.next_game_info())?;
+        {
+            let instance = instance.lock().await;
+            let galaxy = instance.borrow_galaxy();
+            assert_eq!(vec![star_id], galaxy.system_ids());
+            assert_eq!(bodies_count, galaxy.borrow_system(star_id).unwrap().len());
+        }
+
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_15_latency() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        let id = test!(client.register("test213", PASSWORD))?;
+
+        let rtt = test!(async {
+            loop {
+                let player_info = client.until_player_info().await?;
+                if let Some(rtt) = player_info.rtt {
+                    break anyhow::Ok(rtt);
+                }
+            }
+        })?;
+        assert!(rtt >= 0f64);
+
+        let latency = instance.lock().await.player_latency(id).unwrap();
+        assert!(latency.rtt >= 0f64);
+        assert!(latency.clock_offset.unwrap().abs() < 1f64);
+
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_16_message_pack_subprotocol() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain_with_encoding(
+            "localhost",
+            port,
+            Encoding::MessagePack
+        ))?;
+        test!(client.register("test213", PASSWORD))?;
+        let coords = test!(client.until_player_info())?.coords;
+        assert!(coords.iter().all(|coord| coord.is_normal()));
+
+        test!(client.move_in_space(Cartesian::from(1, 0, 0)))?;
+        test!(async {
+            loop {
+                if client.until_player_info().await?.coords[0] > coords[0] {
+                    break anyhow::Ok(());
+                }
+            }
+        })?;
+        test!(client.terminate())?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_17_message_pack_login() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        client.set_encoding(Encoding::MessagePack);
+        let id = test!(client.register("test213", PASSWORD))?;
+        test!(async {
+            while instance.lock().await.player_latency(id).is_none() {
+                client.next_game_info().await?;
+            }
+            anyhow::Ok(())
+        })?;
+        test!(client.terminate())?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_18_json_subprotocol() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain_with_encoding("localhost", port, Encoding::Json))?;
+        test!(client.register("test213", PASSWORD))?;
+        let coords = test!(client.until_player_info())?.coords;
+        assert!(coords.iter().all(|coord| coord.is_normal()));
+        test!(client.terminate())?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_19_message_pack_frames() -> anyhow::Result<()> {
+        use futures::{SinkExt, StreamExt};
+        use spacebuild::protocol::{Action, Login};
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut request = format!("ws://localhost:{}", port).into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", Encoding::MESSAGE_PACK_SUBPROTOCOL.parse()?);
+        let (mut stream, response) = test!(tokio_tungstenite::connect_async(request))?;
+        assert_eq!(
+            Some(Encoding::MESSAGE_PACK_SUBPROTOCOL),
+            response
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .and_then(|header| header.to_str().ok())
+        );
+
+        let login = Action::Register(Login::new("test213", PASSWORD));
+        test!(stream.send(Encoding::MessagePack.encode(&login)?))?;
+        for _ in 0..3 {
+            let message = test!(stream.next()).unwrap()?;
+            assert!(matches!(message, Message::Binary(_)), "{:?}", message);
+        }
+        test!(stream.close(None))?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_20_hello() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        let hello = client.hello().unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), hello.server_version);
+        assert_eq!(PROTOCOL_VERSION, hello.protocol_version);
+        assert_eq!(10f64, hello.tick_rate);
+        assert_eq!(FEATURES, hello.features);
+        assert_eq!(FEATURES, hello.capabilities);
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_21_incompatible_protocol() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        for version in [0, PROTOCOL_VERSION + 1] {
+            let mut client = test!(bot::connect_plain("localhost", port))?;
+            let result = test!(client.register_with(Login {
+                protocol_version: version,
+                ..Login::new("test213", PASSWORD)
+            }));
+            match result {
+                Err(spacebuild::error::Error::LoginRejected(rejection, _)) => assert_eq!(
+                    Rejection::IncompatibleProtocol {
+                        client: version,
+                        min: MIN_PROTOCOL_VERSION,
+                        max: PROTOCOL_VERSION
+                    },
+                    rejection
+                ),
+                _ => panic!("unexpected login result {:?}", result),
+            }
+        }
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_22_capabilities() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        let id = test!(client.register_with(Login {
+            capabilities: vec!["teleport".to_string(), "ships".to_string()],
+            ..Login::new("test213", PASSWORD)
+        }))?;
+        assert_eq!(vec!["ships".to_string()], client.hello().unwrap().capabilities);
+
+        let _ = async { while client.next_game_info().await.is_ok() {} }
+            .timeout(Duration::from_millis(1500))
+            .await;
+        assert!(instance.lock().await.player_latency(id).is_none());
+
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_23_error_before_login() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.move_in_space(Cartesian::from(1, 0, 0)))?;
+        match test!(client.next_game_info()) {
+            Err(spacebuild::error::Error::ServerError(code, _)) => assert_eq!(ErrorCode::NotALoginAction, code),
+            result => panic!("unexpected result {:?}", result),
+        }
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_24_error_on_invalid_message() -> anyhow::Result<()> {
+        use futures::{SinkExt, StreamExt};
+        use spacebuild::protocol::Action;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let (mut stream, _) = test!(tokio_tungstenite::connect_async(format!("ws://localhost:{}", port)))?;
+        test!(stream.send(Encoding::Json.encode(&Action::Register(Login::new("test213", PASSWORD)))?))?;
+        test!(stream.send(Message::text("{\"Teleport\":[1,2,3]}")))?;
+
+        let error = test!(async {
+            while let Some(message) = stream.next().await {
+                if let Ok(Game::Error { code, .. }) = Encoding::decode(&message?) {
+                    return anyhow::Ok(Some(code));
+                }
+            }
+            anyhow::Ok(None)
+        })?;
+        assert_eq!(Some(ErrorCode::InvalidMessage), error);
+
+        test!(async {
+            while !instance.lock().await.borrow_galaxy().system_ids().is_empty() {
+                sleep(*Duration::from_millis(50)).await;
+            }
+        });
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_25_notice_on_shutdown() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        test!(client.until_player_info())?;
+        send_stop.send(())?;
+        let notice = test!(async {
+            loop {
+                if let Game::Notice { message } = client.next_game_info().await? {
+                    break anyhow::Ok(message);
+                }
+            }
+        })?;
+        assert_eq!("Server is shutting down", notice);
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_26_invalid_credentials() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        test!(client.terminate())?;
+
+        for (nickname, password) in [("test213", "wrong password"), ("test456", PASSWORD)] {
+            let mut client = test!(bot::connect_plain("localhost", port))?;
+            match test!(client.login(nickname, password)) {
+                Err(spacebuild::error::Error::LoginRejected(rejection, _)) => {
+                    assert_eq!(Rejection::InvalidCredentials, rejection)
+                }
+                result => panic!("unexpected login result {:?}", result),
+            }
+        }
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_27_register_rejections() -> anyhow::Result<()> {
+        let (_, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        test!(client.terminate())?;
+
+        for (nickname, password, expected) in [
+            ("test213", PASSWORD, Rejection::NicknameTaken),
+            ("test456", "short", Rejection::WeakPassword),
+        ] {
+            let mut client = test!(bot::connect_plain("localhost", port))?;
+            match test!(client.register(nickname, password)) {
+                Err(spacebuild::error::Error::LoginRejected(rejection, _)) => assert_eq!(expected, rejection),
+                result => panic!("unexpected register result {:?}", result),
+            }
+        }
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    async fn resume(port: u16, token: &str) -> anyhow::Result<(bot::Bot<tokio::net::TcpStream>, u32)> {
+        loop {
+            let mut client = bot::connect_plain("localhost", port).await?;
+            match client.resume(token).await {
+                Ok(id) => return Ok((client, id)),
+                Err(spacebuild::error::Error::LoginRejected(Rejection::SessionInUse, _)) => {
+                    sleep(*Duration::from_millis(50)).await
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// First error sent to `bot`, game states being skipped.
+    async fn next_error(bot: &mut bot::Bot<tokio::net::TcpStream>) -> spacebuild::error::Error {
+        loop {
+            if let Err(err) = bot.next_game_info().await {
+                return err;
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_28_resume_after_drop() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        let id = test!(client.register("test213", PASSWORD))?;
+        test!(client.until_player_info())?;
+        let token = client.session_token().unwrap().to_string();
+        let star_id = instance.lock().await.borrow_galaxy().system_ids()[0];
+        drop(client);
+
+        // The session stays in use until the server notices the drop.
+        let (mut client, resumed_id) = test!(resume(port, &token))?;
+        assert_eq!(id, resumed_id);
+        assert_ne!(Some(token.as_str()), client.session_token());
+        test!(client.until_player_info())?;
+        assert_eq!(vec![star_id], instance.lock().await.borrow_galaxy().system_ids());
+
+        let mut client2 = test!(bot::connect_plain("localhost", port))?;
+        match test!(client2.resume(&token)) {
+            Err(spacebuild::error::Error::LoginRejected(rejection, _)) => {
+                assert_eq!(Rejection::InvalidSessionToken, rejection)
+            }
+            result => panic!("unexpected resume result {:?}", result),
+        }
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_29_resume_after_grace_period() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        instance
+            .lock()
+            .await
+            .set_resume_grace_period(std::time::Duration::from_millis(200));
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+        let token = client.session_token().unwrap().to_string();
+        drop(client);
+
+        test!(async {
+            while !instance.lock().await.borrow_galaxy().system_ids().is_empty() {
+                sleep(*Duration::from_millis(50)).await;
+            }
+        });
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        match test!(client.resume(&token)) {
+            Err(spacebuild::error::Error::LoginRejected(rejection, _)) => {
+                assert_eq!(Rejection::InvalidSessionToken, rejection)
+            }
+            result => panic!("unexpected resume result {:?}", result),
+        }
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.login("test213", PASSWORD))?;
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_30_spectators() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test213", PASSWORD))?;
+
+        let mut spectators = vec![];
+        for _ in 0..2 {
+            let mut spectator = test!(bot::connect_plain("localhost", port))?;
+            test!(spectator.spectate(None))?;
+            assert!(spectator.session_token().is_none());
+            test!(async {
+                while !matches!(spectator.next_game_info().await?, Game::EnvEnter(_)) {}
+                anyhow::Ok(())
+            })?;
+            spectators.push(spectator);
+        }
+        assert_eq!(2, instance.lock().await.spectator_count());
+        assert_eq!(1, instance.lock().await.borrow_galaxy().system_ids().len());
+
+        test!(spectators[0].move_in_space(Cartesian::from(1, 0, 0)))?;
+        let error = test!(next_error(&mut spectators[0]));
+        assert!(matches!(
+            error,
+            spacebuild::error::Error::ServerError(ErrorCode::UnexpectedAction, _)
+        ));
+
+        for mut spectator in spectators {
+            test!(spectator.terminate())?;
+        }
+        test!(async {
+            while instance.lock().await.spectator_count() > 0 {
+                sleep(*Duration::from_millis(50)).await;
+            }
+        });
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_31_admin_commands() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut moderator = test!(bot::connect_plain("localhost", port))?;
+        test!(moderator.register("test214", PASSWORD))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test215", PASSWORD))?;
+
+        let kick = Command::Kick {
+            nickname: "test215".to_string(),
+            reason: Some("be nice".to_string()),
+        };
+        assert!(matches!(
+            test!(moderator.command(kick.clone())),
+            Err(spacebuild::error::Error::ServerError(ErrorCode::Forbidden, _))
+        ));
+        test!(instance.lock().await.set_role("test214", Role::Moderator))?;
+        // Notices sent in the meantime are not taken for the answer.
+        test!(instance.lock().await.notify_all("hello"));
+        assert_eq!("No bans", test!(moderator.command(Command::ListBans))?);
+        assert!(matches!(
+            test!(moderator.command(Command::SpawnSystem { coords: [0.; 3] })),
+            Err(spacebuild::error::Error::ServerError(ErrorCode::Forbidden, _))
+        ));
+        let latency = Command::Latency {
+            nickname: "test214".to_string(),
+        };
+        for bot in [&mut moderator, &mut client] {
+            assert!(matches!(
+                test!(bot.command(latency.clone())),
+                Err(spacebuild::error::Error::ServerError(ErrorCode::Forbidden, _))
+            ));
+        }
+        test!(moderator.command(kick))?;
+
+        let error = test!(next_error(&mut client));
+        assert!(matches!(
+            error,
+            spacebuild::error::Error::ServerError(ErrorCode::Kicked, message) if message == "be nice"
+        ));
+        assert!(test!(client.next_game_info()).is_err());
+
+        test!(instance.lock().await.set_role("test214", Role::Admin))?;
+        let answer = test!(moderator.command(latency))?;
+        assert!(answer.starts_with("test214: "), "{}", answer);
+
+        let entries = test!(instance.lock().await.borrow_audit().entries())?;
+        assert_eq!(7, entries.len());
+        // Refused attempts are recorded too, the player's included.
+        assert_eq!(
+            vec!["test215"],
+            entries
+                .iter()
+                .map(|entry| entry.actor.as_str())
+                .filter(|actor| *actor != "test214")
+                .collect::<Vec<_>>()
+        );
+        assert!(entries[5].outcome.starts_with("ok: "));
+        assert!(entries[6].outcome.starts_with("ok: test214: "));
+
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_32_bans() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        let mut moderator = test!(bot::connect_plain("localhost", port))?;
+        test!(moderator.register("test216", PASSWORD))?;
+        test!(instance.lock().await.set_role("test216", Role::Moderator))?;
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test217", PASSWORD))?;
+
+        test!(moderator.command(Command::Ban {
+            nickname: "test217".to_string(),
+            reason: Some("cheating".to_string()),
+            duration: Some(3600.),
+        }))?;
+        assert!(matches!(
+            test!(next_error(&mut client)),
+            spacebuild::error::Error::ServerError(ErrorCode::Kicked, _)
+        ));
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        assert!(matches!(
+            test!(client.login("test217", PASSWORD)),
+            Err(spacebuild::error::Error::LoginRejected(Rejection::Banned(reason), _)) if reason == "cheating"
+        ));
+        let bans = test!(moderator.command(Command::ListBans))?;
+        assert!(bans.starts_with("test217: cheating (by test216, "));
+
+        // Whatever the loopback the client connects from.
+        for address in ["127.0.0.1", "::1"] {
+            let ban = Command::BanAddress {
+                address: address.to_string(),
+                reason: None,
+                duration: None,
+            };
+            test!(instance.lock().await.execute("console", &ban))?;
+        }
+        assert!(matches!(
+            test!(next_error(&mut moderator)),
+            spacebuild::error::Error::ServerError(ErrorCode::Kicked, _)
+        ));
+        assert!(test!(async {
+            match bot::connect_plain("localhost", port).await {
+                Ok(mut bot) => bot.login("test216", PASSWORD).await.map(|_| ()),
+                Err(err) => Err(err),
+            }
+        })
+        .is_err());
+
+        for address in ["127.0.0.1", "::1"] {
+            let unban = Command::UnbanAddress {
+                address: address.to_string(),
+            };
+            test!(instance.lock().await.execute("console", &unban))?;
+        }
+        let mut moderator = test!(bot::connect_plain("localhost", port))?;
+        test!(moderator.login("test216", PASSWORD))?;
 
-            assert_eq!(coords_later[0], coords[0]);
-            assert_eq!(coords_later[1], coords[1]);
-            assert!(coords_later[2] > coords[2]);
+        send_stop.send(())?;
+        test!(game_thread)??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn case_33_flood() -> anyhow::Result<()> {
+        let (instance, send_stop, game_thread, port) = test!(bootstrap(false))?;
+        instance.lock().await.set_rate_limits(RateLimits {
+            messages_per_second: 1f64,
+            message_burst: 10f64,
+            max_message_size: 1024,
+            ..RateLimits::default()
+        });
+
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test218", PASSWORD))?;
+        for _ in 0..20 {
+            test!(client.move_in_space(Cartesian::from(1, 0, 0)))?;
         }
+        // Some of the 10 tokens may have been refilled meanwhile.
+        test!(async {
+            while instance.lock().await.violations().dropped() < 9 {
+                sleep(*Duration::from_millis(50)).await;
+            }
+        });
+        test!(client.until_player_info())?;
+
+        instance.lock().await.set_rate_limits(RateLimits {
+            messages_per_second: 1f64,
+            message_burst: 10f64,
+            max_message_size: 1024,
+            policy: FloodPolicy::Disconnect,
+            ..RateLimits::default()
+        });
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        test!(client.register("test219", PASSWORD))?;
+        for _ in 0..20 {
+            if client.move_in_space(Cartesian::from(1, 0, 0)).await.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(
+            test!(next_error(&mut client)),
+            spacebuild::error::Error::ServerError(ErrorCode::RateLimited, _)
+        ));
+        assert_eq!(1, instance.lock().await.violations().disconnected());
+
+        let mut client = test!(bot::connect_plain("localhost", port))?;
+        let oversized = test!(client.register("test220", &"x".repeat(2048)));
+        assert!(oversized.is_err());
+        assert_eq!(1, instance.lock().await.violations().oversized());
+
         send_stop.send(())?;
         test!(game_thread)??;
         Ok(())